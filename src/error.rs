@@ -1,6 +1,8 @@
 use std::error::Error;
 use std::fmt;
 
+use rocket::http::Status;
+
 #[macro_export]
 macro_rules! local_error {
     // match something(q,r,t,6,7,8) etc
@@ -19,6 +21,10 @@ pub enum LocalError {
     ContextError(String),
     FilterDateParsing(String),
     FilterStringarsing(String),
+    TransientError(String),
+    ConflictError(String),
+    ValidationError(String),
+    ForbiddenError(String),
 }
 
 impl fmt::Display for LocalError {
@@ -31,6 +37,10 @@ impl fmt::Display for LocalError {
             LocalError::ContextError(desc) => write!(f, "Context exception : {}", desc),
             LocalError::FilterDateParsing(desc) => write!(f, "Filter exception : {}", desc),
             LocalError::FilterStringarsing(desc) => write!(f, "Filter exception : {}", desc),
+            LocalError::TransientError(desc) => write!(f, "Transient exception : {}", desc),
+            LocalError::ConflictError(desc) => write!(f, "Conflict exception : {}", desc),
+            LocalError::ValidationError(desc) => write!(f, "Validation exception : {}", desc),
+            LocalError::ForbiddenError(desc) => write!(f, "Forbidden exception : {}", desc),
             // _ => write!(f, "Global exception"),
         }
     }
@@ -50,6 +60,28 @@ impl ApiError {
             error,
         }
     }
+
+    /// Underlying error kind, to map or retry on specific errors
+    pub fn kind(&self) -> &LocalError {
+        &self.error
+    }
+
+    /// HTTP status of routes answering errors with a status instead of an exception body
+    pub fn status(&self) -> Status {
+        match self.error {
+            LocalError::OidFormatError(_)
+            | LocalError::ParsingError(_)
+            | LocalError::FilterDateParsing(_)
+            | LocalError::FilterStringarsing(_)
+            | LocalError::ContextError(_) => Status::BadRequest,
+            LocalError::DataNotFoundError(_) => Status::NotFound,
+            LocalError::ConflictError(_) => Status::Conflict,
+            LocalError::ValidationError(_) => Status::UnprocessableEntity,
+            LocalError::ForbiddenError(_) => Status::Forbidden,
+            LocalError::TransientError(_) => Status::ServiceUnavailable,
+            LocalError::ConnectionError(_) => Status::InternalServerError,
+        }
+    }
 }
 
 impl fmt::Display for ApiError {
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use rocket::tokio::sync::Mutex;
+use serde::Deserialize;
+
+use crate::jwks;
+use crate::jwt_secure::Claims;
+use crate::token::hex_sha256;
+
+/// RFC 7662 introspection of the opaque access tokens, read from the configuration server file.
+/// Disabled when introspection_endpoint is missing.
+/// introspection_endpoint = "https://idp.corp.com/oauth2/introspect"
+/// introspection_client_id = "middleoffice"
+/// introspection_client_secret = "..."
+/// introspection_auth = "client_secret_basic"   # or "client_secret_post"
+/// introspection_cache_seconds = 300           # longest caching of an active token, revocations seen after
+#[derive(Clone, Debug)]
+pub struct IntrospectionConfig {
+    pub endpoint: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth: String,
+    pub cache_seconds: u64,
+}
+
+impl IntrospectionConfig {
+    pub fn from_settings(settings: &config::Config) -> Result<Option<IntrospectionConfig>, String> {
+        let endpoint = match settings.get::<String>("introspection_endpoint") {
+            Ok(o) => o,
+            Err(_e) => return Ok(None),
+        };
+
+        let config = IntrospectionConfig {
+            endpoint,
+            client_id: settings
+                .get::<String>("introspection_client_id")
+                .unwrap_or_default(),
+            client_secret: settings
+                .get::<String>("introspection_client_secret")
+                .unwrap_or_default(),
+            auth: settings
+                .get::<String>("introspection_auth")
+                .unwrap_or_else(|_| String::from("client_secret_basic")),
+            cache_seconds: settings
+                .get::<u64>("introspection_cache_seconds")
+                .unwrap_or(300),
+        };
+
+        if config.auth != "client_secret_basic" && config.auth != "client_secret_post" {
+            return Err(format!("Unknown introspection auth {}.", config.auth));
+        }
+        if config.client_id.is_empty() {
+            return Err(String::from("introspection_client_id is missing."));
+        }
+        Ok(Some(config))
+    }
+}
+
+/// Answer of the introspection endpoint, the claims of an active token read as the JWT claims
+#[derive(Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(flatten)]
+    claims: Claims,
+}
+
+/// Claims of an active token, None for an inactive token.
+/// Tokens of a client without user have no sub, the client id is the subject.
+fn active_claims(response: IntrospectionResponse) -> Option<Claims> {
+    if !response.active {
+        return None;
+    }
+    let mut claims = response.claims;
+    if claims.sub.is_none() {
+        claims.sub = claims.client_id.clone();
+    }
+    Some(claims)
+}
+
+/// Time an active result is kept : until the token expiry, at most `max`
+pub fn cache_lifetime(exp: Option<i64>, now: DateTime<Utc>, max: Duration) -> Duration {
+    match exp {
+        Some(exp) => Duration::from_secs((exp - now.timestamp()).max(0) as u64).min(max),
+        None => max,
+    }
+}
+
+/// Introspection client, with the active results cached by token hash
+pub struct Introspector {
+    config: IntrospectionConfig,
+    client: reqwest::Client,
+    cached: Mutex<HashMap<String, (Claims, Instant)>>,
+}
+
+impl Introspector {
+    pub fn new(
+        config: IntrospectionConfig,
+        settings: &config::Config,
+    ) -> Result<Introspector, String> {
+        Ok(Introspector {
+            config,
+            client: jwks::http_client(settings)?,
+            cached: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Claims of an opaque token, an error when the token is inactive or the endpoint fails
+    pub async fn introspect(&self, token: &str) -> Result<Claims, String> {
+        let hash = hex_sha256(token);
+        let now = Instant::now();
+        {
+            let mut cached = self.cached.lock().await;
+            cached.retain(|_, (_, expires)| *expires > now);
+            if let Some((claims, _)) = cached.get(&hash) {
+                return Ok(claims.clone());
+            }
+        }
+
+        let mut form = vec![("token", token), ("token_type_hint", "access_token")];
+        let mut request = self.client.post(&self.config.endpoint);
+        if self.config.auth == "client_secret_post" {
+            form.push(("client_id", &self.config.client_id));
+            form.push(("client_secret", &self.config.client_secret));
+        } else {
+            request = request.basic_auth(&self.config.client_id, Some(&self.config.client_secret));
+        }
+
+        let response = match request.form(&form).send().await {
+            Ok(o) if o.status().is_success() => o,
+            Ok(o) => return Err(format!("Introspection failed : {}", o.status())),
+            Err(e) => return Err(format!("Introspection failed : {}", e)),
+        };
+        let response = match response.json::<IntrospectionResponse>().await {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Introspection unreadable : {}", e)),
+        };
+
+        // inactive tokens are not cached, they may not be retried with success anyway
+        let claims = match active_claims(response) {
+            Some(o) => o,
+            None => return Err(String::from("Inactive token")),
+        };
+        let lifetime = cache_lifetime(
+            claims.exp,
+            Utc::now(),
+            Duration::from_secs(self.config.cache_seconds),
+        );
+        if !lifetime.is_zero() {
+            self.cached
+                .lock()
+                .await
+                .insert(hash, (claims.clone(), now + lifetime));
+        }
+        Ok(claims)
+    }
+}
+
+#[test]
+fn test_active_claims() {
+    let response: IntrospectionResponse = serde_json::from_value(serde_json::json!({
+        "active": true,
+        "scope": "policies:read admin",
+        "client_id": "batch",
+        "exp": 1714640400,
+    }))
+    .unwrap();
+    let claims = active_claims(response).unwrap();
+    assert_eq!(claims.scope, "policies:read admin");
+    assert_eq!(claims.sub.as_deref(), Some("batch"));
+    assert_eq!(claims.exp, Some(1714640400));
+
+    let response: IntrospectionResponse =
+        serde_json::from_value(serde_json::json!({"active": false})).unwrap();
+    assert!(active_claims(response).is_none());
+}
+
+#[test]
+fn test_introspection_cache_lifetime() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 2, 8, 0, 0).unwrap();
+    let max = Duration::from_secs(300);
+
+    assert_eq!(
+        cache_lifetime(Some(now.timestamp() + 60), now, max),
+        Duration::from_secs(60)
+    );
+    assert_eq!(cache_lifetime(Some(now.timestamp() + 3600), now, max), max);
+    assert_eq!(
+        cache_lifetime(Some(now.timestamp() - 10), now, max),
+        Duration::ZERO
+    );
+    assert_eq!(cache_lifetime(None, now, max), max);
+}
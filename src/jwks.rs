@@ -0,0 +1,541 @@
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use jsonwebtoken::{Algorithm, DecodingKey};
+use rocket::tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// Key of a JWKS : RSA (n, e), EC (crv, x, y) or OKP Ed25519 (x)
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Jwk {
+    #[serde(default)]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kty: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub n: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub e: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub x: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub y: String,
+}
+
+impl Jwk {
+    pub fn decoding_key(&self) -> Result<DecodingKey, String> {
+        let key = match self.kty.as_deref().unwrap_or("RSA") {
+            "RSA" => DecodingKey::from_rsa_components(&self.n, &self.e),
+            "EC" => DecodingKey::from_ec_components(&self.x, &self.y),
+            "OKP" => DecodingKey::from_ed_components(&self.x),
+            other => return Err(format!("Unsupported key type {}.", other)),
+        };
+        key.map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// Key set read from the JWKS endpoint, with the time it may be used until
+#[derive(Default)]
+struct CachedSet {
+    keys: Vec<Jwk>,
+    expires: Option<Instant>,
+    last_fetch: Option<Instant>,
+    jwks_uri: Option<String>,
+}
+
+/// Where the key set is read : a JWKS URL, or the jwks_uri of an OpenID Connect issuer
+enum JwksLocation {
+    Url(Option<String>),
+    Discovery(String),
+}
+
+/// Key set of an identity provider, kept between requests.
+/// Read from the configuration server file :
+/// jwks_cache_seconds = 300        # lifetime when the endpoint sends no Cache-Control max-age or Expires
+/// jwks_min_refresh_seconds = 10   # minimum delay between two downloads, for unknown kids
+pub struct JwksCache {
+    location: JwksLocation,
+    client: reqwest::Client,
+    ttl: Duration,
+    min_refresh: Duration,
+    cached: Mutex<CachedSet>,
+    /// held by the one request downloading the set, the others use the current set meanwhile
+    refresh: Mutex<()>,
+}
+
+#[derive(Deserialize)]
+struct OpenIdConfiguration {
+    issuer: String,
+    jwks_uri: String,
+}
+
+/// Lifetime given by the HTTP cache headers : max-age first, then Expires.
+/// no-store and no-cache ask for a new download on each use.
+pub fn cache_lifetime(
+    cache_control: Option<&str>,
+    expires: Option<&str>,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    if let Some(cache_control) = cache_control {
+        for directive in cache_control
+            .split(',')
+            .map(|d| d.trim().to_ascii_lowercase())
+        {
+            if directive == "no-store" || directive == "no-cache" {
+                return Some(Duration::ZERO);
+            }
+            if let Some(seconds) = directive.strip_prefix("max-age=") {
+                if let Ok(s) = seconds.trim_matches('"').parse::<u64>() {
+                    return Some(Duration::from_secs(s));
+                }
+            }
+        }
+    }
+
+    let expires = DateTime::parse_from_rfc2822(expires?).ok()?;
+    Some(
+        (expires.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Key of the token kid, the first key when the token has no kid
+pub fn select_key<'a>(keys: &'a [Jwk], kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys.iter().find(|k| k.kid.as_deref() == Some(kid)),
+        None => keys.first(),
+    }
+}
+
+/// JWT_HOST is a JWKS URL, http:// is assumed when it has no scheme
+pub fn jwks_url(host: &str) -> String {
+    if host.starts_with("http://") || host.starts_with("https://") {
+        host.to_string()
+    } else {
+        format!("{}{}", "http://", host)
+    }
+}
+
+/// OpenID Connect discovery document of an issuer
+pub fn discovery_url(issuer: &str) -> String {
+    format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    )
+}
+
+/// HTTP client of the key downloads, trusting the system CAs and the optional jwt_ca_file PEM
+pub fn http_client(settings: &config::Config) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+
+    if let Ok(file) = settings.get::<String>("jwt_ca_file") {
+        let pem = match fs::read(&file) {
+            Ok(o) => o,
+            Err(e) => return Err(format!("CA file {} unreadable : {}", file, e)),
+        };
+        match reqwest::Certificate::from_pem(&pem) {
+            Ok(o) => builder = builder.add_root_certificate(o),
+            Err(e) => return Err(format!("CA file {} invalid : {}", file, e)),
+        }
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn cache_timings(settings: &config::Config) -> (Duration, Duration) {
+    (
+        Duration::from_secs(settings.get::<u64>("jwks_cache_seconds").unwrap_or(300)),
+        Duration::from_secs(
+            settings
+                .get::<u64>("jwks_min_refresh_seconds")
+                .unwrap_or(10),
+        ),
+    )
+}
+
+impl JwksCache {
+    pub fn new(host: Option<String>, ttl: Duration, min_refresh: Duration) -> JwksCache {
+        JwksCache {
+            location: JwksLocation::Url(host.map(|h| jwks_url(&h))),
+            client: reqwest::Client::new(),
+            ttl,
+            min_refresh,
+            cached: Mutex::new(CachedSet::default()),
+            refresh: Mutex::new(()),
+        }
+    }
+
+    /// Key set of an issuer, found by OpenID Connect discovery on first use
+    pub fn for_issuer(
+        client: reqwest::Client,
+        issuer: &str,
+        ttl: Duration,
+        min_refresh: Duration,
+    ) -> JwksCache {
+        JwksCache {
+            location: JwksLocation::Discovery(issuer.to_string()),
+            client,
+            ttl,
+            min_refresh,
+            cached: Mutex::new(CachedSet::default()),
+            refresh: Mutex::new(()),
+        }
+    }
+
+    async fn discover(&self, issuer: &str) -> Result<String, String> {
+        let response = match self.client.get(discovery_url(issuer)).send().await {
+            Ok(o) if o.status().is_success() => o,
+            Ok(o) => {
+                return Err(format!(
+                    "Error fetching discovery of {} : {}",
+                    issuer,
+                    o.status()
+                ))
+            }
+            Err(e) => return Err(format!("Error fetching discovery of {} : {}", issuer, e)),
+        };
+
+        let configuration = match response.json::<OpenIdConfiguration>().await {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Error reading discovery of {} : {}", issuer, e)),
+        };
+        // the document must describe the issuer it is published for
+        if configuration.issuer != issuer {
+            return Err(format!(
+                "Discovery of {} returned issuer {}",
+                issuer, configuration.issuer
+            ));
+        }
+        Ok(configuration.jwks_uri)
+    }
+
+    /// URL of the key set, the jwks_uri already discovered is reused
+    async fn jwks_uri(&self, discovered: Option<String>) -> Result<String, String> {
+        match &self.location {
+            JwksLocation::Url(Some(url)) => Ok(url.clone()),
+            JwksLocation::Url(None) => Err(String::from("JWT_HOST is not set.")),
+            JwksLocation::Discovery(issuer) => match discovered {
+                Some(uri) => Ok(uri),
+                None => self.discover(issuer).await,
+            },
+        }
+    }
+
+    /// Whether the set has to be downloaded for the kid, and the key of the current set
+    fn lookup(&self, cached: &CachedSet, kid: Option<&str>, now: Instant) -> (bool, Option<Jwk>) {
+        let key = select_key(&cached.keys, kid).cloned();
+        let expired = cached.expires.is_none_or(|e| now >= e);
+        let may_fetch = cached
+            .last_fetch
+            .is_none_or(|f| now.duration_since(f) >= self.min_refresh);
+        ((expired || key.is_none()) && may_fetch, key)
+    }
+
+    async fn fetch(&self, url: &str) -> Result<(Vec<Jwk>, Duration), String> {
+        let response = match self.client.get(url).send().await {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Error fetching JWKS : {}", e)),
+        };
+        if !response.status().is_success() {
+            return Err(format!("Error fetching JWKS : {}", response.status()));
+        }
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let lifetime = cache_lifetime(
+            header("cache-control").as_deref(),
+            header("expires").as_deref(),
+            Utc::now(),
+        )
+        .unwrap_or(self.ttl);
+
+        match response.json::<JwkSet>().await {
+            Ok(set) => Ok((
+                set.keys
+                    .into_iter()
+                    .filter(|k| k.decoding_key().is_ok())
+                    .collect(),
+                lifetime,
+            )),
+            Err(e) => Err(format!("Error reading JWKS : {}", e)),
+        }
+    }
+
+    /// Key for a token kid. The set is downloaded again when expired, or once for an unknown kid,
+    /// never more often than the minimum refresh delay. The last good set is kept while the endpoint is down.
+    /// The set is downloaded without holding the cache : while one request downloads it,
+    /// the others use the current set, only a kid missing from it waits for the download.
+    pub async fn key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let (fetch, key) = self.lookup(&*self.cached.lock().await, kid, Instant::now());
+        if !fetch {
+            return key;
+        }
+
+        let _refreshing = match self.refresh.try_lock() {
+            Ok(o) => o,
+            Err(_e) => {
+                if key.is_some() {
+                    return key;
+                }
+                let _refreshed = self.refresh.lock().await;
+                return select_key(&self.cached.lock().await.keys, kid).cloned();
+            }
+        };
+
+        // checked again, a download may have ended since the first lookup
+        let now = Instant::now();
+        let discovered = {
+            let mut cached = self.cached.lock().await;
+            let (fetch, key) = self.lookup(&cached, kid, now);
+            if !fetch {
+                return key;
+            }
+            cached.last_fetch = Some(now);
+            cached.jwks_uri.clone()
+        };
+
+        let fetched = match self.jwks_uri(discovered).await {
+            Ok(url) => self.fetch(&url).await.map(|f| (url, f)),
+            Err(e) => Err(e),
+        };
+
+        let mut cached = self.cached.lock().await;
+        match fetched {
+            Ok((url, (keys, lifetime))) => {
+                cached.keys = keys;
+                cached.expires = Some(now + lifetime);
+                if let JwksLocation::Discovery(_) = self.location {
+                    cached.jwks_uri = Some(url);
+                }
+            }
+            Err(e) => eprintln!("{}, keeping the last key set.", e),
+        }
+        select_key(&cached.keys, kid).cloned()
+    }
+}
+
+/// Keys verifying the tokens, selected per deployment in the configuration server file :
+/// jwt_key_source = "jwks_url"   # key set downloaded from JWT_HOST and cached (default)
+/// jwt_key_source = "oidc"       # key sets of the jwt_issuers, found by OpenID Connect discovery
+/// jwt_key_source = "pem"        # public key file, jwt_public_key_file = "rsapk.pem"
+/// jwt_key_source = "jwks"       # inline key set, jwt_jwks = '{"keys":[{"kid":"k1","n":"...","e":"AQAB"}]}'
+/// The file and inline sources verify tokens without any network call.
+/// The downloads trust the system CAs and the optional jwt_ca_file = "corp-ca.pem".
+pub enum KeySource {
+    Remote(Box<JwksCache>),
+    Oidc(Vec<(String, JwksCache)>),
+    Pem(Vec<u8>),
+    Inline(Vec<Jwk>),
+}
+
+impl KeySource {
+    pub fn from_settings(settings: &config::Config) -> Result<KeySource, String> {
+        let source = settings
+            .get::<String>("jwt_key_source")
+            .unwrap_or_else(|_| String::from("jwks_url"));
+
+        let (ttl, min_refresh) = cache_timings(settings);
+        match source.as_str() {
+            "jwks_url" => Ok(KeySource::Remote(Box::new(JwksCache {
+                location: JwksLocation::Url(env::var("JWT_HOST").ok().map(|h| jwks_url(&h))),
+                client: http_client(settings)?,
+                ttl,
+                min_refresh,
+                cached: Mutex::new(CachedSet::default()),
+                refresh: Mutex::new(()),
+            }))),
+            "oidc" => {
+                let issuers = settings
+                    .get::<Vec<String>>("jwt_issuers")
+                    .unwrap_or_default();
+                if issuers.is_empty() {
+                    return Err(String::from("jwt_issuers is missing."));
+                }
+                let client = http_client(settings)?;
+                Ok(KeySource::Oidc(
+                    issuers
+                        .iter()
+                        .map(|i| {
+                            let cache = JwksCache::for_issuer(client.clone(), i, ttl, min_refresh);
+                            (i.clone(), cache)
+                        })
+                        .collect(),
+                ))
+            }
+            "pem" => {
+                let file = match settings.get::<String>("jwt_public_key_file") {
+                    Ok(o) => o,
+                    Err(_e) => return Err(String::from("jwt_public_key_file is missing.")),
+                };
+                match fs::read(&file) {
+                    Ok(pem) => KeySource::from_pem(&pem),
+                    Err(e) => Err(format!("Public key {} unreadable : {}", file, e)),
+                }
+            }
+            "jwks" => match settings.get::<String>("jwt_jwks") {
+                Ok(o) => KeySource::from_jwks(&o),
+                Err(_e) => Err(String::from("jwt_jwks is missing.")),
+            },
+            other => Err(format!("Unknown JWT key source {}.", other)),
+        }
+    }
+
+    /// RSA, EC or Ed25519 public key PEM
+    pub fn from_pem(pem: &[u8]) -> Result<KeySource, String> {
+        if DecodingKey::from_rsa_pem(pem).is_ok()
+            || DecodingKey::from_ec_pem(pem).is_ok()
+            || DecodingKey::from_ed_pem(pem).is_ok()
+        {
+            Ok(KeySource::Pem(pem.to_vec()))
+        } else {
+            Err(String::from("Public key is not a RSA, EC or Ed25519 PEM."))
+        }
+    }
+
+    pub fn from_jwks(json: &str) -> Result<KeySource, String> {
+        match serde_json::from_str::<JwkSet>(json) {
+            Ok(set) if !set.keys.is_empty() => Ok(KeySource::Inline(set.keys)),
+            Ok(_set) => Err(String::from("Inline JWKS has no key.")),
+            Err(e) => Err(format!("Inline JWKS invalid : {}", e)),
+        }
+    }
+
+    /// Key verifying a token of the given kid and algorithm, the file key verifies every token.
+    /// With OpenID Connect discovery, the key is taken from the key set of the token issuer.
+    pub async fn key(
+        &self,
+        kid: Option<&str>,
+        algorithm: Algorithm,
+        issuer: Option<&str>,
+    ) -> Option<DecodingKey> {
+        let jwk = match self {
+            KeySource::Oidc(issuers) => {
+                let (_, cache) = issuers.iter().find(|(i, _)| Some(i.as_str()) == issuer)?;
+                cache.key(kid).await
+            }
+            KeySource::Pem(pem) => {
+                let key = match algorithm {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+                    _ => DecodingKey::from_rsa_pem(pem),
+                };
+                return key.ok();
+            }
+            KeySource::Inline(keys) => select_key(keys, kid).cloned(),
+            KeySource::Remote(cache) => cache.key(kid).await,
+        }?;
+
+        match jwk.decoding_key() {
+            Ok(o) => Some(o),
+            Err(e) => {
+                eprintln!("Invalid key {:?} in JWKS : {}", jwk.kid, e);
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cache_lifetime() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 2, 10, 0, 0).unwrap();
+
+    assert_eq!(
+        cache_lifetime(Some("public, max-age=600"), None, now),
+        Some(Duration::from_secs(600))
+    );
+    assert_eq!(
+        cache_lifetime(Some("no-cache"), None, now),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(
+        cache_lifetime(None, Some("Thu, 02 May 2024 10:05:00 GMT"), now),
+        Some(Duration::from_secs(300))
+    );
+    assert_eq!(
+        cache_lifetime(None, Some("Thu, 02 May 2024 09:00:00 GMT"), now),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(cache_lifetime(Some("public"), None, now), None);
+}
+
+#[test]
+fn test_select_key() {
+    let keys = vec![
+        Jwk {
+            kid: Some(String::from("old")),
+            ..Default::default()
+        },
+        Jwk {
+            kid: Some(String::from("new")),
+            ..Default::default()
+        },
+    ];
+
+    assert_eq!(
+        select_key(&keys, Some("new")).and_then(|k| k.kid.clone()),
+        Some(String::from("new"))
+    );
+    assert!(select_key(&keys, Some("unknown")).is_none());
+    assert_eq!(
+        select_key(&keys, None).and_then(|k| k.kid.clone()),
+        Some(String::from("old"))
+    );
+}
+
+#[rocket::async_test]
+async fn test_offline_key_sources() {
+    use crate::jwt_secure::Claims;
+    use jsonwebtoken::Validation;
+
+    let service = crate::token::test_service();
+    let token = service
+        .issue("client_credentials", "tests", "tests", None)
+        .unwrap()
+        .access_token;
+    let jwks = serde_json::json!({"keys": [service.jwk]}).to_string();
+
+    let sources = vec![
+        KeySource::from_pem(include_bytes!("test/token_test_pk.pem")).unwrap(),
+        KeySource::from_jwks(&jwks).unwrap(),
+    ];
+    for source in sources {
+        let key = source
+            .key(Some("test-1"), Algorithm::RS256, None)
+            .await
+            .unwrap();
+        let claims =
+            jsonwebtoken::decode::<Claims>(&token, &key, &Validation::new(Algorithm::RS256));
+        assert!(claims.is_ok());
+    }
+
+    let inline = KeySource::from_jwks(&jwks).unwrap();
+    assert!(inline
+        .key(Some("other"), Algorithm::RS256, None)
+        .await
+        .is_none());
+    assert!(KeySource::from_jwks("{\"keys\": []}").is_err());
+    assert!(KeySource::from_pem(b"not a key").is_err());
+}
+
+#[test]
+fn test_key_urls() {
+    assert_eq!(jwks_url("idp:8080/certs"), "http://idp:8080/certs");
+    assert_eq!(jwks_url("https://idp/certs"), "https://idp/certs");
+    assert_eq!(
+        discovery_url("https://idp.corp.com/realms/mo/"),
+        "https://idp.corp.com/realms/mo/.well-known/openid-configuration"
+    );
+}
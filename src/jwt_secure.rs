@@ -2,7 +2,7 @@ use rocket::Responder;
 
 use rocket::serde::{Deserialize, Serialize};
 
-use jsonwebtoken::{encode, decode, DecodingKey, Algorithm, Header, Validation}; // 👈 New!
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation}; // 👈 New!
 use jsonwebtoken::errors::{Error, ErrorKind};
 
 use std::fs::File;
@@ -11,106 +11,291 @@ use std::io::Read;
 //use shared::response_models::{Response, ResponseBody, NetworkResponse}; // 👈 New!
 use rocket::request::{Outcome, Request, FromRequest}; // 👈 New!
 use rocket::http::Status;
-use reqwest::get;
 use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use base64::Engine;
+
+use crate::introspection::Introspector;
+use crate::jwks::{JwksCache, KeySource};
+use crate::mongo::mongo_api_keys;
+use crate::revocation::RevocationCache;
+use crate::tenant::TenantRepos;
+use crate::token::TokenService;
 
 #[derive(Debug)]
 pub struct JWT {
     pub claims: Claims,
 }
 
-#[derive(Deserialize,Debug,Serialize)]
-struct Jwk {
-    n: String,
-    e: String,
-}
-
-#[derive(Deserialize,Debug,Serialize)]
-struct JwkSet {
-    keys: Vec<Jwk>,
-}
-
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for JWT {
     type Error = NetworkResponse;
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, NetworkResponse> {
-        async fn is_valid(key: &str) -> Result<Claims, Error> {
-            Ok(decode_jwt(String::from(key)).await?)
+        match request_claims(req).await {
+            Ok(claims) => Outcome::Success(JWT { claims: claims.clone() }),
+            Err(response) => Outcome::Failure((Status::Unauthorized, NetworkResponse::Unauthorized(response.clone()))),
+        }
+    }
+}
+
+async fn is_valid(req: &Request<'_>, key: &str) -> Result<Claims, Error> {
+    // tokens of the built-in token service are verified with its own key, without network
+    if let Some(service) = req.rocket().state::<TokenService>() {
+        let token = key.trim_start_matches("Bearer").trim();
+        if service.is_local(token) {
+            return service.verify(token);
         }
+    }
+    // opaque tokens are not JWTs, their claims are read from the introspection endpoint
+    if let Some(introspector) = req.rocket().state::<Introspector>() {
+        let token = key.trim_start_matches("Bearer").trim();
+        if decode_header(token).is_err() {
+            return introspector.introspect(token).await.map_err(|e| {
+                println!("Error introspecting token - {}", e);
+                Error::from(ErrorKind::InvalidToken)
+            });
+        }
+    }
+    let default_config = ValidationConfig::default();
+    let config = req.rocket().state::<ValidationConfig>().unwrap_or(&default_config);
+    match req.rocket().state::<KeySource>() {
+        Some(keys) => Ok(decode_jwt(String::from(key), keys, config).await?),
+        // without managed key source, the key set is downloaded for the request
+        None => {
+            let jwks = JwksCache::new(env::var("JWT_HOST").ok(), Duration::ZERO, Duration::ZERO);
+            Ok(decode_jwt(String::from(key), &KeySource::Remote(Box::new(jwks)), config).await?)
+        }
+    }
+}
+
+// service callers authenticate with an API key, carrying the scopes of the key
+async fn api_key_claims(req: &Request<'_>, key: &str) -> Result<Claims, String> {
+    let message = |m: String| {
+        println!("Error validating API key - {}", m);
+        let response = Response { body: ResponseBody::Message(format!("Error validating API key - {}", m))};
+        serde_json::to_string(&response).unwrap()
+    };
+    let tenants = match req.rocket().state::<TenantRepos>() {
+        Some(o) => o,
+        None => return Err(message(String::from("Tenant repos not managed"))),
+    };
+    match mongo_api_keys::authenticate(&tenants.default, key).await {
+        Ok(Some(k)) => Ok(Claims {
+            iat: None,
+            exp: k.expires.map(|e| e.timestamp_millis() / 1000),
+            iss: None,
+            jti: None,
+            scope: k.scope,
+            sub: Some(format!("apikey:{}", k.owner)),
+            email: None,
+            tenant: k.tenant,
+            client_id: None,
+        }),
+        Ok(None) => Err(message(String::from("Invalid Key"))),
+        Err(e) => Err(message(e.to_string())),
+    }
+}
+
+// a valid token may have been revoked since it was issued (stolen token, compromised subject)
+async fn is_revoked(req: &Request<'_>, claims: &Claims) -> bool {
+    match (req.rocket().state::<RevocationCache>(), req.rocket().state::<TenantRepos>()) {
+        (Some(cache), Some(tenants)) => cache.is_revoked(&tenants.default, claims).await,
+        _ => false,
+    }
+}
 
+/// Claims of the caller, from its token or API key, or the error response.
+/// Several guards of a route authenticate the caller, the token is verified once per request.
+pub async fn request_claims<'r>(req: &'r Request<'_>) -> &'r Result<Claims, String> {
+    req.local_cache_async(async {
         match req.headers().get_one("authorization") {
+            None if req.headers().contains("x-api-key") => {
+                api_key_claims(req, req.headers().get_one("x-api-key").unwrap_or_default()).await
+            },
             None => {
                 let response = Response { body: ResponseBody::Message(String::from("Error validating JWT token - No token provided"))};
 
                 println!("Error validating JWT token - No token provided");
-                Outcome::Failure((Status::Unauthorized, NetworkResponse::Unauthorized(serde_json::to_string(&response).unwrap())))
+                Err(serde_json::to_string(&response).unwrap())
             },
-            Some(key) => match is_valid(key).await {
-                Ok(claims) => Outcome::Success(JWT {claims}),
+            Some(key) => match is_valid(req, key).await {
+                Ok(claims) if is_revoked(req, &claims).await => {
+                    let response = Response { body: ResponseBody::Message(String::from("Error validating JWT token - Revoked Token"))};
+                    println!("Error validating JWT token - Revoked Token");
+                    Err(serde_json::to_string(&response).unwrap())
+                },
+                Ok(claims) => Ok(claims),
                 Err(err) => match &err.kind() {
                     jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
-                        let response = Response { body: ResponseBody::Message(format!("Error validating JWT token - Expired Token"))};
+                        let response = Response { body: ResponseBody::Message(String::from("Error validating JWT token - Expired Token"))};
                         println!("Error validating JWT token - Expired Token");
-                        Outcome::Failure((Status::Unauthorized, NetworkResponse::Unauthorized(serde_json::to_string(&response).unwrap())))
+                        Err(serde_json::to_string(&response).unwrap())
                     },
                     jsonwebtoken::errors::ErrorKind::InvalidToken => {
-                        let response = Response { body: ResponseBody::Message(format!("Error validating JWT token - Invalid Token"))};
+                        let response = Response { body: ResponseBody::Message(String::from("Error validating JWT token - Invalid Token"))};
                         // Print the error to the console
                         println!("Error validating JWT token - Invalid Token");
-                        Outcome::Failure((Status::Unauthorized, NetworkResponse::Unauthorized(serde_json::to_string(&response).unwrap())))
+                        Err(serde_json::to_string(&response).unwrap())
                     },
                     _ => {
                         let response = Response { body: ResponseBody::Message(format!("Error validating JWT token - {}", err))};
-                        println!("{}",format!("Error validating JWT token - {}", err));
-                        Outcome::Failure((Status::Unauthorized, NetworkResponse::Unauthorized(serde_json::to_string(&response).unwrap())))
+                        println!("Error validating JWT token - {}", err);
+                        Err(serde_json::to_string(&response).unwrap())
                     }
                 }
             },
         }
+    }).await
+}
+
+/// Claims checks of the identity provider tokens, read from the configuration server file :
+/// jwt_issuers = ["https://idp.corp.com/realms/middleoffice"]   # trusted issuers, not checked when missing
+/// jwt_audiences = ["middleoffice"]                             # accepted audiences, not checked when missing
+/// jwt_algorithms = ["RS256", "PS256", "ES256"]                 # RS256 when missing
+/// jwt_leeway_seconds = 60
+/// jwt_required_claims = ["exp", "sub"]                         # exp when missing
+pub struct ValidationConfig {
+    pub issuers: Vec<String>,
+    pub audiences: Vec<String>,
+    pub algorithms: Vec<Algorithm>,
+    pub leeway: u64,
+    pub required_claims: Vec<String>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> ValidationConfig {
+        ValidationConfig {
+            issuers: Vec::new(),
+            audiences: Vec::new(),
+            algorithms: vec![Algorithm::RS256],
+            leeway: 60,
+            required_claims: vec![String::from("exp")],
+        }
     }
 }
 
-   async fn decode_jwt(token: String) -> Result<Claims, ErrorKind> {
-       
-        let token = token.trim_start_matches("Bearer").trim();
-
-        let secret = env::var("JWT_HOST").expect("JWT_HOST must be set.");
-        // Effectuer une requête HTTP pour obtenir le Certs
-        //println!("JWT URL : {}",secret);
-        let jwk_set  = match get(format!("{}{}","http://",secret)).await {
-            Ok(response) => response,
-            Err(err) => {
-                println!("Error fetching PEM file: {:?}", err);
-                return Err(ErrorKind::InvalidToken);
-            }   
-        };
-
-        let jwk_json : JwkSet  = match jwk_set.json().await {
-            Ok(response) => response,
-            Err(err) => {
-                println!("Error fetching to JSON file: {:?}", err);
-                return Err(ErrorKind::InvalidToken);
-            }   
-        };
-
-       // println!("Bearer {}",&token);
-
-       // let pem = response.as_bytes();
-       let jwk = &jwk_json.keys[0];
-
-        // 👇 New!
-        match decode::<Claims>(
-            &token,
-            //&DecodingKey::from_jwk(&pem).unwrap(),
-            &DecodingKey::from_rsa_components(&jwk.n, &jwk.e).unwrap(),
-            &Validation::new(Algorithm::RS256),
-        ) {
-            Ok(token) => Ok(token.claims),
-            Err(err) => Err(err.kind().to_owned())
+impl ValidationConfig {
+    pub fn from_settings(settings: &config::Config) -> Result<ValidationConfig, String> {
+        let default = ValidationConfig::default();
+
+        let mut algorithms = Vec::new();
+        for name in settings.get::<Vec<String>>("jwt_algorithms").unwrap_or_default() {
+            match Algorithm::from_str(&name) {
+                // shared secrets are not accepted, tokens are signed by the identity provider only
+                Ok(Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) | Err(_) => {
+                    return Err(format!("Unsupported JWT algorithm {}.", name));
+                }
+                Ok(o) => algorithms.push(o),
+            }
+        }
+
+        let required_claims = settings
+            .get::<Vec<String>>("jwt_required_claims")
+            .unwrap_or(default.required_claims);
+        for claim in &required_claims {
+            if !["exp", "nbf", "aud", "iss", "sub"].contains(&claim.as_str()) {
+                return Err(format!("Unsupported JWT required claim {}.", claim));
+            }
         }
+
+        Ok(ValidationConfig {
+            issuers: settings.get::<Vec<String>>("jwt_issuers").unwrap_or_default(),
+            audiences: settings.get::<Vec<String>>("jwt_audiences").unwrap_or_default(),
+            algorithms: if algorithms.is_empty() {
+                default.algorithms
+            } else {
+                algorithms
+            },
+            leeway: settings.get::<u64>("jwt_leeway_seconds").unwrap_or(default.leeway),
+            required_claims,
+        })
     }
 
+    /// Validation of a token signed with the given algorithm, None when the algorithm is not allowed.
+    /// Configured issuers and audiences make the iss and aud claims required.
+    pub fn validation(&self, algorithm: Algorithm) -> Option<Validation> {
+        if !self.algorithms.contains(&algorithm) {
+            return None;
+        }
+
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway;
+        let mut required = self.required_claims.clone();
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+            required.push(String::from("iss"));
+        }
+        if !self.audiences.is_empty() {
+            validation.set_audience(&self.audiences);
+            required.push(String::from("aud"));
+        }
+        validation.set_required_spec_claims(&required);
+        validation.validate_nbf = required.iter().any(|c| c == "nbf");
+        Some(validation)
+    }
+}
+
+/// Issuer of a token before its signature is checked, only used to select the key set
+pub fn unverified_issuer(token: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims.get("iss")?.as_str().map(|s| s.to_string())
+}
+
+/// Verify a token with the key of its kid, from the configured key source
+async fn decode_jwt(
+    token: String,
+    keys: &KeySource,
+    config: &ValidationConfig,
+) -> Result<Claims, ErrorKind> {
+    let token = token.trim_start_matches("Bearer").trim();
+
+    let header = match decode_header(token) {
+        Ok(header) => header,
+        Err(err) => return Err(err.kind().to_owned()),
+    };
+
+    let mut validation = match config.validation(header.alg) {
+        Some(v) => v,
+        None => {
+            println!(
+                "Error validating JWT token - algorithm {:?} not allowed",
+                header.alg
+            );
+            return Err(ErrorKind::InvalidAlgorithm);
+        }
+    };
+
+    // the keys of an issuer only verify the tokens of that issuer
+    let issuer = unverified_issuer(token);
+    if let (KeySource::Oidc(_), Some(iss)) = (keys, &issuer) {
+        validation.set_issuer(&[iss]);
+        validation.required_spec_claims.insert(String::from("iss"));
+    }
+
+    let key = match keys
+        .key(header.kid.as_deref(), header.alg, issuer.as_deref())
+        .await
+    {
+        Some(k) => k,
+        None => {
+            println!("Error validating JWT token - no key for kid {:?}", header.kid);
+            return Err(ErrorKind::InvalidToken);
+        }
+    };
+
+    match decode::<Claims>(token, &key, &validation) {
+        Ok(token) => Ok(token.claims),
+        Err(err) => Err(err.kind().to_owned()),
+    }
+}
+
 #[derive(Responder, Debug)]
 pub enum NetworkResponse {
     #[response(status = 201)]
@@ -119,6 +304,8 @@ pub enum NetworkResponse {
     BadRequest(String),
     #[response(status = 401)]
     Unauthorized(String),
+    #[response(status = 403)]
+    Forbidden(String),
     #[response(status = 404)]
     NotFound(String),
     #[response(status = 409)]
@@ -137,8 +324,144 @@ pub struct Response {
     pub body: ResponseBody,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Claims of a verified token, scopes joined with spaces
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "TokenClaims")]
 pub struct Claims {
-    pub iat: i32,
-    scope: String,
+    pub iat: Option<i64>,
+    pub exp: Option<i64>,
+    pub iss: Option<String>,
+    pub jti: Option<String>,
+    pub scope: String,
+    pub sub: Option<String>,
+    pub email: Option<String>,
+    pub tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+/// Scopes as a space separated string or as an array
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scopes {
+    Text(String),
+    List(Vec<String>),
+}
+
+impl Scopes {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Scopes::Text(s) => s.split_whitespace().map(String::from).collect(),
+            Scopes::List(l) => l,
+        }
+    }
+}
+
+/// Claims as sent by the identity providers : scope or scp (Azure AD, Okta), string or array
+#[derive(Deserialize)]
+struct TokenClaims {
+    #[serde(default)]
+    iat: Option<i64>,
+    #[serde(default)]
+    exp: Option<i64>,
+    #[serde(default)]
+    iss: Option<String>,
+    #[serde(default)]
+    jti: Option<String>,
+    #[serde(default)]
+    scope: Option<Scopes>,
+    #[serde(default)]
+    scp: Option<Scopes>,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    client_id: Option<String>,
+    #[serde(default)]
+    azp: Option<String>,
+}
+
+impl From<TokenClaims> for Claims {
+    fn from(claims: TokenClaims) -> Claims {
+        let mut scopes = claims.scope.map(Scopes::into_vec).unwrap_or_default();
+        for scope in claims.scp.map(Scopes::into_vec).unwrap_or_default() {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+
+        Claims {
+            iat: claims.iat,
+            exp: claims.exp,
+            iss: claims.iss,
+            jti: claims.jti,
+            scope: scopes.join(" "),
+            sub: claims.sub,
+            email: claims.email,
+            tenant: claims.tenant,
+            // client of the token : client_id (RFC 9068) or azp (OpenID Connect)
+            client_id: claims.client_id.or(claims.azp),
+        }
+    }
+}
+
+#[test]
+fn test_claims_scopes() {
+    let claims: Claims = serde_json::from_value(serde_json::json!({
+        "scope": "policies:read users:read",
+        "sub": "batch",
+    }))
+    .unwrap();
+    assert_eq!(claims.scope, "policies:read users:read");
+    assert_eq!(claims.iat, None);
+
+    let claims: Claims = serde_json::from_value(serde_json::json!({
+        "iat": 1714636800,
+        "scp": ["policies:read", "admin"],
+    }))
+    .unwrap();
+    assert_eq!(claims.scope, "policies:read admin");
+
+    // client credentials tokens without subject carry the client
+    let claims: Claims = serde_json::from_value(serde_json::json!({
+        "scope": "policies:read",
+        "azp": "batch",
+    }))
+    .unwrap();
+    assert_eq!(claims.sub, None);
+    assert_eq!(claims.client_id.as_deref(), Some("batch"));
+}
+
+#[test]
+fn test_validation_config() {
+    let config = ValidationConfig {
+        issuers: vec![String::from("https://idp.corp.com")],
+        algorithms: vec![Algorithm::RS256, Algorithm::ES256],
+        ..Default::default()
+    };
+
+    assert!(config.validation(Algorithm::HS256).is_none());
+    let validation = config.validation(Algorithm::ES256).unwrap();
+    assert_eq!(validation.algorithms, vec![Algorithm::ES256]);
+    assert!(validation.required_spec_claims.contains("iss"));
+    assert!(validation.required_spec_claims.contains("exp"));
+    assert!(validation.aud.is_none());
+}
+
+#[test]
+fn test_unverified_issuer() {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+    let payload = URL_SAFE_NO_PAD.encode(r#"{"iss":"https://idp.corp.com","sub":"batch"}"#);
+    assert_eq!(
+        unverified_issuer(&format!("e30.{}.c2ln", payload)).as_deref(),
+        Some("https://idp.corp.com")
+    );
+
+    let payload = URL_SAFE_NO_PAD.encode(r#"{"sub":"batch"}"#);
+    assert_eq!(unverified_issuer(&format!("e30.{}.c2ln", payload)), None);
+    assert_eq!(unverified_issuer("not a token"), None);
 }
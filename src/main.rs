@@ -3,14 +3,23 @@ extern crate rocket;
 use std::process;
 
 use rocket::data::ToByteUnit;
-use rocket::http::Status;
+use rocket::http::uri::{fmt::Path, Segments};
+use rocket::http::{ContentType, Status};
 use rocket::serde::json::Json;
 use rocket::Data;
 use rocket::State;
 
 mod config;
+mod introspection;
+mod jwks;
 mod models;
 mod mongo;
+mod outbox;
+mod retention;
+mod revocation;
+mod scope;
+mod tenant;
+mod token;
 #[macro_use]
 mod error;
 
@@ -18,96 +27,493 @@ mod error;
 mod test;
 mod jwt_secure;
 
-use crate::models::policy_model::Policy;
-use crate::models::user_model::User;
+use crate::error::LocalError;
+use crate::models::api_key_model::{ApiKeyCreated, ApiKeyRequest};
+use crate::models::assignment_model::{Assignment, AssignmentRequest};
+use crate::models::attachment_model::AttachmentDownload;
+use crate::models::audit_model::Actor;
+use crate::models::idempotency_model::IdempotencyKey;
+use crate::models::policy_model::{Policy, SearchHit};
+use crate::models::retention_model::{LegalHold, PurgePlan};
+use crate::models::revocation_model::{Revocation, RevocationRequest};
+use crate::models::scim_model::{self, ScimListResponse, ScimPatch, ScimResponse, ScimUser};
+use crate::models::user_model::{User, UserPatch};
+use crate::mongo::business_key::BusinessKey;
 use crate::mongo::mongo::MongoRepo;
 
 use mongodb::results::InsertOneResult;
 use serde_json::json;
 
 use mongo::mongo_any;
+use mongo::mongo_api_keys;
+use mongo::mongo_assignments;
+use mongo::mongo_attachments;
+use mongo::mongo_audit;
+use mongo::mongo_changes;
+use mongo::mongo_idempotency::{self, Reservation};
+use mongo::mongo_retention;
+use mongo::mongo_revocations;
+use mongo::mongo_scim;
+use mongo::mongo_indexes::{self, IndexSpec};
+use mongo::mongo_search::{self, SearchConfig};
+use mongo::mongo_stats;
 use mongo::mongo_users;
-use crate::jwt_secure::{JWT, NetworkResponse};
+use crate::jwt_secure::JWT;
+use crate::scope::{Admin, PoliciesRead, PoliciesWrite, RequireScope, UsersRead, UsersWrite};
+use crate::retention::RetentionConfig;
+use crate::revocation::RevocationCache;
+use crate::tenant::{TenantConfig, TenantRepos};
+use crate::token::{ClientAuthorization, PublicJwk, TokenConfig, TokenService};
 
 
+/// Built-in token service : RS256 token for a configured client (client credentials grant).
+/// Form encoded body : grant_type=client_credentials&client_id=batch&client_secret=...&scope=policies:read
+/// The client may authenticate with HTTP Basic instead of client_id and client_secret.
+#[post("/api/token", data = "<request>")]
+fn post_token(
+    service: &State<TokenService>,
+    authorization: ClientAuthorization,
+    request: rocket::form::Form<std::collections::HashMap<String, String>>,
+) -> (Status, Json<serde_json::Value>) {
+    let field = |name: &str| request.get(name).map(|v| v.as_str());
+    let result = token::client_credentials(
+        authorization.0.as_deref(),
+        field("client_id"),
+        field("client_secret"),
+    )
+    .and_then(|(client_id, client_secret)| {
+        service.issue(
+            field("grant_type").unwrap_or_default(),
+            &client_id,
+            &client_secret,
+            field("scope"),
+        )
+    });
+    match result {
+        Ok(token) => (Status::Ok, Json(json!(token))),
+        Err(e) => {
+            let status = match e {
+                token::TokenError::InvalidClient => Status::Unauthorized,
+                token::TokenError::ServerError => Status::InternalServerError,
+                _ => Status::BadRequest,
+            };
+            (status, Json(json!({"error" : e.code()})))
+        }
+    }
+}
+
+/// Public key of the built-in token service
+#[get("/.well-known/jwks.json")]
+fn get_jwks(service: &State<TokenService>) -> Json<serde_json::Value> {
+    let keys: Vec<&PublicJwk> = vec![&service.jwk];
+    Json(json!({"keys" : keys}))
+}
+
 /// Getter for the /ping URI. allow to execute and ping DB connection each times method is get
 #[get("/api/ping")]
-async fn ping(db: &State<MongoRepo>) -> String {
+async fn ping(db: &MongoRepo) -> String {
     match mongo::mongo::ping_db(&db).await {
         Ok(()) => "Pinged your deployment. You successfully connected to MongoDB!".to_string(),
         Err(e) => format!("Error ping db return error: {}", e.to_string()),
     }
 }
 
-/// Getter for the /ping URI. allow to execute and ping DB connection each times method is get
+/// Create a User, fields are validated
 #[post("/api/user", data = "<user>")]
 async fn post_user(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     user: Json<User>,
+    actor: Actor,
+    _scope: RequireScope<UsersWrite>,
 ) -> Result<Json<InsertOneResult>, Status> {
-    let data = User {
-        id: None,
-        name: user.name.to_owned(),
-        location: user.location.to_owned(),
-        title: user.title.to_owned(),
-    };
-
-    let result = mongo_users::create_user(db, data).await;
+    let result = mongo_users::create_user(db, user.into_inner(), &actor).await;
     match result {
         Ok(user) => Ok(Json(user)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(e) => {
+            eprintln!("Create user Error : {}", e);
+            Err(e.status())
+        }
     }
 }
 
+/// Identity of the caller : validated claims, linked user and the operations its scopes authorize
+#[get("/api/me")]
+async fn get_me(db: &MongoRepo, jwt: JWT) -> Result<Json<serde_json::Value>, Status> {
+    let claims = jwt.claims;
+    let linked = mongo_users::linked_user(db, claims.sub.as_deref(), claims.email.as_deref());
+    let user = match linked.await {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Linked user Error : {}", e);
+            return Err(e.status());
+        }
+    };
+
+    Ok(Json(json!({
+        "subject" : claims.sub,
+        "scopes" : claims.scope.split_whitespace().collect::<Vec<_>>(),
+        "issuer" : claims.iss,
+        "expires" : claims.exp,
+        "tenant" : claims.tenant,
+        "user" : user,
+        "operations" : scope::operations(&claims.scope),
+    })))
+}
+
 /// Retrieve a User from an MongoDB Atlas OID.
 #[get("/api/user/<path>")]
-async fn get_user(db: &State<MongoRepo>, path: String) -> Result<Json<User>, Status> {
-    let id = path;
-    if id.is_empty() {
-        return Err(Status::BadRequest);
-    }
-    let user_detail = mongo_users::get_user(db, &id).await;
+async fn get_user(
+    db: &MongoRepo,
+    path: String,
+    _scope: RequireScope<UsersRead>,
+) -> Result<Json<User>, Status> {
+    let user_detail = mongo_users::get_user(db, &path).await;
     match user_detail {
         Ok(user) => Ok(Json(user)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(e) => Err(e.status()),
     }
 }
 
-/// Retrieve all Users API
-#[get("/api/users")]
-async fn get_users(db: &State<MongoRepo>) -> Result<Json<Vec<User>>, Status> {
-    let users = mongo_users::get_all_users(db).await;
+/// Retrieve Users API, filtered on the start of name, location and title
+#[get("/api/users?<name>&<location>&<title>&<page>&<limit>")]
+async fn get_users(
+    db: &MongoRepo,
+    name: Option<String>,
+    location: Option<String>,
+    title: Option<String>,
+    page: Option<i64>,
+    limit: Option<i64>,
+    _scope: RequireScope<UsersRead>,
+) -> Result<Json<Vec<User>>, Status> {
+    let filter = mongo_users::users_filter(name, location, title);
+    let users =
+        mongo_users::get_all_users(db, filter, (page.unwrap_or(1), limit.unwrap_or(10))).await;
     match users {
         Ok(users) => Ok(Json(users)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(e) => {
+            eprintln!("Get users Error : {}", e);
+            Err(e.status())
+        }
     }
 }
 
-#[delete("/api/user/<path>")]
-async fn delete_user(db: &State<MongoRepo>, path: String) -> Result<Json<&str>, Status> {
-    let id = path;
-    if id.is_empty() {
-        return Err(Status::BadRequest);
+/// Replace a User
+#[put("/api/user/<path>", data = "<user>")]
+async fn put_user(
+    db: &MongoRepo,
+    path: String,
+    user: Json<User>,
+    actor: Actor,
+    _scope: RequireScope<UsersWrite>,
+) -> Result<Json<User>, Status> {
+    let result = mongo_users::update_user(db, &path, user.into_inner(), &actor).await;
+    match result {
+        Ok(user) => Ok(Json(user)),
+        Err(e) => {
+            eprintln!("Update user Error : {}", e);
+            Err(e.status())
+        }
     }
-    let result = mongo_users::delete_user(db, &id).await;
+}
+
+/// Update the fields of a User given in the body
+#[patch("/api/user/<path>", data = "<patch>")]
+async fn patch_user(
+    db: &MongoRepo,
+    path: String,
+    patch: Json<UserPatch>,
+    actor: Actor,
+    _scope: RequireScope<UsersWrite>,
+) -> Result<Json<User>, Status> {
+    let result = mongo_users::patch_user(db, &path, patch.into_inner(), &actor).await;
     match result {
-        Ok(res) => {
-            if res.deleted_count == 1 {
-                return Ok(Json("User successfully deleted!"));
-            } else {
-                return Err(Status::NotFound);
-            }
+        Ok(user) => Ok(Json(user)),
+        Err(e) => {
+            eprintln!("Patch user Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// Roles of a User
+#[get("/api/user/<path>/roles")]
+async fn get_user_roles(
+    db: &MongoRepo,
+    path: String,
+    _scope: RequireScope<UsersRead>,
+) -> Result<Json<Vec<String>>, Status> {
+    match mongo_users::get_user(db, &path).await {
+        Ok(user) => Ok(Json(user.roles)),
+        Err(e) => Err(e.status()),
+    }
+}
+
+/// Current policies a User is assigned to, with the role on each
+#[get("/api/user/<path>/policies")]
+async fn get_user_policies(
+    db: &MongoRepo,
+    path: String,
+    _scope: RequireScope<UsersRead>,
+) -> Result<Json<Vec<Assignment>>, Status> {
+    if let Err(e) = mongo_users::get_user(db, &path).await {
+        return Err(e.status());
+    }
+    match mongo_assignments::user_assignments(db, &path).await {
+        Ok(assignments) => Ok(Json(assignments)),
+        Err(e) => {
+            eprintln!("User policies Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// Grant a role to a User
+#[put("/api/user/<path>/roles/<role>")]
+async fn put_user_role(
+    db: &MongoRepo,
+    path: String,
+    role: String,
+    actor: Actor,
+    _scope: RequireScope<UsersWrite>,
+) -> Result<Json<User>, Status> {
+    match mongo_users::add_role(db, &path, &role, &actor).await {
+        Ok(user) => Ok(Json(user)),
+        Err(e) => {
+            eprintln!("Grant role Error : {}", e);
+            Err(e.status())
         }
-        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Revoke a role of a User
+#[delete("/api/user/<path>/roles/<role>")]
+async fn delete_user_role(
+    db: &MongoRepo,
+    path: String,
+    role: String,
+    actor: Actor,
+    _scope: RequireScope<UsersWrite>,
+) -> Result<Json<User>, Status> {
+    match mongo_users::remove_role(db, &path, &role, &actor).await {
+        Ok(user) => Ok(Json(user)),
+        Err(e) => {
+            eprintln!("Revoke role Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+#[delete("/api/user/<path>")]
+async fn delete_user(
+    db: &MongoRepo,
+    path: String,
+    actor: Actor,
+    _scope: RequireScope<UsersWrite>,
+) -> Result<Json<&'static str>, Status> {
+    let result = mongo_users::delete_user(db, &path, &actor).await;
+    match result {
+        Ok(()) => Ok(Json("User successfully deleted!")),
+        Err(e) => {
+            eprintln!("Delete user Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// Read a SCIM json body, a malformed body is answered with a SCIM invalidSyntax error
+async fn scim_body<T: serde::de::DeserializeOwned>(data: Data<'_>) -> Result<T, ScimResponse> {
+    let body = match data.open(1.mebibytes()).into_string().await {
+        Ok(b) => b.into_inner(),
+        Err(_e) => {
+            return Err(ScimResponse::error(
+                Status::BadRequest,
+                Some("invalidSyntax"),
+                "Body could not be read.",
+            ));
+        }
+    };
+    match serde_json::from_str(&body) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(ScimResponse::error(
+            Status::BadRequest,
+            Some("invalidSyntax"),
+            &e.to_string(),
+        )),
+    }
+}
+
+fn scim_error(e: error::ApiError) -> ScimResponse {
+    eprintln!("SCIM Error : {}", e);
+    ScimResponse::from_api_error(&e)
+}
+
+/// SCIM 2.0 list of users, filtered (RFC 7644 filter) and paginated by startIndex and count
+#[get("/scim/v2/Users?<filter>&<startIndex>&<count>")]
+#[allow(non_snake_case)]
+async fn scim_get_users(
+    db: &MongoRepo,
+    _scope: RequireScope<UsersRead>,
+    filter: Option<String>,
+    startIndex: Option<u64>,
+    count: Option<u64>,
+) -> ScimResponse {
+    let filter = match mongo_scim::parse_filter(filter.as_deref().unwrap_or("")) {
+        Ok(f) => f,
+        Err(e) => return scim_error(e),
+    };
+    let start_index = startIndex.unwrap_or(1).max(1);
+    let count = count.unwrap_or(100).min(mongo_scim::MAX_COUNT);
+
+    match mongo_scim::list_users(db, filter, start_index, count).await {
+        Ok((total, users)) => ScimResponse::ok(
+            Status::Ok,
+            &ScimListResponse {
+                schemas: vec![String::from(scim_model::SCHEMA_LIST)],
+                total_results: total,
+                start_index,
+                items_per_page: users.len() as u64,
+                resources: users.iter().map(ScimUser::from_user).collect(),
+            },
+        ),
+        Err(e) => scim_error(e),
+    }
+}
+
+#[get("/scim/v2/Users/<path>")]
+async fn scim_get_user(
+    db: &MongoRepo,
+    _scope: RequireScope<UsersRead>,
+    path: String,
+) -> ScimResponse {
+    match mongo_users::get_user(db, &path).await {
+        Ok(user) => ScimResponse::ok(Status::Ok, &ScimUser::from_user(&user)),
+        Err(e) => scim_error(e),
+    }
+}
+
+/// SCIM user creation, answered with 201 and the Location of the new user.
+/// Only userName is required.
+#[post("/scim/v2/Users", data = "<user>")]
+async fn scim_post_user(
+    db: &MongoRepo,
+    actor: Actor,
+    user: Data<'_>,
+    _scope: RequireScope<UsersWrite>,
+) -> ScimResponse {
+    let user: ScimUser = match scim_body(user).await {
+        Ok(u) => u,
+        Err(r) => return r,
+    };
+
+    let created = match mongo_users::create_provisioned_user(db, user.to_user(None), &actor).await {
+        Ok(o) => o,
+        Err(e) => return scim_error(e),
+    };
+    let id = created
+        .inserted_id
+        .as_object_id()
+        .map(|o| o.to_hex())
+        .unwrap_or_default();
+
+    match mongo_users::get_user(db, &id).await {
+        Ok(user) => {
+            let mut response = ScimResponse::ok(Status::Created, &ScimUser::from_user(&user));
+            response.location = Some(format!("{}/{}", scim_model::USERS_PATH, id));
+            response
+        }
+        Err(e) => scim_error(e),
+    }
+}
+
+/// SCIM user replacement, attributes missing from the body are cleared
+#[put("/scim/v2/Users/<path>", data = "<user>")]
+async fn scim_put_user(
+    db: &MongoRepo,
+    actor: Actor,
+    path: String,
+    user: Data<'_>,
+    _scope: RequireScope<UsersWrite>,
+) -> ScimResponse {
+    let user: ScimUser = match scim_body(user).await {
+        Ok(u) => u,
+        Err(r) => return r,
+    };
+
+    match mongo_users::update_provisioned_user(db, &path, user.to_user(None), &actor).await {
+        Ok(user) => ScimResponse::ok(Status::Ok, &ScimUser::from_user(&user)),
+        Err(e) => scim_error(e),
+    }
+}
+
+/// SCIM PatchOp : the operations are applied to the current user, then the user is replaced
+#[patch("/scim/v2/Users/<path>", data = "<patch>")]
+async fn scim_patch_user(
+    db: &MongoRepo,
+    actor: Actor,
+    path: String,
+    patch: Data<'_>,
+    _scope: RequireScope<UsersWrite>,
+) -> ScimResponse {
+    let patch: ScimPatch = match scim_body(patch).await {
+        Ok(p) => p,
+        Err(r) => return r,
+    };
+    if !patch.schemas.iter().any(|s| s == scim_model::SCHEMA_PATCH) {
+        return ScimResponse::error(
+            Status::BadRequest,
+            Some("invalidSyntax"),
+            "PatchOp schema is missing.",
+        );
+    }
+
+    let current = match mongo_users::get_user(db, &path).await {
+        Ok(o) => o,
+        Err(e) => return scim_error(e),
+    };
+    let mut user = ScimUser::from_user(&current);
+    if let Err(e) = mongo_scim::apply_operations(&mut user, &patch.operations) {
+        return scim_error(e);
+    }
+
+    match mongo_users::update_provisioned_user(db, &path, user.to_user(None), &actor).await {
+        Ok(user) => ScimResponse::ok(Status::Ok, &ScimUser::from_user(&user)),
+        Err(e) => scim_error(e),
+    }
+}
+
+#[delete("/scim/v2/Users/<path>")]
+async fn scim_delete_user(
+    db: &MongoRepo,
+    actor: Actor,
+    path: String,
+    _scope: RequireScope<UsersWrite>,
+) -> Result<Status, ScimResponse> {
+    match mongo_users::delete_user(db, &path, &actor).await {
+        Ok(()) => Ok(Status::NoContent),
+        Err(e) => Err(scim_error(e)),
     }
 }
 
 /// Post Any
+/// A retry with the same Idempotency-Key header replays the first response
+/// instead of creating a duplicate, or takes over a key left in progress for more than a minute
 #[post("/api/any", data = "<any>")]
-async fn post_any(db: &State<MongoRepo>, any: Data<'_>) -> Result<Json<serde_json::Value>, Status> {
-    let body = any.open(2.mebibytes()).into_string().await;
+async fn post_any(
+    db: &MongoRepo,
+    actor: Actor,
+    idempotency: IdempotencyKey,
+    any: Data<'_>,
+    _scope: RequireScope<PoliciesWrite>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let body = match any.open(2.mebibytes()).into_string().await {
+        Ok(b) => b.into_inner(),
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            return Err(Status::BadRequest);
+        }
+    };
 
-    let request: serde_json::Value = match serde_json::from_str(&body.unwrap()) {
+    let request: serde_json::Value = match serde_json::from_str(&body) {
         Ok(r) => r,
         Err(e) => {
             eprintln!("Error {:?}", e);
@@ -115,16 +521,48 @@ async fn post_any(db: &State<MongoRepo>, any: Data<'_>) -> Result<Json<serde_jso
         }
     };
 
-    let result = mongo_any::create_any(db, request).await;
+    // lease of the Idempotency-Key held by this request
+    let mut lease = None;
+    if let Some(key) = &idempotency.0 {
+        let hash = mongo_idempotency::request_hash(&body);
+        match mongo_idempotency::begin(db, &actor, key, &hash).await {
+            Ok(Reservation::Replay(response)) => return Ok(Json(response)),
+            Ok(Reservation::Acquired(lock)) => lease = Some((key, lock)),
+            Err(e) => match e.kind() {
+                LocalError::ConflictError(_) => return Err(Status::Conflict),
+                LocalError::ContextError(_) => return Err(Status::BadRequest),
+                _ => return Ok(Json(json!({"exception" : e.to_string()}))),
+            },
+        }
+    }
+
+    let result = match &lease {
+        Some((key, lock)) => mongo_any::create_any_idempotent(db, request, &actor, key, lock).await,
+        None => mongo_any::create_any(db, request, &actor).await,
+    };
     match result {
         Ok(policy) => Ok(Json(policy.content)),
-        Err(e) => Ok(Json(json!({"exception" : e.to_string()}))),
+        Err(e) => {
+            if let Some((key, lock)) = &lease {
+                if let Err(ee) = mongo_idempotency::abandon(db, &actor, key, lock).await {
+                    eprintln!("Idempotency key not released : {}", ee);
+                }
+            }
+            match e.kind() {
+                LocalError::ConflictError(_) => Err(Status::Conflict),
+                _ => Ok(Json(json!({"exception" : e.to_string()}))),
+            }
+        }
     }
 }
 
 /// Get any from Oid
 #[get("/api/any/<path>")]
-async fn get_any(db: &State<MongoRepo>, path: String) -> Result<Json<serde_json::Value>, Status> {
+async fn get_any(
+    db: &MongoRepo,
+    path: String,
+    _scope: RequireScope<PoliciesRead>,
+) -> Result<Json<serde_json::Value>, Status> {
     let id = path;
     if id.is_empty() {
         return Err(Status::BadRequest);
@@ -140,15 +578,17 @@ async fn get_any(db: &State<MongoRepo>, path: String) -> Result<Json<serde_json:
 /// Update any from oid
 #[put("/api/any/<path>")]
 async fn update_any_empty(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     path: String,
+    actor: Actor,
+    _scope: RequireScope<PoliciesWrite>,
 ) -> Result<Json<serde_json::Value>, Status> {
     let id = path;
     if id.is_empty() {
         return Err(Status::BadRequest);
     }
 
-    let result = mongo_any::update_any(db, json!({}), id).await;
+    let result = mongo_any::update_any(db, json!({}), id, &actor).await;
     match result {
         Ok(policy) => Ok(Json(policy.content)),
         Err(e) => Ok(Json(json!({"exception" : e.to_string()}))),
@@ -156,11 +596,13 @@ async fn update_any_empty(
 }
 
 /// Update any from oid
-#[put("/api/any/<path>", data = "<any>")]
+#[put("/api/any/<path>", data = "<any>", rank = 2)]
 async fn update_any(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     path: String,
     any: Data<'_>,
+    actor: Actor,
+    _scope: RequireScope<PoliciesWrite>,
 ) -> Result<Json<serde_json::Value>, Status> {
     let id = path;
     if id.is_empty() {
@@ -177,40 +619,65 @@ async fn update_any(
         }
     };
 
-    let result = mongo_any::update_any(db, request, id).await;
+    let result = mongo_any::update_any(db, request, id, &actor).await;
     match result {
         Ok(policy) => Ok(Json(policy.content)),
         Err(e) => Ok(Json(json!({"exception" : e.to_string()}))),
     }
 }
 
+/// Create or version the policy identified by its source system reference (business key).
+/// One URL segment per business key path, in the configured order : /api/any/by-ref/crm/P-42
+/// Ranked before PUT /api/any/<path> and /api/any/<path>/hold, which match the same URLs.
+/// Not found when no business key is configured.
+#[put("/api/any/by-ref/<values..>", data = "<any>", rank = 1)]
+async fn upsert_any_by_ref(
+    db: &MongoRepo,
+    key: &State<BusinessKey>,
+    values: Segments<'_, Path>,
+    actor: Actor,
+    any: Data<'_>,
+    _scope: RequireScope<PoliciesWrite>,
+) -> Result<(Status, Json<serde_json::Value>), Status> {
+    let body = any.open(2.mebibytes()).into_string().await;
+
+    let request: serde_json::Value = match body.map(|b| serde_json::from_str(&b)) {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => {
+            eprintln!("Error {:?}", e);
+            return Err(Status::BadRequest);
+        }
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            return Err(Status::BadRequest);
+        }
+    };
+
+    let values: Vec<&str> = values.collect();
+    let result = mongo_any::upsert_by_ref(db, key, &values, request, &actor).await;
+    match result {
+        Ok((policy, true)) => Ok((Status::Created, Json(policy.content))),
+        Ok((policy, false)) => Ok((Status::Ok, Json(policy.content))),
+        Err(e) => match e.kind() {
+            LocalError::ConflictError(_)
+            | LocalError::DataNotFoundError(_)
+            | LocalError::ContextError(_) => Err(e.status()),
+            _ => Ok((Status::Ok, Json(json!({"exception" : e.to_string()})))),
+        },
+    }
+}
+
 /// Retrieve all Any API
 #[get("/api/anys?<date>&<policyholder>&<page>&<limit>")]
 async fn get_all_any(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     date: Option<String>,
     policyholder: Option<String>,
     page: Option<i64>,
     limit: Option<i64>,
-    key: Result<JWT, NetworkResponse>
+    _scope: RequireScope<PoliciesRead>,
 ) -> Result<Json<Vec<Policy>>, Status> {
 
-    let key = match key {
-        Ok(key) => key,
-        Err(err) => {
-            //println!("Error init connection server api {}", err.);
-            //return Err(Status::BadRequest);
-            match (err) {
-                NetworkResponse::Unauthorized(e) => {
-                    return Err(Status::Unauthorized);
-                },
-                _ => {
-                    return Err(Status::BadRequest);
-                }
-            }
-        },
-    };
-
     let pagev = match page {
         Some(o) => o,
         None => 1,
@@ -232,12 +699,61 @@ async fn get_all_any(
     }
 }
 
+/// Full text search over policy content, most relevant first, paginated as /api/anys
+#[get("/api/anys/search?<q>&<page>&<limit>")]
+async fn search_any(
+    db: &MongoRepo,
+    search: &State<SearchConfig>,
+    q: String,
+    page: Option<i64>,
+    limit: Option<i64>,
+    _scope: RequireScope<PoliciesRead>,
+) -> Result<Json<Vec<SearchHit>>, Status> {
+    let pagev = page.unwrap_or(1);
+    let limitv = limit.unwrap_or(10);
+
+    let records = mongo_search::search_any(db, search, &q, (pagev, limitv)).await;
+
+    match records {
+        Ok(record) => Ok(Json(record)),
+        Err(e) => {
+            eprintln!("Search anys Error : {}", e);
+            Err(Status::BadRequest)
+        }
+    }
+}
+
+/// Policy statistics : counts grouped by month, policyholder, source or status.
+/// `date` is the policy date used for month grouping and the from/to range, requestDate by default.
+#[get("/api/anys/stats/<dimension>?<date>&<from>&<to>")]
+async fn stats_any(
+    db: &MongoRepo,
+    dimension: String,
+    date: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    _scope: RequireScope<PoliciesRead>,
+) -> Result<Json<Vec<serde_json::Value>>, Status> {
+    let date_field = date.unwrap_or_else(|| String::from("requestDate"));
+
+    let records = mongo_stats::stats_any(db, &dimension, &date_field, from, to).await;
+
+    match records {
+        Ok(record) => Ok(Json(record)),
+        Err(e) => {
+            eprintln!("Stats anys Error : {}", e);
+            Err(Status::BadRequest)
+        }
+    }
+}
+
 /// Count all Any API
 #[get("/api/countanys?<date>&<policyholder>")]
 async fn count_all_any(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     date: Option<String>,
     policyholder: Option<String>,
+    _scope: RequireScope<PoliciesRead>,
 ) -> Result<Json<serde_json::Value>, Status> {
     let records = mongo_any::count_all_any(db, date, policyholder).await;
 
@@ -250,17 +766,39 @@ async fn count_all_any(
     }
 }
 
+/// Pull-based change feed : created, updated and deleted policies since a sync token, in commit order
+#[get("/api/any/changes?<since>&<limit>")]
+async fn get_changes(
+    db: &MongoRepo,
+    since: Option<String>,
+    limit: Option<i64>,
+    _scope: RequireScope<PoliciesRead>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let limitv = match limit {
+        Some(o) if o > 0 => o,
+        _ => 100,
+    };
+
+    let result = mongo_changes::get_changes(db, since, limitv).await;
+    match result {
+        Ok(changes) => Ok(Json(changes)),
+        Err(e) => Ok(Json(json!({"exception" : e.to_string()}))),
+    }
+}
+
 /// Delete policy to an input Oid
 #[delete("/api/any/<path>")]
 async fn delete_any(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     path: String,
+    actor: Actor,
+    _scope: RequireScope<PoliciesWrite>,
 ) -> Result<Json<serde_json::Value>, Status> {
     let id = path;
     if id.is_empty() {
         return Err(Status::BadRequest);
     }
-    let result = mongo_any::delete_any(db, &id).await;
+    let result = mongo_any::delete_any(db, &id, &actor).await;
     match result {
         Ok(res) => {
             if res.deleted_count == 1 {
@@ -273,6 +811,419 @@ async fn delete_any(
     }
 }
 
+/// Audit trail of mutations, filtered by actor, policy Oid and date range
+#[get("/api/audit?<actor>&<policy>&<from>&<to>&<page>&<limit>")]
+#[allow(clippy::too_many_arguments)]
+async fn get_audit(
+    db: &MongoRepo,
+    actor: Option<String>,
+    policy: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<i64>,
+    limit: Option<i64>,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let pagev = page.unwrap_or(1);
+    let limitv = limit.unwrap_or(10);
+
+    let result = mongo_audit::get_audit(db, actor, policy, from, to, (pagev, limitv)).await;
+    match result {
+        Ok(records) => Ok(Json(json!(records))),
+        Err(e) => Ok(Json(json!({"exception" : e.to_string()}))),
+    }
+}
+
+/// Attach a file to a policy, stored in GridFS. The content type of the request is kept for downloads.
+#[post("/api/any/<path>/attachments?<filename>", data = "<file>")]
+async fn post_attachment(
+    db: &MongoRepo,
+    path: String,
+    filename: String,
+    content_type: Option<&ContentType>,
+    file: Data<'_>,
+    actor: Actor,
+    _scope: RequireScope<PoliciesWrite>,
+) -> Result<(Status, Json<serde_json::Value>), Status> {
+    let content = match file.open(20.mebibytes()).into_bytes().await {
+        Ok(b) if b.is_complete() => b.into_inner(),
+        Ok(_b) => return Err(Status::PayloadTooLarge),
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            return Err(Status::BadRequest);
+        }
+    };
+    let content_type = content_type
+        .map(|c| c.to_string())
+        .unwrap_or_else(|| ContentType::Binary.to_string());
+
+    let result =
+        mongo_attachments::add_attachment(db, &path, &filename, &content_type, &content, &actor)
+            .await;
+    match result {
+        Ok(attachment) => Ok((Status::Created, Json(json!(attachment)))),
+        Err(e) => Ok((Status::Ok, Json(json!({"exception" : e.to_string()})))),
+    }
+}
+
+/// List the attachments of a policy
+#[get("/api/any/<path>/attachments")]
+async fn get_attachments(
+    db: &MongoRepo,
+    path: String,
+    _scope: RequireScope<PoliciesRead>,
+) -> Json<serde_json::Value> {
+    match mongo_attachments::list_attachments(db, &path).await {
+        Ok(attachments) => Json(json!(attachments)),
+        Err(e) => Json(json!({"exception" : e.to_string()})),
+    }
+}
+
+/// Download an attachment, streamed with its content type
+#[get("/api/any/<path>/attachments/<file>")]
+async fn get_attachment(
+    db: &MongoRepo,
+    path: String,
+    file: String,
+    _scope: RequireScope<PoliciesRead>,
+) -> Result<AttachmentDownload<impl futures::Stream<Item = Vec<u8>>>, Status> {
+    match mongo_attachments::open_attachment(db, &path, &file).await {
+        Ok((attachment, stream)) => Ok(AttachmentDownload { attachment, stream }),
+        Err(e) => match e.kind() {
+            LocalError::DataNotFoundError(_) => Err(Status::NotFound),
+            LocalError::OidFormatError(_) => Err(Status::BadRequest),
+            _ => {
+                eprintln!("Attachment download Error : {}", e);
+                Err(Status::InternalServerError)
+            }
+        },
+    }
+}
+
+/// Remove an attachment of a policy
+#[delete("/api/any/<path>/attachments/<file>")]
+async fn delete_attachment(
+    db: &MongoRepo,
+    path: String,
+    file: String,
+    actor: Actor,
+    _scope: RequireScope<PoliciesWrite>,
+) -> Json<serde_json::Value> {
+    match mongo_attachments::delete_attachment(db, &path, &file, &actor).await {
+        Ok(()) => Json(json!({"result" : "Attachment deleted."})),
+        Err(e) => Json(json!({"exception" : e.to_string()})),
+    }
+}
+
+/// Assign a user to a policy with a role (broker, underwriter, handler)
+#[post("/api/any/<path>/assignments", data = "<assignment>")]
+async fn post_assignment(
+    db: &MongoRepo,
+    path: String,
+    assignment: Json<AssignmentRequest>,
+    actor: Actor,
+    _scope: RequireScope<PoliciesWrite>,
+) -> Result<(Status, Json<serde_json::Value>), Status> {
+    match mongo_assignments::assign(db, &path, assignment.into_inner(), &actor).await {
+        Ok(assignment) => Ok((Status::Created, Json(json!(assignment)))),
+        Err(e) => Ok((Status::Ok, Json(json!({"exception" : e.to_string()})))),
+    }
+}
+
+/// List the users assigned to a policy
+#[get("/api/any/<path>/assignments")]
+async fn get_assignments(
+    db: &MongoRepo,
+    path: String,
+    _scope: RequireScope<PoliciesRead>,
+) -> Json<serde_json::Value> {
+    match mongo_assignments::policy_assignments(db, &path).await {
+        Ok(assignments) => Json(json!(assignments)),
+        Err(e) => Json(json!({"exception" : e.to_string()})),
+    }
+}
+
+/// Remove the assignment of a user to a policy
+#[delete("/api/any/<path>/assignments/<user>/<role>")]
+async fn delete_assignment(
+    db: &MongoRepo,
+    path: String,
+    user: String,
+    role: String,
+    actor: Actor,
+    _scope: RequireScope<PoliciesWrite>,
+) -> Json<serde_json::Value> {
+    match mongo_assignments::unassign(db, &path, &user, &role, &actor).await {
+        Ok(()) => Json(json!({"result" : "Assignment deleted."})),
+        Err(e) => Json(json!({"exception" : e.to_string()})),
+    }
+}
+
+/// Put a policy under legal hold, exempting all its versions from retention purges
+#[put("/api/any/<path>/hold", data = "<hold>", rank = 2)]
+async fn put_hold(
+    db: &MongoRepo,
+    path: String,
+    hold: Option<Json<serde_json::Value>>,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let reason = hold.and_then(|h| h["reason"].as_str().map(|r| r.to_string()));
+    let data = LegalHold {
+        policy_id: path,
+        reason,
+    };
+
+    let result = mongo_retention::hold(db, data, &actor).await;
+    match result {
+        Ok(()) => Ok(Json(json!({"result" : "Legal hold set."}))),
+        Err(e) => match e.kind() {
+            LocalError::DataNotFoundError(_) => Err(Status::NotFound),
+            _ => Ok(Json(json!({"exception" : e.to_string()}))),
+        },
+    }
+}
+
+/// Release the legal hold of a policy
+#[delete("/api/any/<path>/hold")]
+async fn delete_hold(
+    db: &MongoRepo,
+    path: String,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let result = mongo_retention::release(db, &path, &actor).await;
+    match result {
+        Ok(res) => {
+            if res.deleted_count == 1 {
+                Ok(Json(json!({"result" : "Legal hold released."})))
+            } else {
+                Ok(Json(json!({"exception" : "No result.."})))
+            }
+        }
+        Err(e) => Ok(Json(json!({"exception" : e.to_string()}))),
+    }
+}
+
+/// Create an API key for a service caller, answered once with the key.
+/// API keys are shared by the tenants, the tenant of the key is given in the body,
+/// forced to the tenant of a tenant bound caller.
+#[post("/api/admin/apikeys", data = "<request>")]
+async fn post_api_key(
+    tenants: &State<TenantRepos>,
+    request: Json<ApiKeyRequest>,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<(Status, Json<ApiKeyCreated>), Status> {
+    match mongo_api_keys::create(&tenants.default, request.into_inner(), &actor).await {
+        Ok(key) => Ok((Status::Created, Json(key))),
+        Err(e) => {
+            eprintln!("Create API key Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// Replace the key of an API key, the previous key is rejected at once
+#[post("/api/admin/apikeys/<id>/rotate")]
+async fn rotate_api_key(
+    tenants: &State<TenantRepos>,
+    id: String,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<ApiKeyCreated>, Status> {
+    match mongo_api_keys::rotate(&tenants.default, &id, &actor).await {
+        Ok(key) => Ok(Json(key)),
+        Err(e) => {
+            eprintln!("Rotate API key Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// Revoke an API key
+#[delete("/api/admin/apikeys/<id>")]
+async fn revoke_api_key(
+    tenants: &State<TenantRepos>,
+    id: String,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<Status, Status> {
+    match mongo_api_keys::revoke(&tenants.default, &id, &actor).await {
+        Ok(()) => Ok(Status::NoContent),
+        Err(e) => {
+            eprintln!("Revoke API key Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// Revoke a token by jti, or the tokens of a subject issued before a date.
+/// Enforced at once on this instance, after the revocation refresh on the others.
+/// Revocations apply to every tenant, a caller bound to a tenant is refused.
+#[post("/api/admin/revocations", data = "<request>")]
+async fn post_revocation(
+    tenants: &State<TenantRepos>,
+    cache: &State<RevocationCache>,
+    request: Json<RevocationRequest>,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<(Status, Json<Revocation>), Status> {
+    let result = match mongo_revocations::revocation(request.into_inner(), &actor) {
+        Ok(o) => mongo_revocations::revoke(&tenants.default, o, &actor).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(revocation) => {
+            cache.add(&revocation).await;
+            Ok((Status::Created, Json(revocation)))
+        }
+        Err(e) => {
+            eprintln!("Revocation Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// List the revoked tokens and subjects
+#[get("/api/admin/revocations")]
+async fn get_revocations(
+    tenants: &State<TenantRepos>,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<Vec<Revocation>>, Status> {
+    if let Err(e) = mongo_revocations::global_caller(&actor) {
+        return Err(e.status());
+    }
+    match mongo_revocations::list(&tenants.default).await {
+        Ok(revocations) => Ok(Json(revocations)),
+        Err(e) => Err(e.status()),
+    }
+}
+
+/// Lift a revocation
+#[delete("/api/admin/revocations/<id>")]
+async fn delete_revocation(
+    tenants: &State<TenantRepos>,
+    cache: &State<RevocationCache>,
+    id: String,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<Status, Status> {
+    match mongo_revocations::delete(&tenants.default, &id, &actor).await {
+        Ok(()) => {
+            cache.invalidate().await;
+            Ok(Status::NoContent)
+        }
+        Err(e) => {
+            eprintln!("Delete revocation Error : {}", e);
+            Err(e.status())
+        }
+    }
+}
+
+/// Compare the required indexes with the indexes of the database : missing, extra, mismatched and in progress
+#[get("/api/admin/indexes")]
+async fn index_report(
+    db: &MongoRepo,
+    indexes: &State<Vec<IndexSpec>>,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<serde_json::Value>, Status> {
+    match mongo_indexes::index_report(db, indexes).await {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            eprintln!("Index report Error : {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Dry run of the retention purge : list deleted policies and history versions that would be removed
+#[get("/api/retention/report")]
+async fn retention_report(
+    db: &MongoRepo,
+    retention: &State<RetentionConfig>,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<PurgePlan>, Status> {
+    let result =
+        mongo_retention::plan_purge(db, retention.deleted_days, retention.history_versions).await;
+    match result {
+        Ok(plan) => Ok(Json(plan)),
+        Err(e) => {
+            eprintln!("Retention report Error : {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Run the retention purge now
+#[post("/api/retention/purge")]
+async fn retention_purge(
+    db: &MongoRepo,
+    retention: &State<RetentionConfig>,
+    actor: Actor,
+    _scope: RequireScope<Admin>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let result = retention::run_purge(db, retention, &actor).await;
+    match result {
+        Ok((deleted, history)) => Ok(Json(json!({"deleted" : deleted, "history" : history}))),
+        Err(e) => Ok(Json(json!({"exception" : e}))),
+    }
+}
+
+/// Routes of the API, the token service routes are mounted apart
+fn api_routes() -> Vec<rocket::Route> {
+    routes![
+        ping,
+        get_me,
+        post_user,
+        get_user,
+        get_users,
+        put_user,
+        patch_user,
+        delete_user,
+        get_user_roles,
+        get_user_policies,
+        put_user_role,
+        delete_user_role,
+        scim_get_users,
+        scim_get_user,
+        scim_post_user,
+        scim_put_user,
+        scim_patch_user,
+        scim_delete_user,
+        post_any,
+        get_any,
+        get_all_any,
+        search_any,
+        stats_any,
+        count_all_any,
+        get_changes,
+        get_audit,
+        post_attachment,
+        get_attachments,
+        get_attachment,
+        delete_attachment,
+        post_assignment,
+        get_assignments,
+        delete_assignment,
+        put_hold,
+        delete_hold,
+        retention_report,
+        retention_purge,
+        index_report,
+        post_api_key,
+        rotate_api_key,
+        revoke_api_key,
+        post_revocation,
+        get_revocations,
+        delete_revocation,
+        delete_any,
+        update_any,
+        upsert_any_by_ref
+    ]
+}
+
 /// Main start routines.
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
@@ -290,24 +1241,130 @@ async fn main() -> Result<(), rocket::Error> {
         }
     };
 
-    let _rocket = rocket::build()
-        .manage(repo)
-        .mount(
-            "/",
-            routes![
-                ping,
-                post_user,
-                get_user,
-                get_users,
-                delete_user,
-                post_any,
-                get_any,
-                get_all_any,
-                count_all_any,
-                delete_any,
-                update_any
-            ],
-        )
+    let tenants = match TenantConfig::from_settings(&settings) {
+        Ok(o) => TenantRepos::new(repo, o),
+        Err(e) => {
+            eprintln!("Error tenant configuration : {}", e);
+            process::exit(1);
+        }
+    };
+
+    for user_repo in tenants.all() {
+        match mongo_users::migrate_users(&user_repo).await {
+            Ok(0) => {}
+            Ok(n) => println!("{} users migrated to roles, status and timestamps.", n),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    // one outbox relay per tenant, each reading the outbox of its tenant
+    for tenant_repo in tenants.all() {
+        match outbox::OutboxConfig::from_settings(&settings) {
+            Ok(o) => outbox::start_relay(tenant_repo, o),
+            Err(e) => {
+                eprintln!("Error outbox configuration : {}", e);
+                process::exit(1);
+            }
+        };
+    }
+
+    let key = BusinessKey::from_settings(&settings);
+    let search = SearchConfig::from_settings(&settings);
+    let idempotency_ttl = settings.get::<u64>("idempotency_ttl_hours").unwrap_or(24);
+
+    let indexes = match mongo_indexes::required_indexes(
+        &settings,
+        &key,
+        &search,
+        std::time::Duration::from_secs(idempotency_ttl * 3600),
+    ) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error index configuration : {}", e);
+            process::exit(1);
+        }
+    };
+
+    // index builds may be long on large collections, they do not delay the startup
+    for index_repo in tenants.all() {
+        let index_specs = indexes.clone();
+        rocket::tokio::spawn(async move {
+            for e in mongo_indexes::ensure_indexes(&index_repo, &index_specs).await {
+                eprintln!("{}", e);
+            }
+        });
+    }
+
+    // API keys and revocations are shared by the tenants, read from the default repo
+    let shared_repo = tenants.default.clone();
+    rocket::tokio::spawn(async move {
+        let shared_specs = mongo_indexes::shared_indexes();
+        for e in mongo_indexes::ensure_indexes(&shared_repo, &shared_specs).await {
+            eprintln!("{}", e);
+        }
+    });
+
+    let retention = match RetentionConfig::from_settings(&settings) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error retention configuration : {}", e);
+            process::exit(1);
+        }
+    };
+    for purge_repo in tenants.all() {
+        retention::start_purge_job(purge_repo, &retention);
+    }
+
+    let keys = match jwks::KeySource::from_settings(&settings) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error JWT key configuration : {}", e);
+            process::exit(1);
+        }
+    };
+
+    let validation = match jwt_secure::ValidationConfig::from_settings(&settings) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error JWT validation configuration : {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut app = rocket::build();
+    // opaque tokens are only accepted when an introspection endpoint is configured
+    match introspection::IntrospectionConfig::from_settings(&settings)
+        .and_then(|c| c.map(|c| introspection::Introspector::new(c, &settings)).transpose())
+    {
+        Ok(Some(o)) => app = app.manage(o),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Error introspection configuration : {}", e);
+            process::exit(1);
+        }
+    }
+
+    // the token service routes are only mounted when a signing key is configured
+    if let Some(token_config) = TokenConfig::from_settings(&settings) {
+        match TokenService::new(token_config) {
+            Ok(o) => app = app.manage(o).mount("/", routes![post_token, get_jwks]),
+            Err(e) => {
+                eprintln!("Error token service configuration : {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let _rocket = app
+        .manage(keys)
+        .manage(validation)
+        .manage(tenants)
+        .manage(retention)
+        .manage(RevocationCache::from_settings(&settings))
+        .manage(key)
+        .manage(search)
+        .manage(indexes)
+        .mount("/", api_routes())
         .launch()
         .await?;
 
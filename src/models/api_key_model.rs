@@ -0,0 +1,51 @@
+use mongodb::bson::{self, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+/// API key of a service caller, sent in the X-API-Key header.
+/// Only the SHA-256 of the key is stored, `prefix` identifies the key in listings and audit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub owner: String,
+    #[serde(rename = "keySha256")]
+    pub key_sha256: String,
+    pub prefix: String,
+    pub scope: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<bson::DateTime>,
+    #[serde(rename = "lastUsed", default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<bson::DateTime>,
+    #[serde(default)]
+    pub revoked: bool,
+    #[serde(rename = "createdBy")]
+    pub created_by: String,
+    #[serde(rename = "createdDate")]
+    pub created_date: bson::DateTime,
+}
+
+/// Body of a key creation : { "owner" : "batch-policies", "scope" : "policies:read policies:write", "expiresDays" : 90 }
+/// The key never expires without expiresDays.
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyRequest {
+    pub owner: String,
+    pub scope: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(rename = "expiresDays", default)]
+    pub expires_days: Option<u32>,
+}
+
+/// Answer of a key creation or rotation, the only time the key is readable
+#[derive(Debug, Serialize)]
+pub struct ApiKeyCreated {
+    pub id: String,
+    pub key: String,
+    pub prefix: String,
+    pub owner: String,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<bson::DateTime>,
+}
@@ -0,0 +1,40 @@
+use mongodb::bson::{self, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+/// Part a user plays on a policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AssignmentRole {
+    Broker,
+    Underwriter,
+    Handler,
+}
+
+/// User assigned to a policy with a role.
+/// `policyId` follows the current version of the policy, `archived` is set when the policy is deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Assignment {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(rename = "policyId")]
+    pub policy_id: String,
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub role: AssignmentRole,
+    #[serde(rename = "previousObjectIds", default)]
+    pub previous_object_ids: Vec<String>,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(rename = "assignedBy")]
+    pub assigned_by: String,
+    #[serde(rename = "assignedDate")]
+    pub assigned_date: bson::DateTime,
+}
+
+/// Body of an assignment : { "userId" : "655c7c5b037c912bb7ce3973", "role" : "underwriter" }
+#[derive(Debug, Deserialize)]
+pub struct AssignmentRequest {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub role: AssignmentRole,
+}
@@ -0,0 +1,49 @@
+use futures::stream::Stream;
+use rocket::http::{ContentType, Header};
+use rocket::request::Request;
+use rocket::response::{self, stream::ByteStream, Responder};
+use serde::{Deserialize, Serialize};
+
+/// File attached to a policy, stored in GridFS.
+/// `policyId` follows the current version of the policy, `archived` is set when the policy is deleted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    #[serde(rename = "policyId")]
+    pub policy_id: String,
+    pub filename: String,
+    #[serde(rename = "contentType")]
+    pub content_type: String,
+    pub length: u64,
+    #[serde(rename = "uploadDate")]
+    pub upload_date: String,
+    pub archived: bool,
+}
+
+/// Attachment content streamed to the client with its content type and file name
+pub struct AttachmentDownload<S> {
+    pub attachment: Attachment,
+    pub stream: S,
+}
+
+impl<'r, S> Responder<'r, 'r> for AttachmentDownload<S>
+where
+    S: Stream<Item = Vec<u8>> + Send + 'static,
+{
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let mut response = ByteStream(self.stream).respond_to(req)?;
+
+        let content_type = ContentType::parse_flexible(&self.attachment.content_type)
+            .unwrap_or(ContentType::Binary);
+        response.set_header(content_type);
+        response.set_header(Header::new(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}\"",
+                self.attachment.filename.replace('"', "")
+            ),
+        ));
+
+        Ok(response)
+    }
+}
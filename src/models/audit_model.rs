@@ -0,0 +1,39 @@
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+
+use crate::jwt_secure::{NetworkResponse, JWT};
+
+/// Authenticated caller of a route, recorded in the audit trail of mutations.
+/// `subject` is the token subject, else its client id, empty when the token has neither.
+/// `tenant` is the tenant claim of the caller, None for a caller of every tenant.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Actor {
+    pub subject: String,
+    pub scope: String,
+    pub ip: Option<String>,
+    pub route: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Actor {
+    type Error = NetworkResponse;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, NetworkResponse> {
+        let jwt = rocket::outcome::try_outcome!(req.guard::<JWT>().await);
+
+        let route = match req.route() {
+            Some(r) => format!("{} {}", r.method, r.uri),
+            None => format!("{} {}", req.method(), req.uri()),
+        };
+
+        Outcome::Success(Actor {
+            subject: jwt.claims.sub.or(jwt.claims.client_id).unwrap_or_default(),
+            scope: jwt.claims.scope,
+            ip: req.client_ip().map(|ip| ip.to_string()),
+            route,
+            tenant: jwt.claims.tenant,
+        })
+    }
+}
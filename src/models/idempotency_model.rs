@@ -0,0 +1,20 @@
+use rocket::request::{FromRequest, Outcome, Request};
+
+/// Optional `Idempotency-Key` header sent by clients retrying a creation
+#[derive(Debug)]
+pub struct IdempotencyKey(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        let key = req
+            .headers()
+            .get_one("Idempotency-Key")
+            .map(|k| k.trim().to_string())
+            .filter(|k| !k.is_empty());
+
+        Outcome::Success(IdempotencyKey(key))
+    }
+}
@@ -1,2 +1,10 @@
+pub mod api_key_model;
+pub mod assignment_model;
+pub mod attachment_model;
+pub mod audit_model;
+pub mod idempotency_model;
 pub mod policy_model;
+pub mod retention_model;
+pub mod revocation_model;
+pub mod scim_model;
 pub mod user_model;
@@ -8,3 +8,11 @@ pub struct Policy {
     pub id: Option<ObjectId>,
     pub content: serde_json::Value,
 }
+
+/// Full text search result : policy content with its relevance score and highlighted matched fields
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub content: serde_json::Value,
+    pub score: f64,
+    pub highlights: serde_json::Map<String, serde_json::Value>,
+}
@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+/// Records a retention purge removes, or would remove on a dry run
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PurgePlan {
+    /// Oids of deleted policies kept longer than the retention period
+    pub deleted: Vec<String>,
+    /// Oids of history versions beyond the number of versions kept per policy
+    pub history: Vec<String>,
+    /// Oids of policies exempted from the purge by a legal hold
+    pub held: Vec<String>,
+}
+
+/// Legal hold exempting a policy, and all its versions, from retention purges
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LegalHold {
+    #[serde(rename = "policyId")]
+    pub policy_id: String,
+    pub reason: Option<String>,
+}
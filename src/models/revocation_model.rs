@@ -0,0 +1,40 @@
+use mongodb::bson::{self, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+/// Revoked token (`jti`) or subject. A subject revocation rejects the tokens issued before `revokedBefore`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revocation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(
+        rename = "revokedBefore",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub revoked_before: Option<bson::DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(rename = "revokedBy")]
+    pub revoked_by: String,
+    #[serde(rename = "revokedDate")]
+    pub revoked_date: bson::DateTime,
+}
+
+/// Body of a revocation, a jti or a subject :
+/// { "jti" : "5c1f...", "reason" : "leaked in logs" }
+/// { "subject" : "batch", "revokedBefore" : "2024-05-02T08:00:00Z" }   # revokedBefore is now when missing
+#[derive(Debug, Deserialize)]
+pub struct RevocationRequest {
+    #[serde(default)]
+    pub jti: Option<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(rename = "revokedBefore", default)]
+    pub revoked_before: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
@@ -0,0 +1,241 @@
+use mongodb::bson::oid::ObjectId;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiError, LocalError};
+use crate::models::user_model::{User, UserStatus};
+
+pub const SCHEMA_USER: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const SCHEMA_LIST: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const SCHEMA_PATCH: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+pub const SCHEMA_ERROR: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+/// Base path of the SCIM user resources
+pub const USERS_PATH: &str = "/scim/v2/Users";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ScimName {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ScimAddress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locality: Option<String>,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScimRole {
+    pub value: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ScimMeta {
+    #[serde(rename = "resourceType")]
+    pub resource_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(rename = "lastModified", skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    pub location: String,
+}
+
+/// SCIM core User, mapped on the User model :
+/// userName is the email, externalId the identity provider subject, displayName (or name.formatted) the name,
+/// the locality of the primary address the location, active the status
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ScimUser {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "externalId", skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    #[serde(rename = "userName")]
+    pub user_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<ScimName>,
+    #[serde(rename = "displayName", skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub addresses: Vec<ScimAddress>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+    #[serde(default)]
+    pub roles: Vec<ScimRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl ScimUser {
+    pub fn from_user(user: &User) -> ScimUser {
+        let id = user.id.map(|o| o.to_hex()).unwrap_or_default();
+
+        ScimUser {
+            schemas: vec![String::from(SCHEMA_USER)],
+            id: Some(id.clone()),
+            external_id: user.idp_subject.clone(),
+            user_name: user.email.clone().unwrap_or_default(),
+            name: Some(ScimName {
+                formatted: Some(user.name.clone()),
+            }),
+            display_name: Some(user.name.clone()),
+            title: Some(user.title.clone()),
+            addresses: vec![ScimAddress {
+                locality: Some(user.location.clone()),
+                primary: true,
+            }],
+            active: user.status == UserStatus::Active,
+            roles: user
+                .roles
+                .iter()
+                .map(|r| ScimRole { value: r.clone() })
+                .collect(),
+            meta: Some(ScimMeta {
+                resource_type: String::from("User"),
+                created: user.created_date.map(|d| d.to_chrono().to_rfc3339()),
+                last_modified: user.updated_date.map(|d| d.to_chrono().to_rfc3339()),
+                location: format!("{}/{}", USERS_PATH, id),
+            }),
+        }
+    }
+
+    /// Location of the primary address, or of the first one
+    pub fn locality(&self) -> Option<String> {
+        self.addresses
+            .iter()
+            .find(|a| a.primary)
+            .or_else(|| self.addresses.first())
+            .and_then(|a| a.locality.clone())
+    }
+
+    /// displayName first, then name.formatted
+    pub fn formatted_name(&self) -> Option<String> {
+        self.display_name
+            .clone()
+            .or_else(|| self.name.as_ref().and_then(|n| n.formatted.clone()))
+    }
+
+    pub fn to_user(&self, id: Option<ObjectId>) -> User {
+        User {
+            id,
+            name: self.formatted_name().unwrap_or_default(),
+            location: self.locality().unwrap_or_default(),
+            title: self.title.clone().unwrap_or_default(),
+            email: Some(self.user_name.clone()),
+            roles: self.roles.iter().map(|r| r.value.clone()).collect(),
+            status: if self.active {
+                UserStatus::Active
+            } else {
+                UserStatus::Disabled
+            },
+            idp_subject: self.external_id.clone(),
+            created_date: None,
+            updated_date: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScimListResponse {
+    pub schemas: Vec<String>,
+    #[serde(rename = "totalResults")]
+    pub total_results: u64,
+    #[serde(rename = "startIndex")]
+    pub start_index: u64,
+    #[serde(rename = "itemsPerPage")]
+    pub items_per_page: u64,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<ScimUser>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScimOperation {
+    pub op: String,
+    pub path: Option<String>,
+    pub value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScimPatch {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(rename = "Operations")]
+    pub operations: Vec<ScimOperation>,
+}
+
+/// SCIM answer : resource or error body, with the application/scim+json content type
+#[derive(Debug)]
+pub struct ScimResponse {
+    pub status: Status,
+    pub body: serde_json::Value,
+    pub location: Option<String>,
+}
+
+impl ScimResponse {
+    pub fn ok<T: Serialize>(status: Status, body: &T) -> ScimResponse {
+        ScimResponse {
+            status,
+            body: serde_json::json!(body),
+            location: None,
+        }
+    }
+
+    /// SCIM error message, scimType given for the error kinds the RFC names
+    pub fn error(status: Status, scim_type: Option<&str>, detail: &str) -> ScimResponse {
+        let mut body = serde_json::json!({
+            "schemas": [SCHEMA_ERROR],
+            "status": status.code.to_string(),
+            "detail": detail,
+        });
+        if let Some(t) = scim_type {
+            body["scimType"] = serde_json::json!(t);
+        }
+
+        ScimResponse {
+            status,
+            body,
+            location: None,
+        }
+    }
+
+    pub fn from_api_error(e: &ApiError) -> ScimResponse {
+        let scim_type = match e.kind() {
+            LocalError::ConflictError(_) => Some("uniqueness"),
+            LocalError::ValidationError(_) => Some("invalidValue"),
+            LocalError::FilterStringarsing(_) => Some("invalidFilter"),
+            LocalError::ContextError(_) => Some("invalidPath"),
+            _ => None,
+        };
+        // SCIM reports invalid values as bad requests
+        let status = match e.kind() {
+            LocalError::ValidationError(_) => Status::BadRequest,
+            _ => e.status(),
+        };
+        ScimResponse::error(status, scim_type, &e.to_string())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ScimResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(self.body.to_string().respond_to(req)?)
+            .status(self.status)
+            .header(ContentType::new("application", "scim+json"))
+            .finalize();
+        if let Some(location) = self.location {
+            response.set_header(Header::new("Location", location));
+        }
+        Ok(response)
+    }
+}
@@ -1,12 +1,195 @@
-use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{self, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 
-/// User Rust structure
-#[derive(Debug, Serialize, Deserialize)]
+/// Maximum length of the user text fields
+const FIELD_MAX_LENGTH: usize = 100;
+
+/// Account status, a disabled user is kept but may not act
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+    #[default]
+    Active,
+    Disabled,
+}
+
+/// User Rust structure.
+/// Email and identity provider subject are optional (accounts created before them) but unique.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub name: String,
     pub location: String,
     pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub status: UserStatus,
+    #[serde(
+        rename = "idpSubject",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idp_subject: Option<String>,
+    #[serde(
+        rename = "createdDate",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_date: Option<bson::DateTime>,
+    #[serde(
+        rename = "updatedDate",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub updated_date: Option<bson::DateTime>,
+}
+
+/// Partial update of a User, missing fields are kept
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserPatch {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub title: Option<String>,
+    pub email: Option<String>,
+    pub roles: Option<Vec<String>>,
+    pub status: Option<UserStatus>,
+    #[serde(rename = "idpSubject")]
+    pub idp_subject: Option<String>,
+}
+
+fn validate_field(field: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("User {} is empty.", field));
+    }
+    if value.chars().count() > FIELD_MAX_LENGTH {
+        return Err(format!(
+            "User {} is longer than {} characters.",
+            field, FIELD_MAX_LENGTH
+        ));
+    }
+    if value.chars().any(|c| c.is_control()) {
+        return Err(format!("User {} contains control characters.", field));
+    }
+    Ok(())
+}
+
+/// Single address, with a local part and a dotted domain
+pub fn validate_email(email: &str) -> Result<(), String> {
+    validate_field("email", email)?;
+
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("User email {} is not valid.", email))
+    }
+}
+
+/// Role names : letters, digits and - _ : .
+pub fn validate_role(role: &str) -> Result<(), String> {
+    validate_field("role", role)?;
+
+    if role
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_:.".contains(c))
+    {
+        Ok(())
+    } else {
+        Err(format!("User role {} is not valid.", role))
+    }
+}
+
+impl User {
+    /// Check name, location and title are filled, single line and of a reasonable length,
+    /// and the email and roles when given
+    pub fn validate(&self) -> Result<(), String> {
+        validate_field("name", &self.name)?;
+        validate_field("location", &self.location)?;
+        validate_field("title", &self.title)?;
+        if let Some(email) = &self.email {
+            validate_email(email)?;
+        }
+        if let Some(subject) = &self.idp_subject {
+            validate_field("idpSubject", subject)?;
+        }
+        for role in &self.roles {
+            validate_role(role)?;
+        }
+        Ok(())
+    }
+
+    /// Check a user provisioned by SCIM : only the email (the SCIM userName) is required,
+    /// name, location and title are checked when given
+    pub fn validate_provisioned(&self) -> Result<(), String> {
+        match &self.email {
+            Some(email) => validate_email(email)?,
+            None => return Err(String::from("User email is empty.")),
+        }
+        for (field, value) in [
+            ("name", &self.name),
+            ("location", &self.location),
+            ("title", &self.title),
+        ] {
+            if !value.is_empty() {
+                validate_field(field, value)?;
+            }
+        }
+        if let Some(subject) = &self.idp_subject {
+            validate_field("idpSubject", subject)?;
+        }
+        for role in &self.roles {
+            validate_role(role)?;
+        }
+        Ok(())
+    }
+}
+
+impl UserPatch {
+    /// Check the fields given in the patch, at least one is required
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_none()
+            && self.location.is_none()
+            && self.title.is_none()
+            && self.email.is_none()
+            && self.roles.is_none()
+            && self.status.is_none()
+            && self.idp_subject.is_none()
+        {
+            return Err(String::from("User patch has no field."));
+        }
+        if let Some(name) = &self.name {
+            validate_field("name", name)?;
+        }
+        if let Some(location) = &self.location {
+            validate_field("location", location)?;
+        }
+        if let Some(title) = &self.title {
+            validate_field("title", title)?;
+        }
+        if let Some(email) = &self.email {
+            validate_email(email)?;
+        }
+        if let Some(subject) = &self.idp_subject {
+            validate_field("idpSubject", subject)?;
+        }
+        for role in self.roles.iter().flatten() {
+            validate_role(role)?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,161 @@
+use mongodb::{bson::doc, bson::Document, options::IndexOptions, IndexModel};
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Name of the unique index enforcing the business key
+pub const BUSINESS_KEY_INDEX: &str = "business_key";
+
+/// Business key of policies : JSON paths identifying a policy in its source system.
+/// Read from the configuration server file, the by-ref URL gives the values in the same order :
+/// business_key = ["source", "context.policyNumber"]   # PUT /api/any/by-ref/<source>/<policyNumber>
+/// URL values are strings : a business key field stored as a number never matches a by-ref request.
+#[derive(Clone, Debug, Default)]
+pub struct BusinessKey {
+    pub paths: Vec<String>,
+}
+
+impl BusinessKey {
+    pub fn from_settings(settings: &config::Config) -> BusinessKey {
+        BusinessKey {
+            paths: settings
+                .get::<Vec<String>>("business_key")
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Unique index on the business key paths, limited to policies carrying all of them
+    pub fn index(&self) -> Option<IndexModel> {
+        if self.paths.is_empty() {
+            return None;
+        }
+
+        let mut keys = Document::new();
+        let mut partial = Document::new();
+        for path in &self.paths {
+            keys.insert(path, 1);
+            partial.insert(path, doc! {"$exists": true});
+        }
+
+        Some(
+            IndexModel::builder()
+                .keys(keys)
+                .options(
+                    IndexOptions::builder()
+                        .name(String::from(BUSINESS_KEY_INDEX))
+                        .unique(true)
+                        .partial_filter_expression(partial)
+                        .build(),
+                )
+                .build(),
+        )
+    }
+
+    /// Check the values of a by-ref request match the business key paths
+    fn check_values(&self, values: &[&str]) -> ApiResult<()> {
+        // without business key the filter would be empty and match any policy
+        if self.paths.is_empty() {
+            return Err(local_error!(
+                LocalError::DataNotFoundError,
+                "Business key not configured."
+            ));
+        }
+        if self.paths.len() != values.len() {
+            return Err(local_error!(
+                LocalError::ContextError,
+                format!(
+                    "Business key has {} paths, {} values given.",
+                    self.paths.len(),
+                    values.len()
+                )
+            ));
+        }
+        Ok(())
+    }
+
+    /// Filter on the business key values, compared as strings
+    pub fn filter(&self, values: &[&str]) -> ApiResult<Document> {
+        self.check_values(values)?;
+
+        let mut filter = Document::new();
+        for (path, value) in self.paths.iter().zip(values) {
+            filter.insert(path, *value);
+        }
+        Ok(filter)
+    }
+
+    /// Write the business key values in a policy, creating intermediate objects if needed
+    pub fn set_values(&self, any: &mut serde_json::Value, values: &[&str]) -> ApiResult<()> {
+        self.check_values(values)?;
+
+        for (path, value) in self.paths.iter().zip(values) {
+            let mut node = &mut *any;
+            let segments: Vec<&str> = path.split('.').collect();
+            for (i, segment) in segments.iter().enumerate() {
+                let object = match node.as_object_mut() {
+                    Some(o) => o,
+                    None => {
+                        return Err(local_error!(
+                            LocalError::ParsingError,
+                            format!("Business key path {} is not an object.", path)
+                        ));
+                    }
+                };
+                if i == segments.len() - 1 {
+                    object.insert(segment.to_string(), serde_json::json!(value));
+                    break;
+                }
+                node = object
+                    .entry(segment.to_string())
+                    .or_insert_with(|| serde_json::json!({}));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_business_key_values() {
+    let key = BusinessKey {
+        paths: vec![String::from("source"), String::from("context.policyNumber")],
+    };
+    let mut any = serde_json::json!({"context": {"requestDate": "2023-01-01T00:00:00Z"}});
+    key.set_values(&mut any, &["crm", "P-42"]).unwrap();
+
+    assert_eq!(any["source"], "crm");
+    assert_eq!(any["context"]["policyNumber"], "P-42");
+    assert_eq!(any["context"]["requestDate"], "2023-01-01T00:00:00Z");
+    assert_eq!(
+        key.filter(&["crm", "P-42"]).unwrap(),
+        doc! {"source": "crm", "context.policyNumber": "P-42"}
+    );
+    assert!(key.filter(&["crm"]).is_err());
+
+    let key = BusinessKey {
+        paths: vec![
+            String::from("source"),
+            String::from("context.branch"),
+            String::from("context.policyNumber"),
+        ],
+    };
+    let mut any = serde_json::json!({});
+    key.set_values(&mut any, &["crm", "lyon", "P-42"]).unwrap();
+    assert_eq!(any["context"]["branch"], "lyon");
+    assert_eq!(any["context"]["policyNumber"], "P-42");
+    assert!(key.set_values(&mut any, &["crm", "P-42"]).is_err());
+}
+
+#[test]
+fn test_business_key_not_configured() {
+    let key = BusinessKey::default();
+    let mut any = serde_json::json!({"policy": {"name": "toto"}});
+
+    let e = key.filter(&[]).unwrap_err();
+    assert!(matches!(e.kind(), LocalError::DataNotFoundError(_)));
+    assert!(key.filter(&["crm"]).is_err());
+    assert!(key.set_values(&mut any, &[]).is_err());
+    assert_eq!(any, serde_json::json!({"policy": {"name": "toto"}}));
+}
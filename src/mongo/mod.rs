@@ -1,4 +1,18 @@
+pub mod business_key;
 pub mod filter;
 pub mod mongo;
 pub mod mongo_any;
+pub mod mongo_api_keys;
+pub mod mongo_assignments;
+pub mod mongo_attachments;
+pub mod mongo_audit;
+pub mod mongo_changes;
+pub mod mongo_idempotency;
+pub mod mongo_indexes;
+pub mod mongo_outbox;
+pub mod mongo_retention;
+pub mod mongo_revocations;
+pub mod mongo_scim;
+pub mod mongo_search;
+pub mod mongo_stats;
 pub mod mongo_users;
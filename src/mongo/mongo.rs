@@ -3,23 +3,77 @@ use bson::Document;
 use futures::stream::StreamExt;
 use mongodb::{
     bson::doc,
-    options::{ClientOptions, ServerApi, ServerApiVersion},
-    Client, Collection,
+    error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    gridfs::GridFsBucket,
+    options::{ClientOptions, GridFsBucketOptions, ServerApi, ServerApiVersion},
+    Client, ClientSession, Collection,
 };
 
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use crate::models::api_key_model::ApiKey;
+use crate::models::assignment_model::Assignment;
+use crate::models::revocation_model::Revocation;
 use crate::models::user_model::User;
 use std::env;
 
+/// Number of attempts for a transaction failing with a transient error (write conflict, failover)
+pub const TRANSACTION_ATTEMPTS: usize = 5;
+
 /// Collection structure to CRUD User object
 /// MongoDb shared connection
+#[derive(Clone)]
 pub struct MongoRepo {
     pub user_col: Collection<User>,
     pub policy_col: Collection<Document>,
     pub history_col: Collection<Document>,
     pub deleted_col: Collection<Document>,
+    pub change_col: Collection<Document>,
+    pub counter_col: Collection<Document>,
+    pub outbox_col: Collection<Document>,
+    pub audit_col: Collection<Document>,
+    pub hold_col: Collection<Document>,
+    pub idempotency_col: Collection<Document>,
+    pub attachment_col: Collection<Document>,
+    pub assignment_col: Collection<Assignment>,
+    pub api_key_col: Collection<ApiKey>,
+    pub revocation_col: Collection<Revocation>,
+    pub attachments: GridFsBucket,
+    pub database: String,
     pub repo: mongodb::Client,
 }
 
+impl MongoRepo {
+    /// Collections of a database, names optionally prefixed (tenant isolation by collection prefix)
+    pub fn new(client: Client, database: &str, prefix: &str) -> MongoRepo {
+        let db = client.database(database);
+        MongoRepo {
+            user_col: db.collection(&format!("{}Users", prefix)),
+            policy_col: db.collection(&format!("{}policies", prefix)),
+            history_col: db.collection(&format!("{}history", prefix)),
+            deleted_col: db.collection(&format!("{}deleted", prefix)),
+            change_col: db.collection(&format!("{}changes", prefix)),
+            counter_col: db.collection(&format!("{}counters", prefix)),
+            outbox_col: db.collection(&format!("{}outbox", prefix)),
+            audit_col: db.collection(&format!("{}audit", prefix)),
+            hold_col: db.collection(&format!("{}holds", prefix)),
+            idempotency_col: db.collection(&format!("{}idempotency", prefix)),
+            // files collection of the attachments bucket, to link attachments to policy versions
+            attachment_col: db.collection(&format!("{}attachments.files", prefix)),
+            assignment_col: db.collection(&format!("{}assignments", prefix)),
+            api_key_col: db.collection(&format!("{}apikeys", prefix)),
+            revocation_col: db.collection(&format!("{}revocations", prefix)),
+            attachments: db.gridfs_bucket(
+                GridFsBucketOptions::builder()
+                    .bucket_name(format!("{}attachments", prefix))
+                    .build(),
+            ),
+            database: database.to_string(),
+            repo: client,
+        }
+    }
+}
+
 /// Initialize DB object containings the tested, alive mongodb::Client instance
 ///
 pub async fn init_connection(uri: String) -> Option<MongoRepo> {
@@ -59,18 +113,7 @@ pub async fn init_connection(uri: String) -> Option<MongoRepo> {
         .await
     {
         Ok(_o) => {
-            let user_col = client.database("middleoffice").collection("Users");
-            let policy_col = client.database("middleoffice").collection("policies");
-            let history_col = client.database("middleoffice").collection("history");
-            let deleted_col = client.database("middleoffice").collection("deleted");
-            let repo = client; //.database("middleoffice");
-            return Some(MongoRepo {
-                user_col,
-                policy_col,
-                history_col,
-                deleted_col,
-                repo,
-            });
+            return Some(MongoRepo::new(client, "middleoffice", ""));
         } // don't care about the document but connection is validated
         Err(e) => {
             println!("Error init collection{}", e.to_string());
@@ -85,9 +128,93 @@ pub async fn init_connection(uri: String) -> Option<MongoRepo> {
 pub async fn ping_db(db: &MongoRepo) -> mongodb::error::Result<()> {
     // Send a ping to confirm a successful connection
     db.repo
-        .database("middleoffice")
+        .database(&db.database)
         .run_command(doc! { "ping": 1 }, None)
         .await?;
 
     Ok(())
 }
+
+/// Run a mutation in a transaction, retried on transient errors.
+/// The body gets the transaction session and has to return a future of Result<T, ApiError>.
+#[macro_export]
+macro_rules! transaction {
+    ($db:expr, |$session:ident| $body:expr) => {{
+        let mut attempt = 1;
+        loop {
+            let mut $session = $crate::mongo::mongo::start_transaction($db).await?;
+            let result = $body.await;
+            match $crate::mongo::mongo::end_transaction(&mut $session, result).await {
+                Err(e)
+                    if matches!(e.kind(), $crate::error::LocalError::TransientError(_))
+                        && attempt < $crate::mongo::mongo::TRANSACTION_ATTEMPTS =>
+                {
+                    attempt += 1;
+                }
+                other => break other,
+            }
+        }
+    }};
+}
+
+/// Map a Mongo write error to an ApiError, flagging errors the driver labels as transient so the transaction is retried
+pub fn write_error(e: mongodb::error::Error, desc: &str) -> ApiError {
+    if e.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+        local_error!(LocalError::TransientError, format!("{} : {}", desc, e))
+    } else {
+        local_error!(LocalError::ConnectionError, format!("{} : {}", desc, e))
+    }
+}
+
+/// True when a write failed on a unique index
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(we)) => {
+            we.code == 11000
+        }
+        mongodb::error::ErrorKind::Command(ce) => ce.code == 11000,
+        _ => false,
+    }
+}
+
+/// Open a session and start a transaction, all writes of a mutation have to go through this session
+pub async fn start_transaction(db: &MongoRepo) -> Result<ClientSession, ApiError> {
+    let mut session = match db.repo.start_session(None).await {
+        Ok(o) => o,
+        Err(e) => return Err(write_error(e, "Session creation failed")),
+    };
+
+    match session.start_transaction(None).await {
+        Ok(()) => Ok(session),
+        Err(e) => Err(write_error(e, "Transaction start failed")),
+    }
+}
+
+/// Commit the transaction if the mutation succeeded, abort it otherwise.
+/// Commit is retried while its result is unknown.
+pub async fn end_transaction<T>(
+    session: &mut ClientSession,
+    result: Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    let value = match result {
+        Ok(o) => o,
+        Err(e) => {
+            let _ = session.abort_transaction().await;
+            return Err(e);
+        }
+    };
+
+    let mut attempt = 1;
+    loop {
+        match session.commit_transaction().await {
+            Ok(()) => return Ok(value),
+            Err(e)
+                if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                    && attempt < TRANSACTION_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            Err(e) => return Err(write_error(e, "Transaction commit failed")),
+        }
+    }
+}
@@ -1,15 +1,25 @@
-use mongodb::{bson::doc, bson::oid::ObjectId, results::DeleteResult};
+use mongodb::{bson::doc, bson::oid::ObjectId, results::DeleteResult, ClientSession};
 
 use futures::stream::StreamExt;
 
+use crate::models::audit_model::Actor;
 use crate::models::policy_model::Policy;
 use mongodb::bson;
+use mongodb::options::FindOneOptions;
 
+use crate::mongo::business_key::BusinessKey;
 use crate::mongo::filter;
-use crate::mongo::mongo::MongoRepo;
+use crate::mongo::mongo::{self, MongoRepo};
+use crate::mongo::mongo_assignments;
+use crate::mongo::mongo_attachments;
+use crate::mongo::mongo_audit;
+use crate::mongo::mongo_changes;
+use crate::mongo::mongo_idempotency;
+use crate::mongo::mongo_outbox;
 
 use crate::error::{ApiError, LocalError};
 use crate::local_error;
+use crate::transaction;
 use rocket::serde::json::json;
 
 // Alias for ResultwT,ApiError>
@@ -17,12 +27,84 @@ type ApiResult<T> = Result<T, ApiError>;
 
 /// Create Any API, return Mongo Oid on success, Err(e) if exception.
 /// Json Any data
-pub async fn create_any(db: &MongoRepo, any: serde_json::Value) -> ApiResult<Policy> {
-    let new_doc = bson::to_document(&any);
+pub async fn create_any(
+    db: &MongoRepo,
+    any: serde_json::Value,
+    actor: &Actor,
+) -> ApiResult<Policy> {
+    transaction!(db, |session| create_any_with_session(
+        db,
+        &any,
+        actor,
+        &mut session
+    ))
+}
+
+/// Create Any for a request with an Idempotency-Key held under the lease `lock`,
+/// the response is stored for the key in the transaction of the creation
+pub async fn create_any_idempotent(
+    db: &MongoRepo,
+    any: serde_json::Value,
+    actor: &Actor,
+    key: &str,
+    lock: &str,
+) -> ApiResult<Policy> {
+    transaction!(db, |session| create_idempotent_with_session(
+        db,
+        &any,
+        actor,
+        (key, lock),
+        &mut session
+    ))
+}
+
+async fn create_idempotent_with_session(
+    db: &MongoRepo,
+    any: &serde_json::Value,
+    actor: &Actor,
+    (key, lock): (&str, &str),
+    session: &mut ClientSession,
+) -> ApiResult<Policy> {
+    let policy = create_any_with_session(db, any, actor, session).await?;
+    mongo_idempotency::complete(db, session, actor, key, lock, &policy.content).await?;
+    Ok(policy)
+}
+
+async fn create_any_with_session(
+    db: &MongoRepo,
+    any: &serde_json::Value,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<Policy> {
+    let policy = insert_any(db, any, session).await?;
+
+    if let Some(oid) = policy.id {
+        log_mutation(
+            db,
+            session,
+            actor,
+            mongo_changes::CHANGE_CREATED,
+            (None, Some(&oid.to_hex())),
+            Vec::new(),
+            Some(any),
+        )
+        .await?;
+    }
+
+    Ok(policy)
+}
+
+/// Insert Any in policy collection without logging the change, shared by create and update.
+async fn insert_any(
+    db: &MongoRepo,
+    any: &serde_json::Value,
+    session: &mut ClientSession,
+) -> ApiResult<Policy> {
+    let new_doc = bson::to_document(any);
 
     match new_doc {
         Ok(mut record) => {
-            match filter::get_date_filter(&any, "context", "requestDate") {
+            match filter::get_date_filter(any, "context", "requestDate") {
                 Ok(r) => record.insert("requestDate", bson::DateTime::from_chrono(r)),
                 Err(_e) => {
                     return Err(local_error!(
@@ -32,7 +114,7 @@ pub async fn create_any(db: &MongoRepo, any: serde_json::Value) -> ApiResult<Pol
                 }
             };
 
-            match filter::get_date_filter(&any, "context", "policyStartDate") {
+            match filter::get_date_filter(any, "context", "policyStartDate") {
                 Ok(r) => record.insert("policyStartDate", bson::DateTime::from_chrono(r)),
                 Err(_e) => {
                     return Err(local_error!(
@@ -42,7 +124,7 @@ pub async fn create_any(db: &MongoRepo, any: serde_json::Value) -> ApiResult<Pol
                 }
             };
 
-            match filter::get_date_filter(&any, "context", "policyEndDate") {
+            match filter::get_date_filter(any, "context", "policyEndDate") {
                 Ok(r) => record.insert("policyEndDate", bson::DateTime::from_chrono(r)),
                 Err(_e) => {
                     return Err(local_error!(
@@ -57,11 +139,23 @@ pub async fn create_any(db: &MongoRepo, any: serde_json::Value) -> ApiResult<Pol
                 bson::DateTime::from_chrono(chrono::Utc::now()),
             );
 
-            let r = match db.policy_col.insert_one(record, None).await {
+            let r = match db
+                .policy_col
+                .insert_one_with_session(record, None, session)
+                .await
+            {
                 Ok(o) => o,
-                Err(e) => return Err(local_error!(LocalError::ConnectionError, format!("{}", e))),
+                Err(e) if mongo::is_duplicate_key(&e) => {
+                    return Err(local_error!(
+                        LocalError::ConflictError,
+                        "A policy with the same business key already exists."
+                    ));
+                }
+                Err(e) => return Err(mongo::write_error(e, "Insert in policy store failed")),
             };
 
+            let oid = r.inserted_id.as_object_id();
+
             let response = match bson::from_bson(r.inserted_id) {
                 Ok(o) => o,
                 Err(_e) => {
@@ -73,7 +167,7 @@ pub async fn create_any(db: &MongoRepo, any: serde_json::Value) -> ApiResult<Pol
             };
 
             let policy = Policy {
-                id: None,
+                id: oid,
                 content: response,
             };
 
@@ -231,7 +325,21 @@ pub async fn count_all_any(
 
 /// Delete Any based on an Oid
 /// first Get Any Raw Data, Stored it in Deleted collection, then remove from Policy collection
-pub async fn delete_any(db: &MongoRepo, id: &String) -> ApiResult<DeleteResult> {
+pub async fn delete_any(db: &MongoRepo, id: &String, actor: &Actor) -> ApiResult<DeleteResult> {
+    transaction!(db, |session| delete_any_with_session(
+        db,
+        id,
+        actor,
+        &mut session
+    ))
+}
+
+async fn delete_any_with_session(
+    db: &MongoRepo,
+    id: &String,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<DeleteResult> {
     let obj_id = match ObjectId::parse_str(id) {
         //.ok().expect("Error parsing object Id");
         Ok(obj) => obj,
@@ -248,14 +356,20 @@ pub async fn delete_any(db: &MongoRepo, id: &String) -> ApiResult<DeleteResult>
             let new_doc = bson::to_document(&r);
 
             match new_doc {
-                Ok(record) => {
-                    let _record = match db.deleted_col.insert_one(record, None).await {
+                Ok(mut record) => {
+                    // deletion date drives the retention of deleted policies
+                    record.insert(
+                        "deletedDate",
+                        bson::DateTime::from_chrono(chrono::Utc::now()),
+                    );
+                    let _record = match db
+                        .deleted_col
+                        .insert_one_with_session(record, None, session)
+                        .await
+                    {
                         Ok(o) => o,
                         Err(e) => {
-                            return Err(local_error!(
-                                LocalError::ConnectionError,
-                                format!("Insert in delete store failed : {}", e)
-                            ));
+                            return Err(mongo::write_error(e, "Insert in delete store failed"));
                         }
                     };
                 }
@@ -272,22 +386,59 @@ pub async fn delete_any(db: &MongoRepo, id: &String) -> ApiResult<DeleteResult>
         }
     }
 
-    let policy_detail = match db.policy_col.delete_one(filter, None).await {
+    let policy_detail = match db
+        .policy_col
+        .delete_one_with_session(filter, None, session)
+        .await
+    {
         Ok(o) => o,
         Err(e) => {
-            return Err(local_error!(
-                LocalError::ConnectionError,
-                format!("Delete from policy store failed: {}", e)
-            ));
+            return Err(mongo::write_error(e, "Delete from policy store failed"));
         }
     };
 
+    if policy_detail.deleted_count == 1 {
+        mongo_attachments::archive(db, session, id).await?;
+        mongo_assignments::archive(db, session, id).await?;
+        log_mutation(
+            db,
+            session,
+            actor,
+            mongo_changes::CHANGE_DELETED,
+            (Some(id), None),
+            Vec::new(),
+            None,
+        )
+        .await?;
+    }
+
     Ok(policy_detail)
 }
 
 /// Delete Any based on an Oid
 /// first Get Any Raw Data,Create new one after attaching old ObjectId, Stored it in History collection, then remove original from Policy collection
-pub async fn update_any(db: &MongoRepo, any: serde_json::Value, id: String) -> ApiResult<Policy> {
+pub async fn update_any(
+    db: &MongoRepo,
+    any: serde_json::Value,
+    id: String,
+    actor: &Actor,
+) -> ApiResult<Policy> {
+    transaction!(db, |session| update_any_with_session(
+        db,
+        any.clone(),
+        id.clone(),
+        actor,
+        &mut session
+    ))
+}
+
+async fn update_any_with_session(
+    db: &MongoRepo,
+    any: serde_json::Value,
+    id: String,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<Policy> {
     let obj_id = match ObjectId::parse_str(&id) {
         Ok(obj) => obj,
         Err(_e) => {
@@ -305,69 +456,87 @@ pub async fn update_any(db: &MongoRepo, any: serde_json::Value, id: String) -> A
             // retrieve existing objectId from Get and push the new one in the Body to list in array of previous modification
             let v = r.content["previousObjectIds"].clone();
 
-            data["previousObjectIds"] = match v.as_array() {
+            let previous = match v.as_array() {
                 Some(o) => {
                     let mut vec = Vec::new();
                     vec.push(id);
                     for item in o {
                         vec.push(item.as_str().unwrap().to_string());
                     }
-                    json!(vec)
+                    vec
                 }
                 None => {
                     //let arr: [String; 1] = [id];
                     let mut vec = Vec::new();
                     vec.push(id);
-                    json!(vec)
+                    vec
+                }
+            };
+            data["previousObjectIds"] = json!(previous);
+
+            let record = match bson::to_document(&r.content) {
+                Ok(o) => o,
+                Err(_e) => {
+                    return Err(local_error!(
+                        LocalError::ParsingError,
+                        "Parsing create output failed."
+                    ));
+                }
+            };
+
+            // History older version
+            let _rr = match db
+                .history_col
+                .insert_one_with_session(&record, None, session)
+                .await
+            {
+                Ok(o) => o,
+                Err(e) => {
+                    return Err(mongo::write_error(e, "Insert in history failed"));
+                }
+            };
+
+            // Delete current version before creating the new one, both carry the same unique business key
+            let _policy_deleted = match db
+                .policy_col
+                .delete_one_with_session(filter, None, session)
+                .await
+            {
+                Ok(o) => o,
+                Err(e) => {
+                    return Err(mongo::write_error(e, "Delete from policy failed"));
                 }
             };
 
-            match create_any(db, data).await {
+            match insert_any(db, &data, session).await {
                 Ok(created_policy) => {
-                    let new_doc = bson::to_document(&r.content);
-
-                    match new_doc {
-                        Ok(record) => {
-                            // History older version
-                            let _rr = match db.history_col.insert_one(&record, None).await {
-                                Ok(o) => o,
-                                Err(_e) => {
-                                    return Err(local_error!(
-                                        LocalError::ConnectionError,
-                                        "Insert in history failed."
-                                    ));
-                                }
-                            };
-
-                            // Delete current version
-                            let _policy_deleted = match db.policy_col.delete_one(filter, None).await
-                            {
-                                Ok(o) => o,
-                                Err(_e) => {
-                                    return Err(local_error!(
-                                        LocalError::ConnectionError,
-                                        "Delete from policy failed."
-                                    ));
-                                }
-                            };
-
-                            return Ok(created_policy);
-                        }
-                        Err(_e) => {
-                            return Err(local_error!(
-                                LocalError::ParsingError,
-                                "Parsing create output failed."
-                            ));
-                        }
+                    if let Some(oid) = created_policy.id {
+                        let old = previous[0].clone();
+                        mongo_attachments::carry_over(db, session, &old, &oid.to_hex()).await?;
+                        mongo_assignments::carry_over(db, session, &old, &oid.to_hex()).await?;
+                        log_mutation(
+                            db,
+                            session,
+                            actor,
+                            mongo_changes::CHANGE_UPDATED,
+                            (Some(&old), Some(&oid.to_hex())),
+                            previous,
+                            Some(&data),
+                        )
+                        .await?;
                     }
+
+                    Ok(created_policy)
                 }
-                Err(e) => {
-                    return Err(local_error!(
+                // keep transient and conflict errors as is so that they are retried or reported
+                Err(e) => match e.kind() {
+                    LocalError::TransientError(_) | LocalError::ConflictError(_) => Err(e),
+                    _ => Err(local_error!(
                         LocalError::ConnectionError,
                         format!("Create policy failed : {}.", e)
-                    ));
-                }
-            };
+                    )),
+                },
+            }
         }
         Err(e) => {
             return Err(local_error!(
@@ -377,3 +546,86 @@ pub async fn update_any(db: &MongoRepo, any: serde_json::Value, id: String) -> A
         }
     }
 }
+
+/// Create or version, through the update history path, the policy identified by its business key values.
+/// Return the policy and true when it has been created.
+pub async fn upsert_by_ref(
+    db: &MongoRepo,
+    key: &BusinessKey,
+    values: &[&str],
+    any: serde_json::Value,
+    actor: &Actor,
+) -> ApiResult<(Policy, bool)> {
+    let mut data = any;
+    key.set_values(&mut data, values)?;
+    let filter = key.filter(values)?;
+    let options = FindOneOptions::builder()
+        .projection(doc! {"_id": 1})
+        .build();
+
+    // a policy created concurrently with the same business key is versioned on the second attempt
+    for _attempt in 0..2 {
+        let current = match db
+            .policy_col
+            .find_one(filter.clone(), options.clone())
+            .await
+        {
+            Ok(o) => o,
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception on get any by reference : {}", e)
+                ));
+            }
+        };
+
+        match current.and_then(|d| d.get_object_id("_id").ok()) {
+            Some(oid) => {
+                let policy = update_any(db, data, oid.to_hex(), actor).await?;
+                return Ok((policy, false));
+            }
+            None => match create_any(db, data.clone(), actor).await {
+                Ok(policy) => return Ok((policy, true)),
+                Err(e) => {
+                    if let LocalError::ConflictError(_) = e.kind() {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            },
+        }
+    }
+
+    Err(local_error!(
+        LocalError::ConflictError,
+        "Concurrent modifications of the same business key."
+    ))
+}
+
+/// Record a policy mutation in the change log, the outbox and the audit trail, in the transaction of the mutation
+/// `oids` holds the policy Oid before and after the mutation
+async fn log_mutation(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    actor: &Actor,
+    op: &str,
+    oids: (Option<&str>, Option<&str>),
+    previous: Vec<String>,
+    payload: Option<&serde_json::Value>,
+) -> ApiResult<()> {
+    let (old, new) = oids;
+    // the change log and the outbox follow the current version, the deleted one for a delete
+    let id = match new.or(old) {
+        Some(o) => o,
+        None => {
+            return Err(local_error!(
+                LocalError::ContextError,
+                "Mutation without policy Oid."
+            ));
+        }
+    };
+
+    let seq = mongo_changes::record_change(db, session, op, id, previous.clone()).await?;
+    mongo_outbox::enqueue(db, session, op, seq, id, previous, payload).await?;
+    mongo_audit::record_with_session(db, session, actor, &format!("policy.{}", op), old, new).await
+}
@@ -0,0 +1,366 @@
+use chrono::prelude::*;
+use mongodb::bson;
+use mongodb::{
+    bson::doc,
+    bson::oid::ObjectId,
+    bson::Document,
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    ClientSession,
+};
+use rand::RngCore;
+
+use crate::models::api_key_model::{ApiKey, ApiKeyCreated, ApiKeyRequest};
+use crate::models::audit_model::Actor;
+use crate::mongo::mongo::{self, MongoRepo};
+use crate::mongo::mongo_audit;
+use crate::scope::has_scope;
+use crate::token::hex_sha256;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use crate::transaction;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Length of the key start kept in clear to recognize a key
+const PREFIX_LEN: usize = 11;
+
+fn parse_oid(id: &str) -> ApiResult<ObjectId> {
+    match ObjectId::parse_str(id) {
+        Ok(o) => Ok(o),
+        Err(_e) => Err(local_error!(
+            LocalError::OidFormatError,
+            "ObjectId wrongly structure."
+        )),
+    }
+}
+
+/// New random key : mo_ followed by 32 random bytes in hex
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("mo_{}", hex)
+}
+
+/// Filter of an active key of the given id, limited to the tenant of a tenant bound caller
+fn active_key(oid: ObjectId, actor: &Actor) -> Document {
+    let mut filter = doc! {"_id": oid, "revoked": false};
+    if let Some(tenant) = &actor.tenant {
+        filter.insert("tenant", tenant);
+    }
+    filter
+}
+
+/// Limit a key request to the caller : the tenant of a tenant bound caller is forced,
+/// and the key only gets scopes the caller holds.
+pub fn bounded_request(mut request: ApiKeyRequest, actor: &Actor) -> ApiResult<ApiKeyRequest> {
+    if let Some(tenant) = &actor.tenant {
+        match &request.tenant {
+            Some(t) if t != tenant => {
+                return Err(local_error!(
+                    LocalError::ForbiddenError,
+                    format!("Key of tenant {} out of the caller tenant.", t)
+                ))
+            }
+            _ => request.tenant = Some(tenant.clone()),
+        }
+    }
+
+    if let Some(scope) = request
+        .scope
+        .split_whitespace()
+        .find(|s| !has_scope(&actor.scope, s))
+    {
+        return Err(local_error!(
+            LocalError::ForbiddenError,
+            format!("Scope {} not held by the caller.", scope)
+        ));
+    }
+    Ok(request)
+}
+
+fn created(key: &ApiKey, secret: String) -> ApiKeyCreated {
+    ApiKeyCreated {
+        id: key.id.map(|o| o.to_hex()).unwrap_or_default(),
+        key: secret,
+        prefix: key.prefix.clone(),
+        owner: key.owner.clone(),
+        scope: key.scope.clone(),
+        expires: key.expires,
+    }
+}
+
+/// Create a key for a service caller, the key itself is only returned here.
+/// The key is limited to the tenant and the scopes of the caller.
+pub async fn create(
+    db: &MongoRepo,
+    request: ApiKeyRequest,
+    actor: &Actor,
+) -> ApiResult<ApiKeyCreated> {
+    if request.owner.trim().is_empty() {
+        return Err(local_error!(
+            LocalError::ValidationError,
+            "Owner is required."
+        ));
+    }
+    if request.scope.trim().is_empty() {
+        return Err(local_error!(
+            LocalError::ValidationError,
+            "Scope is required."
+        ));
+    }
+    let request = bounded_request(request, actor)?;
+
+    let secret = generate_key();
+    let now = Utc::now();
+    let mut key = ApiKey {
+        id: None,
+        owner: request.owner,
+        key_sha256: hex_sha256(&secret),
+        prefix: secret[..PREFIX_LEN].to_string(),
+        scope: request
+            .scope
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+        tenant: request.tenant,
+        expires: request
+            .expires_days
+            .map(|d| bson::DateTime::from_chrono(now + chrono::Duration::days(d as i64))),
+        last_used: None,
+        revoked: false,
+        created_by: actor.subject.clone(),
+        created_date: bson::DateTime::from_chrono(now),
+    };
+
+    key.id = transaction!(db, |session| insert_with_session(
+        db,
+        &key,
+        actor,
+        &mut session
+    ))?;
+
+    Ok(created(&key, secret))
+}
+
+async fn insert_with_session(
+    db: &MongoRepo,
+    key: &ApiKey,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<Option<ObjectId>> {
+    let result = match db
+        .api_key_col
+        .insert_one_with_session(key, None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Error creating API key")),
+    };
+
+    let oid = result.inserted_id.as_object_id();
+    let id = oid.map(|o| o.to_hex());
+    mongo_audit::record_with_session(db, session, actor, "apikey.created", None, id.as_deref())
+        .await?;
+    Ok(oid)
+}
+
+/// Replace the key of an active API key, the previous key stops working at once.
+/// A tenant bound caller only rotates the keys of its tenant.
+pub async fn rotate(db: &MongoRepo, id: &str, actor: &Actor) -> ApiResult<ApiKeyCreated> {
+    let oid = parse_oid(id)?;
+    let secret = generate_key();
+    let key = transaction!(db, |session| rotate_with_session(
+        db,
+        oid,
+        &secret,
+        actor,
+        &mut session
+    ))?;
+
+    Ok(created(&key, secret))
+}
+
+async fn rotate_with_session(
+    db: &MongoRepo,
+    oid: ObjectId,
+    secret: &str,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<ApiKey> {
+    let update = doc! {
+        "$set": {
+            "keySha256": hex_sha256(secret),
+            "prefix": &secret[..PREFIX_LEN],
+            "rotatedDate": bson::DateTime::from_chrono(Utc::now()),
+        },
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+
+    let key = match db
+        .api_key_col
+        .find_one_and_update_with_session(active_key(oid, actor), update, options, session)
+        .await
+    {
+        Ok(Some(o)) => o,
+        Ok(None) => {
+            return Err(local_error!(
+                LocalError::DataNotFoundError,
+                "No active API key."
+            ))
+        }
+        Err(e) => return Err(mongo::write_error(e, "Error rotating API key")),
+    };
+
+    let id = oid.to_hex();
+    mongo_audit::record_with_session(db, session, actor, "apikey.rotated", Some(&id), Some(&id))
+        .await?;
+    Ok(key)
+}
+
+/// Revoke an API key, kept for the audit trail.
+/// A tenant bound caller only revokes the keys of its tenant.
+pub async fn revoke(db: &MongoRepo, id: &str, actor: &Actor) -> ApiResult<()> {
+    let oid = parse_oid(id)?;
+    transaction!(db, |session| revoke_with_session(
+        db,
+        oid,
+        actor,
+        &mut session
+    ))
+}
+
+async fn revoke_with_session(
+    db: &MongoRepo,
+    oid: ObjectId,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {
+            "revoked": true,
+            "revokedDate": bson::DateTime::from_chrono(Utc::now()),
+        },
+    };
+
+    let result = match db
+        .api_key_col
+        .update_one_with_session(active_key(oid, actor), update, None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Error revoking API key")),
+    };
+
+    if result.matched_count == 0 {
+        return Err(local_error!(
+            LocalError::DataNotFoundError,
+            "No active API key."
+        ));
+    }
+
+    let id = oid.to_hex();
+    mongo_audit::record_with_session(db, session, actor, "apikey.revoked", Some(&id), None).await
+}
+
+/// Active, unexpired API key of the given key. The last use date is updated in the background.
+pub async fn authenticate(db: &MongoRepo, key: &str) -> ApiResult<Option<ApiKey>> {
+    let filter = doc! {"keySha256": hex_sha256(key), "revoked": false};
+    let found = match db.api_key_col.find_one(filter, None).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Error reading API key : {}", e)
+            ))
+        }
+    };
+
+    let now = bson::DateTime::from_chrono(Utc::now());
+    let found = found.filter(|k| k.expires.is_none_or(|e| e > now));
+
+    if let Some(id) = found.as_ref().and_then(|k| k.id) {
+        let collection = db.api_key_col.clone();
+        rocket::tokio::spawn(async move {
+            let update = doc! {"$set": {"lastUsed": now}};
+            if let Err(e) = collection.update_one(doc! {"_id": id}, update, None).await {
+                eprintln!("Last use of API key {} not recorded : {}", id, e);
+            }
+        });
+    }
+
+    Ok(found)
+}
+
+#[test]
+fn test_generate_key() {
+    let key = generate_key();
+    assert_eq!(key.len(), 67);
+    assert!(key.starts_with("mo_"));
+    assert_ne!(key, generate_key());
+    assert_eq!(hex_sha256(&key).len(), 64);
+}
+
+#[test]
+fn test_bounded_request() {
+    let actor = |tenant: Option<&str>| Actor {
+        subject: String::from("admin"),
+        scope: String::from("policies:read policies:write admin"),
+        ip: None,
+        route: String::from("POST /api/admin/apikeys"),
+        tenant: tenant.map(String::from),
+    };
+    let request =
+        |value: serde_json::Value| -> ApiKeyRequest { serde_json::from_value(value).unwrap() };
+
+    // the tenant of a tenant bound caller is forced
+    let bounded = bounded_request(
+        request(serde_json::json!({"owner": "batch", "scope": "policies:read"})),
+        &actor(Some("retail")),
+    )
+    .unwrap();
+    assert_eq!(bounded.tenant.as_deref(), Some("retail"));
+
+    let bounded = bounded_request(
+        request(
+            serde_json::json!({"owner": "batch", "scope": "policies:read", "tenant": "retail"}),
+        ),
+        &actor(Some("retail")),
+    )
+    .unwrap();
+    assert_eq!(bounded.tenant.as_deref(), Some("retail"));
+
+    let other = bounded_request(
+        request(
+            serde_json::json!({"owner": "batch", "scope": "policies:read", "tenant": "corporate"}),
+        ),
+        &actor(Some("retail")),
+    );
+    assert_eq!(other.unwrap_err().status(), rocket::http::Status::Forbidden);
+
+    // a caller of every tenant chooses the tenant of the key
+    let bounded = bounded_request(
+        request(
+            serde_json::json!({"owner": "batch", "scope": "policies:read", "tenant": "corporate"}),
+        ),
+        &actor(None),
+    )
+    .unwrap();
+    assert_eq!(bounded.tenant.as_deref(), Some("corporate"));
+
+    // scopes beyond the caller scopes are refused
+    let wider = bounded_request(
+        request(serde_json::json!({"owner": "batch", "scope": "policies:read users:write"})),
+        &actor(None),
+    );
+    assert_eq!(wider.unwrap_err().status(), rocket::http::Status::Forbidden);
+
+    let mut filter = active_key(ObjectId::new(), &actor(Some("retail")));
+    assert_eq!(filter.get_str("tenant").unwrap(), "retail");
+    filter = active_key(ObjectId::new(), &actor(None));
+    assert!(!filter.contains_key("tenant"));
+}
@@ -0,0 +1,278 @@
+use chrono::prelude::*;
+use futures::stream::StreamExt;
+use mongodb::{bson::doc, bson::Document, options::FindOptions, ClientSession};
+
+use mongodb::bson;
+
+use crate::models::assignment_model::{Assignment, AssignmentRequest};
+use crate::models::audit_model::Actor;
+use crate::mongo::mongo::{self, MongoRepo};
+use crate::mongo::mongo_any;
+use crate::mongo::mongo_audit;
+use crate::mongo::mongo_users;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use crate::transaction;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+fn write_error(e: mongodb::error::Error, desc: &str) -> ApiError {
+    if mongo::is_duplicate_key(&e) {
+        return local_error!(
+            LocalError::ConflictError,
+            "The user is already assigned to the policy with this role."
+        );
+    }
+    mongo::write_error(e, desc)
+}
+
+async fn find_assignments(db: &MongoRepo, filter: Document) -> ApiResult<Vec<Assignment>> {
+    let find_options = FindOptions::builder()
+        .sort(doc! {"assignedDate": 1, "_id": 1})
+        .build();
+
+    let mut cursors = match db.assignment_col.find(filter, find_options).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading assignments : {}", e)
+            ));
+        }
+    };
+
+    let mut assignments = Vec::new();
+    while let Some(assignment) = cursors.next().await {
+        match assignment {
+            Ok(o) => assignments.push(o),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading assignments : {}", e)
+                ));
+            }
+        }
+    }
+    Ok(assignments)
+}
+
+/// Assign an existing user to the current version of a policy with a role
+pub async fn assign(
+    db: &MongoRepo,
+    policy_id: &String,
+    request: AssignmentRequest,
+    actor: &Actor,
+) -> ApiResult<Assignment> {
+    // only current policies take new assignments
+    mongo_any::get_any(db, policy_id).await?;
+    mongo_users::get_user(db, &request.user_id).await?;
+
+    let assignment = Assignment {
+        id: None,
+        policy_id: policy_id.clone(),
+        user_id: request.user_id,
+        role: request.role,
+        previous_object_ids: Vec::new(),
+        archived: false,
+        assigned_by: actor.subject.clone(),
+        assigned_date: bson::DateTime::from_chrono(Utc::now()),
+    };
+
+    transaction!(db, |session| assign_with_session(
+        db,
+        assignment.clone(),
+        actor,
+        &mut session
+    ))
+}
+
+async fn assign_with_session(
+    db: &MongoRepo,
+    mut assignment: Assignment,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<Assignment> {
+    let result = match db
+        .assignment_col
+        .insert_one_with_session(&assignment, None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(write_error(e, "Assignment failed")),
+    };
+    assignment.id = result.inserted_id.as_object_id();
+
+    let id = assignment.id.map(|o| o.to_hex());
+    mongo_audit::record_with_session(
+        db,
+        session,
+        actor,
+        "policy.assignment.created",
+        Some(&assignment.policy_id),
+        id.as_deref(),
+    )
+    .await?;
+
+    Ok(assignment)
+}
+
+/// Users assigned to a policy, archived assignments included for a deleted policy
+pub async fn policy_assignments(db: &MongoRepo, policy_id: &str) -> ApiResult<Vec<Assignment>> {
+    find_assignments(db, doc! {"policyId": policy_id}).await
+}
+
+/// Current policies a user is assigned to
+pub async fn user_assignments(db: &MongoRepo, user_id: &str) -> ApiResult<Vec<Assignment>> {
+    find_assignments(db, doc! {"userId": user_id, "archived": false}).await
+}
+
+/// Remove the assignment of a user to a policy with a role
+pub async fn unassign(
+    db: &MongoRepo,
+    policy_id: &str,
+    user_id: &str,
+    role: &str,
+    actor: &Actor,
+) -> ApiResult<()> {
+    let filter = doc! {"policyId": policy_id, "userId": user_id, "role": role, "archived": false};
+
+    transaction!(db, |session| unassign_with_session(
+        db,
+        policy_id,
+        &filter,
+        actor,
+        &mut session
+    ))
+}
+
+async fn unassign_with_session(
+    db: &MongoRepo,
+    policy_id: &str,
+    filter: &Document,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    let result = match db
+        .assignment_col
+        .delete_one_with_session(filter.clone(), None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Assignment removal failed")),
+    };
+
+    if result.deleted_count == 0 {
+        return Err(local_error!(
+            LocalError::DataNotFoundError,
+            "No assignment."
+        ));
+    }
+
+    mongo_audit::record_with_session(
+        db,
+        session,
+        actor,
+        "policy.assignment.deleted",
+        Some(policy_id),
+        None,
+    )
+    .await
+}
+
+/// Link the assignments of a policy to its new version, in the transaction of `update_any`
+pub async fn carry_over(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    old: &str,
+    new: &str,
+) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {"policyId": new},
+        "$push": {"previousObjectIds": old},
+    };
+
+    match db
+        .assignment_col
+        .update_many_with_session(doc! {"policyId": old}, update, None, session)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(mongo::write_error(e, "Assignments carry over failed")),
+    }
+}
+
+/// Archive the assignments of a deleted policy, in the transaction of `delete_any`
+pub async fn archive(db: &MongoRepo, session: &mut ClientSession, id: &str) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {
+            "archived": true,
+            "archivedDate": bson::DateTime::from_chrono(Utc::now()),
+        },
+    };
+
+    match db
+        .assignment_col
+        .update_many_with_session(doc! {"policyId": id}, update, None, session)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(mongo::write_error(e, "Assignments archiving failed")),
+    }
+}
+
+/// Remove the archived assignments of purged deleted policies, in the transaction of the purge
+pub async fn purge(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    policy_ids: &[String],
+) -> ApiResult<u64> {
+    let filter = doc! {"policyId": {"$in": policy_ids}, "archived": true};
+
+    match db
+        .assignment_col
+        .delete_many_with_session(filter, None, session)
+        .await
+    {
+        Ok(o) => Ok(o.deleted_count),
+        Err(e) => Err(mongo::write_error(e, "Purge of assignments failed")),
+    }
+}
+
+/// Remove the assignments of a deleted user, in the transaction of `delete_user`
+pub async fn remove_user(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    user_id: &str,
+) -> ApiResult<u64> {
+    match db
+        .assignment_col
+        .delete_many_with_session(doc! {"userId": user_id}, None, session)
+        .await
+    {
+        Ok(o) => Ok(o.deleted_count),
+        Err(e) => Err(mongo::write_error(
+            e,
+            "Removal of the user assignments failed",
+        )),
+    }
+}
+
+#[test]
+fn test_assignment_request() {
+    use crate::models::assignment_model::AssignmentRole;
+
+    let request: AssignmentRequest = serde_json::from_value(serde_json::json!({
+        "userId": "655c7c5b037c912bb7ce3973",
+        "role": "underwriter",
+    }))
+    .unwrap();
+    assert_eq!(request.role, AssignmentRole::Underwriter);
+
+    let request = serde_json::from_value::<AssignmentRequest>(serde_json::json!({
+        "userId": "655c7c5b037c912bb7ce3973",
+        "role": "owner",
+    }));
+    assert!(request.is_err());
+}
@@ -0,0 +1,422 @@
+use chrono::prelude::*;
+use futures::io::AsyncReadExt;
+use futures::stream::{Stream, StreamExt};
+use mongodb::{
+    bson::doc, bson::oid::ObjectId, bson::Bson, bson::Document, options::GridFsUploadOptions,
+    ClientSession,
+};
+
+use mongodb::bson;
+
+use crate::models::attachment_model::Attachment;
+use crate::models::audit_model::Actor;
+use crate::mongo::mongo::{self, MongoRepo};
+use crate::mongo::mongo_any;
+use crate::mongo::mongo_audit;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use crate::transaction;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Size of the blocks streamed to the client, the default GridFS chunk size
+const DOWNLOAD_BLOCK: usize = 255 * 1024;
+
+fn parse_oid(id: &str) -> ApiResult<ObjectId> {
+    match ObjectId::parse_str(id) {
+        Ok(o) => Ok(o),
+        Err(_e) => Err(local_error!(
+            LocalError::OidFormatError,
+            "ObjectId wrongly structure."
+        )),
+    }
+}
+
+/// Build the attachment description from a GridFS files collection document
+fn to_attachment(file: &Document) -> Attachment {
+    let metadata = file.get_document("metadata").ok();
+    let meta_str = |key: &str| {
+        metadata
+            .and_then(|m| m.get_str(key).ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    Attachment {
+        id: file
+            .get_object_id("_id")
+            .map(|o| o.to_hex())
+            .unwrap_or_default(),
+        policy_id: meta_str("policyId"),
+        filename: file.get_str("filename").unwrap_or_default().to_string(),
+        content_type: meta_str("contentType"),
+        length: match file.get("length") {
+            Some(Bson::Int64(l)) => *l as u64,
+            Some(Bson::Int32(l)) => *l as u64,
+            _ => 0,
+        },
+        upload_date: file
+            .get_datetime("uploadDate")
+            .map(|d| d.to_chrono().to_rfc3339())
+            .unwrap_or_default(),
+        archived: metadata
+            .and_then(|m| m.get_bool("archived").ok())
+            .unwrap_or_default(),
+    }
+}
+
+/// Find the attachment of a policy, an attachment of another policy is not found
+async fn find_attachment(db: &MongoRepo, policy_id: &str, file_id: &str) -> ApiResult<Document> {
+    let filter = doc! {"_id": parse_oid(file_id)?, "metadata.policyId": policy_id};
+
+    match db.attachment_col.find_one(filter, None).await {
+        Ok(Some(o)) => Ok(o),
+        Ok(None) => Err(local_error!(
+            LocalError::DataNotFoundError,
+            "No attachment."
+        )),
+        Err(e) => Err(local_error!(
+            LocalError::ConnectionError,
+            format!("Exception while reading attachment : {}", e)
+        )),
+    }
+}
+
+/// Store a file in GridFS, attached to the current version of a policy.
+/// GridFS uploads take no session : the file is uploaded unattached, then attached to the policy
+/// and audited in one transaction. An upload failing to be attached is removed.
+pub async fn add_attachment(
+    db: &MongoRepo,
+    policy_id: &String,
+    filename: &str,
+    content_type: &str,
+    content: &[u8],
+    actor: &Actor,
+) -> ApiResult<Attachment> {
+    // only current policies take new attachments
+    mongo_any::get_any(db, policy_id).await?;
+
+    let options = GridFsUploadOptions::builder()
+        .metadata(doc! {
+            "contentType": content_type,
+            "previousObjectIds": [],
+            "archived": false,
+            "uploadedBy": &actor.subject,
+        })
+        .build();
+
+    let file_id = match db
+        .attachments
+        .upload_from_futures_0_3_reader(filename, content, options)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Attachment upload failed : {}", e)
+            ));
+        }
+    };
+
+    let attached = transaction!(db, |session| attach_with_session(
+        db,
+        policy_id,
+        file_id,
+        actor,
+        &mut session
+    ));
+    if let Err(e) = attached {
+        remove_file(db, file_id).await;
+        return Err(e);
+    }
+
+    let file = find_attachment(db, policy_id, &file_id.to_hex()).await?;
+    Ok(to_attachment(&file))
+}
+
+async fn attach_with_session(
+    db: &MongoRepo,
+    policy_id: &str,
+    file_id: ObjectId,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    if let Err(e) = db
+        .attachment_col
+        .update_one_with_session(
+            doc! {"_id": file_id},
+            doc! {"$set": {"metadata.policyId": policy_id}},
+            None,
+            session,
+        )
+        .await
+    {
+        return Err(mongo::write_error(e, "Attachment link failed"));
+    }
+
+    let file_hex = file_id.to_hex();
+    mongo_audit::record_with_session(
+        db,
+        session,
+        actor,
+        "policy.attachment.created",
+        Some(policy_id),
+        Some(&file_hex),
+    )
+    .await
+}
+
+/// Remove the file of an attachment. Only logged on failure,
+/// a file left behind is attached to no policy.
+async fn remove_file(db: &MongoRepo, file_id: ObjectId) {
+    if let Err(e) = db.attachments.delete(Bson::ObjectId(file_id)).await {
+        eprintln!("Attachment file {} not removed : {}", file_id, e);
+    }
+}
+
+/// Attachments of a policy, archived ones included for a deleted policy
+pub async fn list_attachments(db: &MongoRepo, policy_id: &str) -> ApiResult<Vec<Attachment>> {
+    parse_oid(policy_id)?;
+
+    let mut cursors = match db
+        .attachment_col
+        .find(doc! {"metadata.policyId": policy_id}, None)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading attachments : {}", e)
+            ));
+        }
+    };
+
+    let mut attachments = Vec::new();
+    while let Some(file) = cursors.next().await {
+        match file {
+            Ok(o) => attachments.push(to_attachment(&o)),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading attachments : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(attachments)
+}
+
+/// Open an attachment for download, content streamed block by block from GridFS chunks
+pub async fn open_attachment(
+    db: &MongoRepo,
+    policy_id: &str,
+    file_id: &str,
+) -> ApiResult<(Attachment, impl Stream<Item = Vec<u8>>)> {
+    let file = find_attachment(db, policy_id, file_id).await?;
+    let attachment = to_attachment(&file);
+
+    let reader = match db
+        .attachments
+        .open_download_stream(Bson::ObjectId(parse_oid(file_id)?))
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Attachment download failed : {}", e)
+            ));
+        }
+    };
+
+    let stream = futures::stream::unfold(reader, |mut reader| async move {
+        let mut block = vec![0u8; DOWNLOAD_BLOCK];
+        match reader.read(&mut block).await {
+            Ok(0) => None,
+            Ok(n) => {
+                block.truncate(n);
+                Some((block, reader))
+            }
+            // headers are already sent, the download ends short
+            Err(e) => {
+                eprintln!("Attachment download interrupted : {}", e);
+                None
+            }
+        }
+    });
+
+    Ok((attachment, stream))
+}
+
+/// Remove an attachment of a policy. The file is detached from the policy and audited
+/// in one transaction, then removed from GridFS.
+pub async fn delete_attachment(
+    db: &MongoRepo,
+    policy_id: &str,
+    file_id: &str,
+    actor: &Actor,
+) -> ApiResult<()> {
+    let oid = parse_oid(file_id)?;
+    find_attachment(db, policy_id, file_id).await?;
+
+    transaction!(db, |session| detach_with_session(
+        db,
+        policy_id,
+        oid,
+        actor,
+        &mut session
+    ))?;
+
+    remove_file(db, oid).await;
+    Ok(())
+}
+
+async fn detach_with_session(
+    db: &MongoRepo,
+    policy_id: &str,
+    file_id: ObjectId,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    let filter = doc! {"_id": file_id, "metadata.policyId": policy_id};
+    let update = doc! {"$unset": {"metadata.policyId": ""}};
+    let result = match db
+        .attachment_col
+        .update_one_with_session(filter, update, None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Attachment deletion failed")),
+    };
+
+    if result.matched_count == 0 {
+        return Err(local_error!(
+            LocalError::DataNotFoundError,
+            "No attachment."
+        ));
+    }
+
+    let file_hex = file_id.to_hex();
+    mongo_audit::record_with_session(
+        db,
+        session,
+        actor,
+        "policy.attachment.deleted",
+        Some(policy_id),
+        Some(&file_hex),
+    )
+    .await
+}
+
+/// Link the attachments of a policy to its new version, in the transaction of `update_any`
+pub async fn carry_over(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    old: &str,
+    new: &str,
+) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {"metadata.policyId": new},
+        "$push": {"metadata.previousObjectIds": old},
+    };
+
+    match db
+        .attachment_col
+        .update_many_with_session(doc! {"metadata.policyId": old}, update, None, session)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(mongo::write_error(e, "Attachments carry over failed")),
+    }
+}
+
+/// Archive the attachments of a deleted policy, in the transaction of `delete_any`
+pub async fn archive(db: &MongoRepo, session: &mut ClientSession, id: &str) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {
+            "metadata.archived": true,
+            "metadata.archivedDate": bson::DateTime::from_chrono(Utc::now()),
+        },
+    };
+
+    match db
+        .attachment_col
+        .update_many_with_session(doc! {"metadata.policyId": id}, update, None, session)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(mongo::write_error(e, "Attachments archiving failed")),
+    }
+}
+
+/// Remove the archived attachments of purged deleted policies
+pub async fn purge(db: &MongoRepo, policy_ids: &[String]) -> ApiResult<u64> {
+    let filter = doc! {"metadata.policyId": {"$in": policy_ids}, "metadata.archived": true};
+
+    let mut cursors = match db.attachment_col.find(filter, None).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading attachments : {}", e)
+            ));
+        }
+    };
+
+    let mut ids = Vec::new();
+    while let Some(file) = cursors.next().await {
+        match file {
+            Ok(o) => {
+                if let Ok(id) = o.get_object_id("_id") {
+                    ids.push(id);
+                }
+            }
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading attachments : {}", e)
+                ));
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for id in ids {
+        // delete removes the chunks then the files document
+        if let Err(e) = db.attachments.delete(Bson::ObjectId(id)).await {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Purge of attachments failed : {}", e)
+            ));
+        }
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+#[test]
+fn test_to_attachment() {
+    let id = ObjectId::new();
+    let file = doc! {
+        "_id": id,
+        "length": 1024_i64,
+        "chunkSize": 261120,
+        "uploadDate": bson::DateTime::from_chrono("2024-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap()),
+        "filename": "contract.pdf",
+        "metadata": {"policyId": "655c7c5b037c912bb7ce3973", "contentType": "application/pdf", "archived": false},
+    };
+
+    let attachment = to_attachment(&file);
+    assert_eq!(attachment.id, id.to_hex());
+    assert_eq!(attachment.policy_id, "655c7c5b037c912bb7ce3973");
+    assert_eq!(attachment.content_type, "application/pdf");
+    assert_eq!(attachment.length, 1024);
+    assert_eq!(attachment.upload_date, "2024-01-02T03:04:05+00:00");
+    assert!(!attachment.archived);
+}
@@ -0,0 +1,163 @@
+use chrono::prelude::*;
+use mongodb::{bson::doc, bson::Document, options::FindOptions, ClientSession};
+
+use futures::stream::StreamExt;
+
+use mongodb::bson;
+
+use crate::models::audit_model::Actor;
+use crate::mongo::mongo::{self, MongoRepo};
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Build the audit entry of a mutation. `old` and `new` are the Oids before and after the mutation.
+/// The actor is null for a caller without identity, never an empty string.
+fn audit_document(actor: &Actor, action: &str, old: Option<&str>, new: Option<&str>) -> Document {
+    let subject = Some(actor.subject.as_str()).filter(|s| !s.is_empty());
+    doc! {
+        "actor": subject,
+        "scope": &actor.scope,
+        "ip": &actor.ip,
+        "route": &actor.route,
+        "action": action,
+        "oldObjectId": old,
+        "newObjectId": new,
+        "date": bson::DateTime::from_chrono(Utc::now()),
+    }
+}
+
+/// Record an audit entry in the transaction of a mutation
+pub async fn record_with_session(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    actor: &Actor,
+    action: &str,
+    old: Option<&str>,
+    new: Option<&str>,
+) -> ApiResult<()> {
+    let record = audit_document(actor, action, old, new);
+
+    match db
+        .audit_col
+        .insert_one_with_session(record, None, session)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(mongo::write_error(e, "Insert in audit failed")),
+    }
+}
+
+fn parse_date(value: &str, name: &str) -> ApiResult<bson::DateTime> {
+    match value.parse::<DateTime<Utc>>() {
+        Ok(o) => Ok(bson::DateTime::from_chrono(o)),
+        Err(_e) => Err(local_error!(
+            LocalError::FilterDateParsing,
+            format!("Date filter {} wrongly formatted.", name)
+        )),
+    }
+}
+
+/// Return audit entries, most recent first, filtered by actor, policy Oid (old or new) and date range
+pub async fn get_audit(
+    db: &MongoRepo,
+    filter_actor: Option<String>,
+    filter_policy: Option<String>,
+    filter_from: Option<String>,
+    filter_to: Option<String>,
+    pagination: (i64, i64),
+) -> ApiResult<Vec<serde_json::Value>> {
+    let mut filter = Document::new();
+
+    if let Some(a) = filter_actor {
+        filter.insert("actor", a);
+    }
+
+    if let Some(p) = filter_policy {
+        filter.insert(
+            "$or",
+            vec![doc! {"oldObjectId": &p}, doc! {"newObjectId": &p}],
+        );
+    }
+
+    let mut range = Document::new();
+    if let Some(f) = filter_from {
+        range.insert("$gte", parse_date(&f, "from")?);
+    }
+    if let Some(t) = filter_to {
+        range.insert("$lte", parse_date(&t, "to")?);
+    }
+    if !range.is_empty() {
+        filter.insert("date", range);
+    }
+
+    let (page, limit) = pagination;
+    if page < 1 || limit < 1 {
+        return Err(local_error!(
+            LocalError::ParsingError,
+            "Pagination page and limit must be positive."
+        ));
+    }
+
+    let find_options = FindOptions::builder()
+        .sort(doc! {"date": -1})
+        .skip(((page - 1) * limit) as u64)
+        .limit(limit)
+        .build();
+
+    let mut cursors = match db.audit_col.find(filter, find_options).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading audit : {}", e)
+            ));
+        }
+    };
+
+    let mut records = Vec::new();
+    while let Some(doc) = cursors.next().await {
+        match doc {
+            Ok(mut o) => {
+                if let Ok(id) = o.get_object_id("_id") {
+                    o.insert("_id", id.to_hex());
+                }
+                records.push(bson::Bson::Document(o).into_relaxed_extjson());
+            }
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading audit : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+#[test]
+fn test_audit_document() {
+    let mut actor = Actor {
+        subject: String::from("batch"),
+        scope: String::from("policies:write"),
+        ip: None,
+        route: String::from("POST /api/any"),
+        tenant: None,
+    };
+    let record = audit_document(
+        &actor,
+        "policy.created",
+        None,
+        Some("655c7c5b037c912bb7ce3973"),
+    );
+    assert_eq!(record.get_str("actor").unwrap(), "batch");
+
+    // a caller without identity is recorded as null, not as an empty actor
+    actor.subject = String::new();
+    let record = audit_document(&actor, "policy.created", None, None);
+    assert_eq!(record.get("actor"), Some(&bson::Bson::Null));
+}
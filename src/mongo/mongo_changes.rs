@@ -0,0 +1,201 @@
+use mongodb::{
+    bson::doc,
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    ClientSession,
+};
+
+use futures::stream::StreamExt;
+
+use mongodb::bson;
+
+use crate::mongo::mongo::{self, MongoRepo};
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use rocket::serde::json::json;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Name of the counter document holding the last change sequence number
+const CHANGE_SEQUENCE: &str = "changes";
+
+/// Change operation types stored in the change log
+pub const CHANGE_CREATED: &str = "created";
+pub const CHANGE_UPDATED: &str = "updated";
+pub const CHANGE_DELETED: &str = "deleted";
+
+/// Reserve the next sequence number of the change log.
+/// Sequence numbers are strictly increasing, so they give the commit order of changes.
+async fn next_sequence(db: &MongoRepo, session: &mut ClientSession) -> ApiResult<i64> {
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+
+    let counter = match db
+        .counter_col
+        .find_one_and_update_with_session(
+            doc! {"_id": CHANGE_SEQUENCE},
+            doc! {"$inc": {"seq": 1_i64}},
+            options,
+            session,
+        )
+        .await
+    {
+        Ok(Some(o)) => o,
+        Ok(None) => {
+            return Err(local_error!(
+                LocalError::DataNotFoundError,
+                "Change sequence counter missing."
+            ));
+        }
+        Err(e) => return Err(mongo::write_error(e, "Change sequence increment failed")),
+    };
+
+    match counter.get_i64("seq") {
+        Ok(seq) => Ok(seq),
+        Err(_e) => Err(local_error!(
+            LocalError::ParsingError,
+            "Change sequence counter wrongly structure."
+        )),
+    }
+}
+
+/// Append a change to the change log, in the transaction of the policy mutation.
+/// `previous` holds the superseded policy Oids for an update.
+pub async fn record_change(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    op: &str,
+    id: &str,
+    previous: Vec<String>,
+) -> ApiResult<i64> {
+    let seq = next_sequence(db, session).await?;
+
+    let record = doc! {
+        "seq": seq,
+        "op": op,
+        "policyId": id,
+        "previousObjectIds": previous,
+        "changeDate": bson::DateTime::from_chrono(chrono::Utc::now()),
+    };
+
+    match db
+        .change_col
+        .insert_one_with_session(record, None, session)
+        .await
+    {
+        Ok(_o) => Ok(seq),
+        Err(e) => Err(mongo::write_error(e, "Insert in change log failed")),
+    }
+}
+
+/// Parse a sync token as returned by `get_changes`. An empty token starts from the beginning.
+pub fn parse_sync_token(token: Option<String>) -> ApiResult<i64> {
+    match token {
+        None => Ok(0),
+        Some(t) if t.is_empty() => Ok(0),
+        Some(t) => match t.parse::<i64>() {
+            Ok(seq) if seq >= 0 => Ok(seq),
+            _ => Err(local_error!(
+                LocalError::ParsingError,
+                "Invalid sync token."
+            )),
+        },
+    }
+}
+
+/// Return changes committed after the `since` sync token, in commit order, with the token to use for the next call.
+pub async fn get_changes(
+    db: &MongoRepo,
+    since: Option<String>,
+    limit: i64,
+) -> ApiResult<serde_json::Value> {
+    let from = parse_sync_token(since)?;
+
+    // read one more record than asked to know if another call is needed
+    let find_options = FindOptions::builder()
+        .sort(doc! {"seq": 1})
+        .limit(limit + 1)
+        .build();
+
+    let mut cursors = match db
+        .change_col
+        .find(doc! {"seq": {"$gt": from}}, find_options)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading change log : {}", e)
+            ));
+        }
+    };
+
+    let mut changes = Vec::new();
+    let mut created = Vec::new();
+    let mut updated = Vec::new();
+    let mut deleted = Vec::new();
+    let mut token = from;
+    let mut has_more = false;
+
+    while let Some(doc) = cursors.next().await {
+        let record = match doc {
+            Ok(o) => o,
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading change log : {}", e)
+                ));
+            }
+        };
+
+        if changes.len() as i64 == limit {
+            has_more = true;
+            break;
+        }
+
+        let (seq, op, id) = match (
+            record.get_i64("seq"),
+            record.get_str("op"),
+            record.get_str("policyId"),
+        ) {
+            (Ok(seq), Ok(op), Ok(id)) => (seq, op.to_string(), id.to_string()),
+            _ => {
+                return Err(local_error!(
+                    LocalError::ParsingError,
+                    "Change log record wrongly structure."
+                ));
+            }
+        };
+
+        let previous: Vec<String> = match record.get_array("previousObjectIds") {
+            Ok(o) => o
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect(),
+            Err(_e) => Vec::new(),
+        };
+
+        match op.as_str() {
+            CHANGE_CREATED => created.push(json!(id)),
+            CHANGE_UPDATED => updated.push(json!({"id": id, "previousObjectIds": previous})),
+            CHANGE_DELETED => deleted.push(json!(id)),
+            _ => {}
+        }
+
+        changes.push(json!({"seq": seq, "op": op, "id": id, "previousObjectIds": previous}));
+        token = seq;
+    }
+
+    Ok(json!({
+        "changes": changes,
+        "created": created,
+        "updated": updated,
+        "deleted": deleted,
+        "hasMore": has_more,
+        "token": token.to_string(),
+    }))
+}
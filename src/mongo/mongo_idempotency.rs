@@ -0,0 +1,274 @@
+use std::time::Duration;
+
+use chrono::prelude::*;
+use mongodb::{
+    bson::doc, bson::oid::ObjectId, bson::Document, options::IndexOptions, ClientSession,
+    IndexModel,
+};
+use sha2::{Digest, Sha256};
+
+use mongodb::bson;
+
+use crate::models::audit_model::Actor;
+use crate::mongo::mongo::{self, MongoRepo};
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Hash of a request body, to detect a key reused with a different body
+pub fn request_hash(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body.as_bytes()))
+}
+
+/// Delay after which a key left in progress (crash, cancelled request) is taken over by a retry
+const LEASE_SECONDS: i64 = 60;
+
+/// Reservation of an idempotency key
+#[derive(Debug)]
+pub enum Reservation {
+    /// The request has to be processed, under the lease `lock`
+    Acquired(String),
+    /// Response of the first request with this key
+    Replay(serde_json::Value),
+}
+
+/// Keys are scoped to the caller, two callers may use the same key.
+/// Callers without subject would share their keys, they cannot send one.
+fn record_id(actor: &Actor, key: &str) -> ApiResult<String> {
+    if actor.subject.is_empty() {
+        return Err(local_error!(
+            LocalError::ContextError,
+            "Idempotency-Key requires a token subject."
+        ));
+    }
+    Ok(format!("{}:{}", actor.subject, key))
+}
+
+/// TTL index expiring stored keys and responses
+pub fn ttl_index(ttl: Duration) -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! {"createdDate": 1})
+        .options(
+            IndexOptions::builder()
+                .name(String::from("idempotency_ttl"))
+                .expire_after(ttl)
+                .build(),
+        )
+        .build()
+}
+
+/// Reserve an idempotency key before processing a request.
+/// Return the stored response when the key was already used with the same body.
+/// A key whose lease expired without response is taken over.
+pub async fn begin(db: &MongoRepo, actor: &Actor, key: &str, hash: &str) -> ApiResult<Reservation> {
+    let id = record_id(actor, key)?;
+    let lock = ObjectId::new().to_hex();
+    let now = Utc::now();
+    let locked_until = bson::DateTime::from_chrono(now + chrono::Duration::seconds(LEASE_SECONDS));
+    let record = doc! {
+        "_id": &id,
+        "requestHash": hash,
+        "lock": &lock,
+        "lockedUntil": locked_until,
+        "createdDate": bson::DateTime::from_chrono(now),
+    };
+
+    match db.idempotency_col.insert_one(record, None).await {
+        Ok(_o) => return Ok(Reservation::Acquired(lock)),
+        Err(e) if mongo::is_duplicate_key(&e) => {}
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Insert in idempotency store failed : {}", e)
+            ));
+        }
+    };
+
+    let existing = match db.idempotency_col.find_one(doc! {"_id": &id}, None).await {
+        Ok(Some(o)) => o,
+        // expired between the insert and the read
+        Ok(None) => {
+            return Err(local_error!(
+                LocalError::TransientError,
+                "Idempotency key expired."
+            ))
+        }
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception on get idempotency key : {}", e)
+            ));
+        }
+    };
+
+    if let Some(response) = stored_response(&existing, hash, now)? {
+        return Ok(Reservation::Replay(response));
+    }
+
+    // the lease is taken over once, by the retry replacing the lock it read
+    let taken = db
+        .idempotency_col
+        .update_one(
+            doc! {
+                "_id": &id,
+                "lock": existing.get("lock").cloned().unwrap_or(bson::Bson::Null),
+                "response": {"$exists": false},
+            },
+            doc! {"$set": {"lock": &lock, "lockedUntil": locked_until}},
+            None,
+        )
+        .await;
+    match taken {
+        Ok(o) if o.modified_count == 1 => Ok(Reservation::Acquired(lock)),
+        Ok(_o) => Err(local_error!(
+            LocalError::ConflictError,
+            "A request with this Idempotency-Key is in progress."
+        )),
+        Err(e) => Err(local_error!(
+            LocalError::ConnectionError,
+            format!("Idempotency key lease update failed : {}", e)
+        )),
+    }
+}
+
+/// Response of a key already used : replayed for the same body, a conflict for another body
+/// or while the first request holds its lease. None when the lease expired without response.
+fn stored_response(
+    existing: &Document,
+    hash: &str,
+    now: DateTime<Utc>,
+) -> ApiResult<Option<serde_json::Value>> {
+    if existing.get_str("requestHash").unwrap_or_default() != hash {
+        return Err(local_error!(
+            LocalError::ConflictError,
+            "Idempotency-Key already used with a different request body."
+        ));
+    }
+
+    if let Some(response) = existing.get("response") {
+        return Ok(Some(response.clone().into_relaxed_extjson()));
+    }
+
+    match existing.get_datetime("lockedUntil") {
+        Ok(until) if until.to_chrono() > now => Err(local_error!(
+            LocalError::ConflictError,
+            "A request with this Idempotency-Key is in progress."
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// Store the response of a processed request, replayed for retries with the same key.
+/// Written in the transaction of the mutation, the key is never left in progress after a success.
+/// A request whose lease was taken over by a retry fails, its mutation is rolled back.
+pub async fn complete(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    actor: &Actor,
+    key: &str,
+    lock: &str,
+    response: &serde_json::Value,
+) -> ApiResult<()> {
+    let response = match bson::to_bson(response) {
+        Ok(o) => o,
+        Err(_e) => {
+            return Err(local_error!(
+                LocalError::ParsingError,
+                "Idempotent response parsing failed."
+            ));
+        }
+    };
+
+    match db
+        .idempotency_col
+        .update_one_with_session(
+            doc! {"_id": record_id(actor, key)?, "lock": lock},
+            doc! {"$set": {"response": response}},
+            None,
+            session,
+        )
+        .await
+    {
+        Ok(o) if o.matched_count == 1 => Ok(()),
+        Ok(_o) => Err(local_error!(
+            LocalError::ConflictError,
+            "Idempotency-Key lease expired, the request was taken over by a retry."
+        )),
+        Err(e) => Err(mongo::write_error(e, "Idempotent response update failed")),
+    }
+}
+
+/// Release a key whose request failed, so that the client can retry it.
+/// A key taken over by a retry is left to it.
+pub async fn abandon(db: &MongoRepo, actor: &Actor, key: &str, lock: &str) -> ApiResult<()> {
+    match db
+        .idempotency_col
+        .delete_one(doc! {"_id": record_id(actor, key)?, "lock": lock}, None)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(local_error!(
+            LocalError::ConnectionError,
+            format!("Delete from idempotency store failed : {}", e)
+        )),
+    }
+}
+
+#[test]
+fn test_stored_response() {
+    let now = Utc::now();
+    let leased = bson::DateTime::from_chrono(now + chrono::Duration::seconds(LEASE_SECONDS));
+    let expired = bson::DateTime::from_chrono(now - chrono::Duration::seconds(1));
+    let hash = request_hash(r#"{"policy":{"name":"toto"}}"#);
+    let in_progress = doc! {"_id": "tests:k1", "requestHash": &hash, "lockedUntil": leased};
+    let completed = doc! {
+        "_id": "tests:k1",
+        "requestHash": &hash,
+        "lockedUntil": expired,
+        "response": {"policy": {"name": "toto"}, "version": 1},
+    };
+
+    // a retry with the same body replays the first response, even after the lease
+    assert_eq!(
+        stored_response(&completed, &hash, now).unwrap(),
+        Some(serde_json::json!({"policy": {"name": "toto"}, "version": 1}))
+    );
+
+    // the same key with another body is a conflict, even once completed
+    let other = request_hash(r#"{"policy":{"name":"titi"}}"#);
+    assert_ne!(hash, other);
+    let mismatch = stored_response(&completed, &other, now).unwrap_err();
+    assert!(matches!(mismatch.kind(), LocalError::ConflictError(_)));
+    assert!(mismatch.to_string().contains("different request body"));
+
+    let running = stored_response(&in_progress, &hash, now).unwrap_err();
+    assert!(running.to_string().contains("in progress"));
+
+    // a request which never completed is taken over once its lease expired
+    let abandoned = doc! {"_id": "tests:k1", "requestHash": &hash, "lockedUntil": expired};
+    assert_eq!(stored_response(&abandoned, &hash, now).unwrap(), None);
+    let after_lease = now + chrono::Duration::seconds(LEASE_SECONDS + 1);
+    assert_eq!(
+        stored_response(&in_progress, &hash, after_lease).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_record_id() {
+    let mut actor = Actor {
+        subject: String::from("batch"),
+        scope: String::from("policies:write"),
+        ip: None,
+        route: String::from("POST /api/any"),
+        tenant: None,
+    };
+    assert_eq!(record_id(&actor, "k1").unwrap(), "batch:k1");
+
+    actor.subject = String::new();
+    let e = record_id(&actor, "k1").unwrap_err();
+    assert!(matches!(e.kind(), LocalError::ContextError(_)));
+}
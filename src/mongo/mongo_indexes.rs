@@ -0,0 +1,536 @@
+use std::time::Duration;
+
+use mongodb::{
+    bson::doc, bson::Bson, bson::Document, options::IndexOptions, Collection, IndexModel,
+};
+use serde::Deserialize;
+
+use futures::stream::StreamExt;
+
+use crate::mongo::business_key::BusinessKey;
+use crate::mongo::mongo::MongoRepo;
+use crate::mongo::mongo_idempotency;
+use crate::mongo::mongo_search::SearchConfig;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use rocket::serde::json::json;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Index required by the application on one of the MongoRepo collections
+#[derive(Clone, Debug)]
+pub struct IndexSpec {
+    pub collection: String,
+    pub model: IndexModel,
+}
+
+/// Additional index declared in the configuration server file
+/// [[extra_indexes]]
+/// collection = "policies"
+/// name = "policy_location"
+/// keys = ["policy.location:1", "requestDate:-1"]
+/// unique = false
+#[derive(Debug, Deserialize)]
+pub struct IndexConfig {
+    pub collection: String,
+    pub name: String,
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub unique: bool,
+}
+
+fn spec(collection: &str, name: &str, keys: Document, options: Option<IndexOptions>) -> IndexSpec {
+    let mut options = options.unwrap_or_default();
+    options.name = Some(name.to_string());
+
+    IndexSpec {
+        collection: collection.to_string(),
+        model: IndexModel::builder().keys(keys).options(options).build(),
+    }
+}
+
+fn unique() -> Option<IndexOptions> {
+    Some(IndexOptions::builder().unique(true).build())
+}
+
+/// Unique among the documents having the field, users created before it are not concerned
+fn unique_partial(field: &str) -> IndexOptions {
+    IndexOptions::builder()
+        .unique(true)
+        .partial_filter_expression(doc! {field: {"$type": "string"}})
+        .build()
+}
+
+/// Parse the "path:direction" keys of a configured index
+fn config_spec(index: &IndexConfig) -> Result<IndexSpec, String> {
+    let mut keys = Document::new();
+    for key in &index.keys {
+        let (path, direction) = match key.rsplit_once(':') {
+            Some((p, d)) => (p, d),
+            None => (key.as_str(), "1"),
+        };
+        match direction.parse::<i32>() {
+            Ok(d) => keys.insert(path, d),
+            Err(_e) => keys.insert(path, direction),
+        };
+    }
+
+    if keys.is_empty() {
+        return Err(format!("Index {} has no key.", index.name));
+    }
+
+    let options = if index.unique { unique() } else { None };
+    Ok(spec(&index.collection, &index.name, keys, options))
+}
+
+/// Indexes required by the application : unique user emails and subjects, policy filters, version chains,
+/// business key, full text search, and the lookups of the change log, outbox, audit, legal hold, idempotency, attachment and assignment collections.
+/// Indexes declared in the `extra_indexes` configuration are added.
+pub fn required_indexes(
+    settings: &config::Config,
+    key: &BusinessKey,
+    search: &SearchConfig,
+    idempotency_ttl: Duration,
+) -> Result<Vec<IndexSpec>, String> {
+    let mut specs = vec![
+        spec("policies", "requestDate", doc! {"requestDate": 1}, None),
+        spec(
+            "policies",
+            "policyStartDate",
+            doc! {"policyStartDate": 1},
+            None,
+        ),
+        spec("policies", "policyholder", doc! {"policy.name": 1}, None),
+        spec(
+            "policies",
+            "previousObjectIds",
+            doc! {"previousObjectIds": 1},
+            None,
+        ),
+        spec(
+            "history",
+            "previousObjectIds",
+            doc! {"previousObjectIds": 1},
+            None,
+        ),
+        spec("deleted", "policyId", doc! {"content._id": 1}, None),
+        spec("deleted", "deletedDate", doc! {"deletedDate": 1}, None),
+        spec("changes", "seq", doc! {"seq": 1}, unique()),
+        spec("outbox", "pending", doc! {"delivered": 1, "seq": 1}, None),
+        spec("audit", "actor", doc! {"actor": 1, "date": -1}, None),
+        spec("audit", "date", doc! {"date": -1}, None),
+        spec("audit", "oldObjectId", doc! {"oldObjectId": 1}, None),
+        spec("audit", "newObjectId", doc! {"newObjectId": 1}, None),
+        spec("holds", "policyId", doc! {"policyId": 1}, unique()),
+        spec(
+            "Users",
+            "email",
+            doc! {"email": 1},
+            Some(unique_partial("email")),
+        ),
+        spec(
+            "Users",
+            "idpSubject",
+            doc! {"idpSubject": 1},
+            Some(unique_partial("idpSubject")),
+        ),
+        spec(
+            "attachments.files",
+            "policyId",
+            doc! {"metadata.policyId": 1},
+            None,
+        ),
+        spec(
+            "assignments",
+            "assignment",
+            doc! {"policyId": 1, "userId": 1, "role": 1},
+            unique(),
+        ),
+        spec("assignments", "userId", doc! {"userId": 1}, None),
+    ];
+
+    if let Some(model) = key.index() {
+        specs.push(IndexSpec {
+            collection: String::from("policies"),
+            model,
+        });
+    }
+
+    if !search.paths.is_empty() {
+        specs.push(IndexSpec {
+            collection: String::from("policies"),
+            model: search.index(),
+        });
+    }
+
+    specs.push(IndexSpec {
+        collection: String::from("idempotency"),
+        model: mongo_idempotency::ttl_index(idempotency_ttl),
+    });
+
+    let extra = settings
+        .get::<Vec<IndexConfig>>("extra_indexes")
+        .unwrap_or_default();
+    for index in &extra {
+        specs.push(config_spec(index)?);
+    }
+
+    Ok(specs)
+}
+
+/// Indexes of the collections shared by the tenants, in the default repo : unique API key hashes
+/// and the revocation list order
+pub fn shared_indexes() -> Vec<IndexSpec> {
+    vec![
+        spec("apikeys", "keySha256", doc! {"keySha256": 1}, unique()),
+        spec(
+            "revocations",
+            "revokedDate",
+            doc! {"revokedDate": 1, "_id": 1},
+            None,
+        ),
+    ]
+}
+
+/// Collection of the repo holding the indexes of a spec
+fn collection(db: &MongoRepo, name: &str) -> ApiResult<Collection<Document>> {
+    match name {
+        "Users" => Ok(db.user_col.clone_with_type()),
+        "policies" => Ok(db.policy_col.clone()),
+        "history" => Ok(db.history_col.clone()),
+        "deleted" => Ok(db.deleted_col.clone()),
+        "changes" => Ok(db.change_col.clone()),
+        "counters" => Ok(db.counter_col.clone()),
+        "outbox" => Ok(db.outbox_col.clone()),
+        "audit" => Ok(db.audit_col.clone()),
+        "holds" => Ok(db.hold_col.clone()),
+        "idempotency" => Ok(db.idempotency_col.clone()),
+        "attachments.files" => Ok(db.attachment_col.clone()),
+        "assignments" => Ok(db.assignment_col.clone_with_type()),
+        "apikeys" => Ok(db.api_key_col.clone_with_type()),
+        "revocations" => Ok(db.revocation_col.clone_with_type()),
+        other => Err(local_error!(
+            LocalError::ContextError,
+            format!("Unknown collection {} for index.", other)
+        )),
+    }
+}
+
+fn index_name(model: &IndexModel) -> String {
+    model
+        .options
+        .as_ref()
+        .and_then(|o| o.name.clone())
+        .unwrap_or_default()
+}
+
+/// Values are compared as numbers when possible, the server may return 1 as a double
+fn normalize(v: &Bson) -> Bson {
+    match v {
+        Bson::Int32(i) => Bson::Double(*i as f64),
+        Bson::Int64(i) => Bson::Double(*i as f64),
+        Bson::Document(d) => {
+            Bson::Document(d.iter().map(|(k, v)| (k.clone(), normalize(v))).collect())
+        }
+        Bson::Array(a) => Bson::Array(a.iter().map(normalize).collect()),
+        other => other.clone(),
+    }
+}
+
+fn same_keys(a: &Document, b: &Document) -> bool {
+    // text indexes are listed with internal keys (_fts, _ftsx), only their name is compared
+    if a.values().any(|v| v.as_str() == Some("text")) || b.contains_key("_fts") {
+        return true;
+    }
+
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|((ka, va), (kb, vb))| ka == kb && normalize(va) == normalize(vb))
+}
+
+/// Options changing the behaviour of an index : unique, sparse, TTL, partial filter and text language.
+/// The text language is only compared when required.
+fn same_options(required: Option<&IndexOptions>, existing: Option<&IndexOptions>) -> bool {
+    let default = IndexOptions::default();
+    let a = required.unwrap_or(&default);
+    let b = existing.unwrap_or(&default);
+    let partial = |o: &IndexOptions| {
+        o.partial_filter_expression
+            .as_ref()
+            .map(|p| normalize(&Bson::Document(p.clone())))
+    };
+
+    a.unique.unwrap_or(false) == b.unique.unwrap_or(false)
+        && a.sparse.unwrap_or(false) == b.sparse.unwrap_or(false)
+        && a.expire_after == b.expire_after
+        && partial(a) == partial(b)
+        && (a.default_language.is_none() || a.default_language == b.default_language)
+}
+
+/// Same keys and options, the name being already matched
+fn same_index(required: &IndexModel, existing: &IndexModel) -> bool {
+    same_keys(&required.keys, &existing.keys)
+        && same_options(required.options.as_ref(), existing.options.as_ref())
+}
+
+/// True when an index creation failed on an existing index of the same name
+/// with other options (IndexOptionsConflict) or other keys (IndexKeySpecsConflict)
+fn is_index_conflict(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        mongodb::error::ErrorKind::Command(ce) => ce.code == 85 || ce.code == 86,
+        _ => false,
+    }
+}
+
+async fn list_indexes(col: &Collection<Document>) -> ApiResult<Vec<IndexModel>> {
+    let mut cursors = match col.list_indexes(None).await {
+        Ok(o) => o,
+        Err(e) => {
+            // a collection not created yet has no index
+            if let mongodb::error::ErrorKind::Command(ce) = e.kind.as_ref() {
+                if ce.code == 26 {
+                    return Ok(Vec::new());
+                }
+            }
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while listing indexes : {}", e)
+            ));
+        }
+    };
+
+    let mut indexes = Vec::new();
+    while let Some(index) = cursors.next().await {
+        match index {
+            Ok(o) => indexes.push(o),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while listing indexes : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(indexes)
+}
+
+/// Create the missing indexes. An existing index with the same name but other keys or options is
+/// replaced. Errors are collected so that one failing index does not prevent the others.
+pub async fn ensure_indexes(db: &MongoRepo, specs: &[IndexSpec]) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    for spec in specs {
+        let col = match collection(db, &spec.collection) {
+            Ok(o) => o,
+            Err(e) => {
+                errors.push(e.to_string());
+                continue;
+            }
+        };
+        let name = index_name(&spec.model);
+
+        let existing = match list_indexes(&col).await {
+            Ok(o) => o,
+            Err(e) => {
+                errors.push(e.to_string());
+                continue;
+            }
+        };
+        if existing
+            .iter()
+            .any(|i| index_name(i) == name && same_index(&spec.model, i))
+        {
+            continue;
+        }
+
+        // an index of the same name with other keys or options is replaced, other errors are reported
+        match col.create_index(spec.model.clone(), None).await {
+            Ok(_) => continue,
+            Err(e) if !is_index_conflict(&e) => {
+                errors.push(format!(
+                    "Index {}.{} creation failed : {}",
+                    spec.collection, name, e
+                ));
+                continue;
+            }
+            Err(_e) => {}
+        }
+
+        if let Err(e) = col.drop_index(&name, None).await {
+            errors.push(format!(
+                "Index {}.{} replacement failed : {}",
+                spec.collection, name, e
+            ));
+            continue;
+        }
+        if let Err(e) = col.create_index(spec.model.clone(), None).await {
+            errors.push(format!(
+                "Index {}.{} creation failed : {}",
+                spec.collection, name, e
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Index builds currently running on the database of the repo, by namespace and index name
+async fn indexes_in_progress(db: &MongoRepo) -> ApiResult<Vec<serde_json::Value>> {
+    let command = doc! {
+        "currentOp": true,
+        "ns": {"$regex": format!("^{}\\.", db.database)},
+        "$or": [
+            {"command.createIndexes": {"$exists": true}},
+            {"msg": {"$regex": "^Index Build"}},
+        ],
+    };
+
+    let result = match db.repo.database("admin").run_command(command, None).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading current operations : {}", e)
+            ));
+        }
+    };
+
+    let mut builds = Vec::new();
+    if let Ok(operations) = result.get_array("inprog") {
+        for op in operations.iter().filter_map(|o| o.as_document()) {
+            let ns = op.get_str("ns").unwrap_or_default();
+            let names: Vec<String> = op
+                .get_document("command")
+                .and_then(|c| c.get_array("indexes"))
+                .map(|indexes| {
+                    indexes
+                        .iter()
+                        .filter_map(|i| i.as_document())
+                        .filter_map(|i| i.get_str("name").ok().map(|n| n.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            builds.push(json!({
+                "ns": ns,
+                "indexes": names,
+                "progress": op.get_str("msg").unwrap_or_default(),
+            }));
+        }
+    }
+
+    Ok(builds)
+}
+
+/// Compare the required indexes with the indexes of the collections :
+/// missing, extra (not declared, _id excepted), mismatched (same name, other keys or options) and in progress builds
+pub async fn index_report(db: &MongoRepo, specs: &[IndexSpec]) -> ApiResult<serde_json::Value> {
+    let mut collections: Vec<&str> = specs.iter().map(|s| s.collection.as_str()).collect();
+    collections.sort();
+    collections.dedup();
+
+    let mut missing = Vec::new();
+    let mut extra = Vec::new();
+    let mut mismatched = Vec::new();
+
+    for name in collections {
+        let existing = list_indexes(&collection(db, name)?).await?;
+        let required: Vec<&IndexSpec> = specs.iter().filter(|s| s.collection == name).collect();
+
+        for spec in &required {
+            let index = index_name(&spec.model);
+            match existing.iter().find(|i| index_name(i) == index) {
+                None => missing.push(json!({"collection": name, "name": index})),
+                Some(i) if !same_index(&spec.model, i) => {
+                    mismatched.push(json!({"collection": name, "name": index}))
+                }
+                Some(_i) => {}
+            }
+        }
+
+        for i in &existing {
+            let index = index_name(i);
+            if index != "_id_" && !required.iter().any(|s| index_name(&s.model) == index) {
+                extra.push(json!({"collection": name, "name": index}));
+            }
+        }
+    }
+
+    let in_progress = match indexes_in_progress(db).await {
+        Ok(o) => json!(o),
+        Err(e) => json!({"exception": e.to_string()}),
+    };
+
+    Ok(json!({
+        "missing": missing,
+        "extra": extra,
+        "mismatched": mismatched,
+        "inProgress": in_progress,
+    }))
+}
+
+#[test]
+fn test_config_index() {
+    let index = IndexConfig {
+        collection: String::from("policies"),
+        name: String::from("policy_location"),
+        keys: vec![
+            String::from("policy.location:1"),
+            String::from("requestDate:-1"),
+        ],
+        unique: true,
+    };
+    let spec = config_spec(&index).unwrap();
+
+    assert_eq!(
+        spec.model.keys,
+        doc! {"policy.location": 1, "requestDate": -1}
+    );
+    assert_eq!(index_name(&spec.model), "policy_location");
+    assert!(same_keys(
+        &spec.model.keys,
+        &doc! {"policy.location": 1.0, "requestDate": -1_i64}
+    ));
+    assert!(!same_keys(&spec.model.keys, &doc! {"policy.location": 1}));
+}
+
+#[test]
+fn test_same_index() {
+    let existing = |options: Document| -> IndexModel {
+        let mut index = doc! {"v": 2, "key": {"email": 1.0}, "name": "email"};
+        index.extend(options);
+        mongodb::bson::from_document(index).unwrap()
+    };
+    let required = spec(
+        "Users",
+        "email",
+        doc! {"email": 1},
+        Some(unique_partial("email")),
+    );
+
+    assert!(same_index(
+        &required.model,
+        &existing(doc! {"unique": true, "partialFilterExpression": {"email": {"$type": "string"}}})
+    ));
+    assert!(!same_index(
+        &required.model,
+        &existing(doc! {"unique": true})
+    ));
+    assert!(!same_index(
+        &required.model,
+        &existing(doc! {"partialFilterExpression": {"email": {"$type": "string"}}})
+    ));
+
+    let ttl = mongo_idempotency::ttl_index(Duration::from_secs(3600));
+    let listed = |seconds: i32| -> IndexModel {
+        mongodb::bson::from_document(doc! {
+            "v": 2, "key": {"createdDate": 1}, "name": "idempotency_ttl", "expireAfterSeconds": seconds,
+        })
+        .unwrap()
+    };
+    assert!(same_index(&ttl, &listed(3600)));
+    assert!(!same_index(&ttl, &listed(60)));
+}
@@ -0,0 +1,138 @@
+use mongodb::{
+    bson::doc, bson::oid::ObjectId, bson::Document, options::FindOptions, ClientSession,
+};
+
+use futures::stream::StreamExt;
+
+use mongodb::bson;
+
+use crate::mongo::mongo::{self, MongoRepo};
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Write a domain event in the outbox, in the transaction of the policy mutation.
+/// The event is published later by the outbox relay.
+pub async fn enqueue(
+    db: &MongoRepo,
+    session: &mut ClientSession,
+    event_type: &str,
+    seq: i64,
+    id: &str,
+    previous: Vec<String>,
+    payload: Option<&serde_json::Value>,
+) -> ApiResult<()> {
+    let payload = match payload.map(bson::to_bson) {
+        Some(Ok(o)) => o,
+        Some(Err(_e)) => {
+            return Err(local_error!(
+                LocalError::ParsingError,
+                "Outbox event payload parsing failed."
+            ));
+        }
+        None => bson::Bson::Null,
+    };
+
+    let record = doc! {
+        "type": format!("policy.{}", event_type),
+        "seq": seq,
+        "policyId": id,
+        "previousObjectIds": previous,
+        "payload": payload,
+        "createdDate": bson::DateTime::from_chrono(chrono::Utc::now()),
+        "delivered": false,
+        "attempts": 0,
+    };
+
+    match db
+        .outbox_col
+        .insert_one_with_session(record, None, session)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(mongo::write_error(e, "Insert in outbox failed")),
+    }
+}
+
+/// Return undelivered events, oldest first
+pub async fn get_pending(db: &MongoRepo, limit: i64) -> ApiResult<Vec<Document>> {
+    let find_options = FindOptions::builder()
+        .sort(doc! {"seq": 1})
+        .limit(limit)
+        .build();
+
+    let mut cursors = match db
+        .outbox_col
+        .find(doc! {"delivered": false}, find_options)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading outbox : {}", e)
+            ));
+        }
+    };
+
+    let mut events = Vec::new();
+    while let Some(doc) = cursors.next().await {
+        match doc {
+            Ok(o) => events.push(o),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading outbox : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+/// Flag an event as delivered to every sink
+pub async fn mark_delivered(db: &MongoRepo, id: ObjectId) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {
+            "delivered": true,
+            "deliveredDate": bson::DateTime::from_chrono(chrono::Utc::now()),
+        },
+        "$inc": {"attempts": 1},
+    };
+
+    match db
+        .outbox_col
+        .update_one(doc! {"_id": id}, update, None)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(local_error!(
+            LocalError::ConnectionError,
+            format!("Outbox delivery update failed : {}", e)
+        )),
+    }
+}
+
+/// Keep track of a failed delivery, the event stays pending and is sent again
+pub async fn mark_failed(db: &MongoRepo, id: ObjectId, error: &str) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {"lastError": error},
+        "$inc": {"attempts": 1},
+    };
+
+    match db
+        .outbox_col
+        .update_one(doc! {"_id": id}, update, None)
+        .await
+    {
+        Ok(_o) => Ok(()),
+        Err(e) => Err(local_error!(
+            LocalError::ConnectionError,
+            format!("Outbox delivery update failed : {}", e)
+        )),
+    }
+}
@@ -0,0 +1,475 @@
+use std::collections::HashSet;
+
+use chrono::prelude::*;
+use mongodb::{
+    bson::doc, bson::oid::ObjectId, bson::Document, options::FindOptions, options::UpdateOptions,
+    results::DeleteResult, ClientSession,
+};
+
+use futures::stream::StreamExt;
+
+use mongodb::bson;
+
+use crate::models::audit_model::Actor;
+use crate::models::retention_model::{LegalHold, PurgePlan};
+use crate::mongo::mongo::{self, MongoRepo};
+use crate::mongo::mongo_assignments;
+use crate::mongo::mongo_attachments;
+use crate::mongo::mongo_audit;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use crate::transaction;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// True when the Oid is a current, history or deleted version of a policy
+async fn version_exists(db: &MongoRepo, oid: ObjectId) -> ApiResult<bool> {
+    let lookups = [
+        (&db.policy_col, doc! {"_id": oid}),
+        (&db.history_col, doc! {"_id": oid}),
+        (&db.deleted_col, doc! {"content._id": oid}),
+    ];
+    for (collection, filter) in lookups {
+        match collection.count_documents(filter, None).await {
+            Ok(0) => {}
+            Ok(_n) => return Ok(true),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception on get policy version : {}", e)
+                ));
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Put a policy under legal hold, its versions are then never purged
+pub async fn hold(db: &MongoRepo, hold: LegalHold, actor: &Actor) -> ApiResult<()> {
+    let oid = match ObjectId::parse_str(&hold.policy_id) {
+        Ok(o) => o,
+        Err(_e) => {
+            return Err(local_error!(
+                LocalError::OidFormatError,
+                "ObjectId wrongly structure."
+            ));
+        }
+    };
+
+    // a mistyped Oid would hold nothing
+    if !version_exists(db, oid).await? {
+        return Err(local_error!(
+            LocalError::DataNotFoundError,
+            "No policy version with this ObjectId."
+        ));
+    }
+
+    transaction!(db, |session| hold_with_session(
+        db,
+        &hold,
+        actor,
+        &mut session
+    ))
+}
+
+async fn hold_with_session(
+    db: &MongoRepo,
+    hold: &LegalHold,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    let update = doc! {
+        "$set": {
+            "policyId": &hold.policy_id,
+            "reason": &hold.reason,
+            "actor": &actor.subject,
+            "date": bson::DateTime::from_chrono(Utc::now()),
+        }
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+
+    match db
+        .hold_col
+        .update_one_with_session(doc! {"policyId": &hold.policy_id}, update, options, session)
+        .await
+    {
+        Ok(_o) => {}
+        Err(e) => return Err(mongo::write_error(e, "Insert in legal hold failed")),
+    };
+
+    mongo_audit::record_with_session(
+        db,
+        session,
+        actor,
+        "policy.held",
+        Some(&hold.policy_id),
+        None,
+    )
+    .await
+}
+
+/// Release the legal hold of a policy
+pub async fn release(db: &MongoRepo, id: &str, actor: &Actor) -> ApiResult<DeleteResult> {
+    transaction!(db, |session| release_with_session(
+        db,
+        id,
+        actor,
+        &mut session
+    ))
+}
+
+async fn release_with_session(
+    db: &MongoRepo,
+    id: &str,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<DeleteResult> {
+    let result = match db
+        .hold_col
+        .delete_one_with_session(doc! {"policyId": id}, None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Delete from legal hold failed")),
+    };
+
+    if result.deleted_count == 1 {
+        mongo_audit::record_with_session(db, session, actor, "policy.released", Some(id), None)
+            .await?;
+    }
+
+    Ok(result)
+}
+
+/// Oids of policies under legal hold
+async fn get_holds(db: &MongoRepo) -> ApiResult<HashSet<String>> {
+    let mut cursors = match db.hold_col.find(None, None).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading legal holds : {}", e)
+            ));
+        }
+    };
+
+    let mut holds = HashSet::new();
+    while let Some(doc) = cursors.next().await {
+        match doc {
+            Ok(o) => {
+                if let Ok(id) = o.get_str("policyId") {
+                    holds.insert(id.to_string());
+                }
+            }
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading legal holds : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(holds)
+}
+
+/// Smallest ObjectId generated at the given date, to filter records on their creation time
+fn oid_from_date(date: DateTime<Utc>) -> ObjectId {
+    let mut bytes = [0u8; 12];
+    bytes[..4].copy_from_slice(&(date.timestamp() as u32).to_be_bytes());
+    ObjectId::from_bytes(bytes)
+}
+
+/// Policy Oid and previous versions Oids, most recent first, of a policy document
+fn versions(record: &Document) -> (Option<String>, Vec<String>) {
+    let id = record.get_object_id("_id").ok().map(|oid| oid.to_hex());
+    let previous = match record.get_array("previousObjectIds") {
+        Ok(o) => o
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        Err(_e) => Vec::new(),
+    };
+    (id, previous)
+}
+
+async fn find_all(
+    col: &mongodb::Collection<Document>,
+    filter: Document,
+    projection: Document,
+) -> ApiResult<Vec<Document>> {
+    let find_options = FindOptions::builder().projection(projection).build();
+
+    let mut cursors = match col.find(filter, find_options).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading retention candidates : {}", e)
+            ));
+        }
+    };
+
+    let mut records = Vec::new();
+    while let Some(doc) = cursors.next().await {
+        match doc {
+            Ok(o) => records.push(o),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading retention candidates : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// A policy is held when any of its versions is under legal hold
+fn is_held(holds: &HashSet<String>, id: &Option<String>, previous: &[String]) -> bool {
+    id.as_ref().is_some_and(|i| holds.contains(i)) || previous.iter().any(|p| holds.contains(p))
+}
+
+/// Deleted records past the cutoff.
+/// Records deleted before the deletedDate field existed are dated by their ObjectId.
+fn deleted_filter(cutoff: DateTime<Utc>) -> Document {
+    doc! {
+        "$or": [
+            {"deletedDate": {"$lt": bson::DateTime::from_chrono(cutoff)}},
+            {"deletedDate": {"$exists": false}, "_id": {"$lt": oid_from_date(cutoff)}},
+        ]
+    }
+}
+
+/// Deleted policies past retention are purged with all their versions, unless held
+fn plan_deleted(plan: &mut PurgePlan, records: &[Document], holds: &HashSet<String>) {
+    for record in records {
+        let content = match record.get_document("content") {
+            Ok(o) => o,
+            Err(_e) => continue,
+        };
+        let (id, previous) = versions(content);
+        let id_str = match &id {
+            Some(i) => i.clone(),
+            None => continue,
+        };
+
+        if is_held(holds, &id, &previous) {
+            plan.held.push(id_str);
+        } else {
+            plan.deleted.push(id_str);
+            plan.history.extend(previous);
+        }
+    }
+}
+
+/// History versions beyond the `keep` most recent ones of each policy, unless held.
+/// Policies already purged as deleted are skipped.
+fn plan_history(
+    plan: &mut PurgePlan,
+    candidates: Vec<Document>,
+    keep: usize,
+    holds: &HashSet<String>,
+) {
+    let purged: HashSet<String> = plan.deleted.iter().cloned().collect();
+
+    for record in candidates {
+        let (id, previous) = versions(&record);
+        if id.as_ref().is_some_and(|i| purged.contains(i)) {
+            // already fully purged with the deleted policy
+            continue;
+        }
+
+        if is_held(holds, &id, &previous) {
+            if let Some(i) = id {
+                plan.held.push(i);
+            }
+        } else {
+            plan.history.extend(previous.into_iter().skip(keep));
+        }
+    }
+}
+
+/// List records past retention, without removing anything.
+/// `deleted_days` : deleted policies are purged, with all their versions, after this number of days.
+/// `history_versions` : number of history versions kept for each policy.
+/// A policy is skipped when any of its versions is under legal hold.
+pub async fn plan_purge(
+    db: &MongoRepo,
+    deleted_days: Option<i64>,
+    history_versions: Option<usize>,
+) -> ApiResult<PurgePlan> {
+    let holds = get_holds(db).await?;
+    let mut plan = PurgePlan::default();
+
+    if let Some(days) = deleted_days {
+        let cutoff = Utc::now() - chrono::Duration::days(days);
+        let projection = doc! {"content._id": 1, "content.previousObjectIds": 1};
+        let records = find_all(&db.deleted_col, deleted_filter(cutoff), projection).await?;
+        plan_deleted(&mut plan, &records, &holds);
+    }
+
+    if let Some(keep) = history_versions {
+        let path = format!("previousObjectIds.{}", keep);
+        let filter = doc! {path.as_str(): {"$exists": true}};
+        let projection = doc! {"previousObjectIds": 1};
+        let mut candidates = find_all(&db.policy_col, filter, projection).await?;
+
+        let path = format!("content.previousObjectIds.{}", keep);
+        let filter = doc! {path.as_str(): {"$exists": true}};
+        let projection = doc! {"content._id": 1, "content.previousObjectIds": 1};
+        for record in find_all(&db.deleted_col, filter, projection).await? {
+            if let Ok(content) = record.get_document("content") {
+                candidates.push(content.clone());
+            }
+        }
+
+        plan_history(&mut plan, candidates, keep, &holds);
+    }
+
+    plan.held.sort();
+    plan.held.dedup();
+
+    Ok(plan)
+}
+
+fn to_oids(ids: &[String]) -> Vec<ObjectId> {
+    ids.iter()
+        .filter_map(|id| ObjectId::parse_str(id).ok())
+        .collect()
+}
+
+/// Remove the records of a purge plan, audited for `actor`.
+/// Return the number of deleted and history records removed
+pub async fn purge(db: &MongoRepo, plan: &PurgePlan, actor: &Actor) -> ApiResult<(u64, u64)> {
+    if !plan.deleted.is_empty() {
+        // GridFS has no session, attachments first : a failure leaves the deleted policies to purge again
+        mongo_attachments::purge(db, &plan.deleted).await?;
+    }
+
+    transaction!(db, |session| purge_with_session(
+        db,
+        plan,
+        actor,
+        &mut session
+    ))
+}
+
+async fn purge_with_session(
+    db: &MongoRepo,
+    plan: &PurgePlan,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<(u64, u64)> {
+    let mut removed = (0, 0);
+
+    if !plan.deleted.is_empty() {
+        mongo_assignments::purge(db, session, &plan.deleted).await?;
+
+        let filter = doc! {"content._id": {"$in": to_oids(&plan.deleted)}};
+        match db
+            .deleted_col
+            .delete_many_with_session(filter, None, session)
+            .await
+        {
+            Ok(o) => removed.0 = o.deleted_count,
+            Err(e) => return Err(mongo::write_error(e, "Purge of deleted store failed")),
+        };
+    }
+
+    if !plan.history.is_empty() {
+        let filter = doc! {"_id": {"$in": to_oids(&plan.history)}};
+        match db
+            .history_col
+            .delete_many_with_session(filter, None, session)
+            .await
+        {
+            Ok(o) => removed.1 = o.deleted_count,
+            Err(e) => return Err(mongo::write_error(e, "Purge of history failed")),
+        };
+    }
+
+    mongo_audit::record_with_session(db, session, actor, "retention.purged", None, None).await?;
+    Ok(removed)
+}
+
+#[test]
+fn test_deleted_filter() {
+    let cutoff = Utc.with_ymd_and_hms(2024, 5, 2, 8, 0, 0).unwrap();
+    let filter = deleted_filter(cutoff);
+    let or = filter.get_array("$or").unwrap();
+
+    let dated = or[0].as_document().unwrap();
+    assert_eq!(
+        dated
+            .get_document("deletedDate")
+            .unwrap()
+            .get_datetime("$lt")
+            .unwrap()
+            .timestamp_millis(),
+        cutoff.timestamp_millis()
+    );
+
+    // undated records are compared on the creation time of their ObjectId
+    let undated = or[1].as_document().unwrap();
+    let bound = undated
+        .get_document("_id")
+        .unwrap()
+        .get_object_id("$lt")
+        .unwrap();
+    assert_eq!(
+        bound.timestamp().timestamp_millis(),
+        cutoff.timestamp_millis()
+    );
+    assert!(ObjectId::new() > bound);
+}
+
+#[test]
+fn test_plan_deleted() {
+    let (v1, v2, v3) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+    let (held, held_version) = (ObjectId::new(), ObjectId::new());
+    let deleted = |id: ObjectId, previous: Vec<String>| -> Document {
+        doc! {"content": {"_id": id, "previousObjectIds": previous}}
+    };
+    let records = vec![
+        deleted(v3, vec![v2.to_hex(), v1.to_hex()]),
+        deleted(held, vec![held_version.to_hex()]),
+        doc! {"content": {"source": "no id"}},
+    ];
+    let holds: HashSet<String> = [held_version.to_hex()].into_iter().collect();
+
+    let mut plan = PurgePlan::default();
+    plan_deleted(&mut plan, &records, &holds);
+
+    // an eligible policy is purged with all its versions, a held version keeps the whole policy
+    assert_eq!(plan.deleted, vec![v3.to_hex()]);
+    assert_eq!(plan.history, vec![v2.to_hex(), v1.to_hex()]);
+    assert_eq!(plan.held, vec![held.to_hex()]);
+}
+
+#[test]
+fn test_plan_history() {
+    let versions: Vec<String> = (0..4).map(|_| ObjectId::new().to_hex()).collect();
+    let current = ObjectId::new();
+    let purged = ObjectId::new();
+    let held = ObjectId::new();
+    let candidates = vec![
+        doc! {"_id": current, "previousObjectIds": versions.clone()},
+        doc! {"_id": purged, "previousObjectIds": [ObjectId::new().to_hex(), ObjectId::new().to_hex()]},
+        doc! {"_id": held, "previousObjectIds": [ObjectId::new().to_hex(), ObjectId::new().to_hex()]},
+    ];
+    let holds: HashSet<String> = [held.to_hex()].into_iter().collect();
+
+    let mut plan = PurgePlan {
+        deleted: vec![purged.to_hex()],
+        ..Default::default()
+    };
+    plan_history(&mut plan, candidates, 2, &holds);
+
+    // the two most recent versions are kept, the policies purged as deleted are not counted twice
+    assert_eq!(plan.history, versions[2..].to_vec());
+    assert_eq!(plan.held, vec![held.to_hex()]);
+}
@@ -0,0 +1,240 @@
+use chrono::prelude::*;
+use futures::stream::StreamExt;
+use mongodb::bson;
+use mongodb::{bson::doc, bson::oid::ObjectId, options::FindOptions, ClientSession};
+
+use crate::models::audit_model::Actor;
+use crate::models::revocation_model::{Revocation, RevocationRequest};
+use crate::mongo::mongo::{self, MongoRepo};
+use crate::mongo::mongo_audit;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use crate::transaction;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Revocations apply to the tokens of every tenant, only a caller bound to no tenant manages them
+pub fn global_caller(actor: &Actor) -> ApiResult<()> {
+    match &actor.tenant {
+        Some(tenant) => Err(local_error!(
+            LocalError::ForbiddenError,
+            format!(
+                "Caller bound to tenant {} cannot manage revocations.",
+                tenant
+            )
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Revocation of a request : a jti, or a subject with the date before which its tokens are rejected
+pub fn revocation(request: RevocationRequest, actor: &Actor) -> ApiResult<Revocation> {
+    global_caller(actor)?;
+    let now = Utc::now();
+    let jti = request.jti.filter(|j| !j.trim().is_empty());
+    let subject = request.subject.filter(|s| !s.trim().is_empty());
+
+    let revoked_before = match (&jti, &subject) {
+        (Some(_), Some(_)) | (None, None) => {
+            return Err(local_error!(
+                LocalError::ValidationError,
+                "Either jti or subject is required."
+            ))
+        }
+        (Some(_), None) => None,
+        (None, Some(_)) => match request.revoked_before {
+            Some(date) => match date.parse::<DateTime<Utc>>() {
+                Ok(o) => Some(o),
+                Err(_e) => {
+                    return Err(local_error!(
+                        LocalError::FilterDateParsing,
+                        "Date revokedBefore wrongly formatted."
+                    ))
+                }
+            },
+            None => Some(now),
+        },
+    };
+
+    Ok(Revocation {
+        id: None,
+        jti,
+        subject,
+        revoked_before: revoked_before.map(bson::DateTime::from_chrono),
+        reason: request.reason,
+        revoked_by: actor.subject.clone(),
+        revoked_date: bson::DateTime::from_chrono(now),
+    })
+}
+
+/// Record a revocation, effective on every instance at their next refresh
+pub async fn revoke(
+    db: &MongoRepo,
+    mut revocation: Revocation,
+    actor: &Actor,
+) -> ApiResult<Revocation> {
+    revocation.id = transaction!(db, |session| revoke_with_session(
+        db,
+        &revocation,
+        actor,
+        &mut session
+    ))?;
+    Ok(revocation)
+}
+
+async fn revoke_with_session(
+    db: &MongoRepo,
+    revocation: &Revocation,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<Option<ObjectId>> {
+    let result = match db
+        .revocation_col
+        .insert_one_with_session(revocation, None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Error recording revocation")),
+    };
+
+    let oid = result.inserted_id.as_object_id();
+    let id = oid.map(|o| o.to_hex());
+    mongo_audit::record_with_session(db, session, actor, "token.revoked", None, id.as_deref())
+        .await?;
+    Ok(oid)
+}
+
+/// Every revocation, the oldest first
+pub async fn list(db: &MongoRepo) -> ApiResult<Vec<Revocation>> {
+    let find_options = FindOptions::builder()
+        .sort(doc! {"revokedDate": 1, "_id": 1})
+        .build();
+
+    let mut cursors = match db.revocation_col.find(doc! {}, find_options).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while reading revocations : {}", e)
+            ));
+        }
+    };
+
+    let mut revocations = Vec::new();
+    while let Some(revocation) = cursors.next().await {
+        match revocation {
+            Ok(o) => revocations.push(o),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while reading revocations : {}", e)
+                ));
+            }
+        }
+    }
+    Ok(revocations)
+}
+
+/// Lift a revocation
+pub async fn delete(db: &MongoRepo, id: &str, actor: &Actor) -> ApiResult<()> {
+    global_caller(actor)?;
+    let oid = match ObjectId::parse_str(id) {
+        Ok(o) => o,
+        Err(_e) => {
+            return Err(local_error!(
+                LocalError::OidFormatError,
+                "ObjectId wrongly structure."
+            ))
+        }
+    };
+
+    transaction!(db, |session| delete_with_session(
+        db,
+        oid,
+        actor,
+        &mut session
+    ))
+}
+
+async fn delete_with_session(
+    db: &MongoRepo,
+    oid: ObjectId,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    let result = match db
+        .revocation_col
+        .delete_one_with_session(doc! {"_id": oid}, None, session)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Error deleting revocation")),
+    };
+
+    if result.deleted_count == 0 {
+        return Err(local_error!(
+            LocalError::DataNotFoundError,
+            "No revocation."
+        ));
+    }
+
+    let id = oid.to_hex();
+    mongo_audit::record_with_session(
+        db,
+        session,
+        actor,
+        "token.revocation.deleted",
+        Some(&id),
+        None,
+    )
+    .await
+}
+
+#[test]
+fn test_revocation_request() {
+    let mut actor = Actor {
+        subject: String::from("admin"),
+        scope: String::from("admin"),
+        ip: None,
+        route: String::from("POST /api/admin/revocations"),
+        tenant: None,
+    };
+    let request =
+        |value: serde_json::Value| -> RevocationRequest { serde_json::from_value(value).unwrap() };
+
+    let jti = revocation(request(serde_json::json!({"jti": "abc"})), &actor).unwrap();
+    assert_eq!(jti.jti.as_deref(), Some("abc"));
+    assert!(jti.revoked_before.is_none());
+
+    let subject = revocation(
+        request(serde_json::json!({"subject": "batch", "revokedBefore": "2024-05-02T08:00:00Z"})),
+        &actor,
+    )
+    .unwrap();
+    assert_eq!(
+        subject.revoked_before.unwrap().timestamp_millis(),
+        1714636800000
+    );
+
+    assert!(revocation(request(serde_json::json!({})), &actor).is_err());
+    assert!(revocation(
+        request(serde_json::json!({"jti": "abc", "subject": "batch"})),
+        &actor
+    )
+    .is_err());
+    assert!(revocation(
+        request(serde_json::json!({"subject": "batch", "revokedBefore": "yesterday"})),
+        &actor
+    )
+    .is_err());
+
+    // an admin of one tenant cannot revoke the tokens of every tenant
+    actor.tenant = Some(String::from("retail"));
+    let refused = revocation(request(serde_json::json!({"jti": "abc"})), &actor);
+    assert_eq!(
+        refused.unwrap_err().status(),
+        rocket::http::Status::Forbidden
+    );
+}
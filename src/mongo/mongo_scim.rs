@@ -0,0 +1,489 @@
+use chrono::prelude::*;
+use futures::stream::StreamExt;
+use mongodb::{bson::doc, bson::oid::ObjectId, bson::Bson, bson::Document, options::FindOptions};
+
+use mongodb::bson;
+
+use crate::models::scim_model::{ScimName, ScimOperation, ScimRole, ScimUser};
+use crate::models::user_model::User;
+use crate::mongo::mongo::MongoRepo;
+use crate::mongo::mongo_users::escape_regex;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Largest page of a SCIM list
+pub const MAX_COUNT: u64 = 1000;
+
+fn filter_error(desc: &str) -> ApiError {
+    local_error!(LocalError::FilterStringarsing, desc)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Open,
+    Close,
+}
+
+fn tokenize(filter: &str) -> ApiResult<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut text = String::new();
+                let mut closed = false;
+                while let Some(t) = chars.next() {
+                    match t {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                text.push(escaped);
+                            }
+                        }
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        _ => text.push(t),
+                    }
+                }
+                if !closed {
+                    return Err(filter_error("Unterminated string in filter."));
+                }
+                tokens.push(Token::Text(text));
+            }
+            _ => {
+                let mut word = String::from(c);
+                while let Some(&w) = chars.peek() {
+                    if w == ' ' || w == '(' || w == ')' || w == '"' {
+                        break;
+                    }
+                    word.push(w);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// User document field of a SCIM attribute, attribute names are case insensitive
+fn field(attribute: &str) -> ApiResult<&'static str> {
+    match attribute.to_ascii_lowercase().as_str() {
+        "id" => Ok("_id"),
+        "username" | "emails" | "emails.value" => Ok("email"),
+        "externalid" => Ok("idpSubject"),
+        "displayname" | "name.formatted" => Ok("name"),
+        "title" => Ok("title"),
+        "addresses.locality" => Ok("location"),
+        "active" => Ok("status"),
+        "roles" | "roles.value" => Ok("roles"),
+        "meta.created" => Ok("createdDate"),
+        "meta.lastmodified" => Ok("updatedDate"),
+        _ => Err(filter_error(&format!(
+            "Unknown attribute {} in filter.",
+            attribute
+        ))),
+    }
+}
+
+fn value(field: &str, token: &Token) -> ApiResult<Bson> {
+    let text = match token {
+        Token::Text(t) => t.clone(),
+        Token::Word(w) => w.clone(),
+        _ => return Err(filter_error("Missing value in filter.")),
+    };
+
+    match field {
+        "_id" => match ObjectId::parse_str(&text) {
+            Ok(o) => Ok(Bson::ObjectId(o)),
+            // an id that is not an Oid matches no user
+            Err(_e) => Ok(Bson::String(text)),
+        },
+        "status" => match text.as_str() {
+            "true" => Ok(Bson::String(String::from("active"))),
+            "false" => Ok(Bson::String(String::from("disabled"))),
+            _ => Err(filter_error("active is compared to true or false.")),
+        },
+        "createdDate" | "updatedDate" => match text.parse::<DateTime<Utc>>() {
+            Ok(o) => Ok(Bson::DateTime(bson::DateTime::from_chrono(o))),
+            Err(_e) => Err(filter_error("Date filter wrongly formatted.")),
+        },
+        "email" => Ok(Bson::String(text.to_lowercase())),
+        _ => Ok(Bson::String(text)),
+    }
+}
+
+fn comparison(field: &str, op: &str, value: Bson) -> ApiResult<Document> {
+    let text = match &value {
+        Bson::String(s) => Some(escape_regex(s)),
+        _ => None,
+    };
+    let regex = |pattern: String| doc! {field: {"$regex": pattern, "$options": "i"}};
+
+    match (op, text) {
+        // string comparisons ignore case, as SCIM attributes are not case exact
+        ("eq", Some(t)) if field != "status" => Ok(regex(format!("^{}$", t))),
+        ("ne", Some(t)) if field != "status" => {
+            Ok(doc! {field: {"$not": {"$regex": format!("^{}$", t), "$options": "i"}}})
+        }
+        ("eq", _) => Ok(doc! {field: value}),
+        ("ne", _) => Ok(doc! {field: {"$ne": value}}),
+        ("co", Some(t)) => Ok(regex(t)),
+        ("sw", Some(t)) => Ok(regex(format!("^{}", t))),
+        ("ew", Some(t)) => Ok(regex(format!("{}$", t))),
+        ("gt", _) => Ok(doc! {field: {"$gt": value}}),
+        ("ge", _) => Ok(doc! {field: {"$gte": value}}),
+        ("lt", _) => Ok(doc! {field: {"$lt": value}}),
+        ("le", _) => Ok(doc! {field: {"$lte": value}}),
+        _ => Err(filter_error(&format!(
+            "Operator {} not supported on {}.",
+            op, field
+        ))),
+    }
+}
+
+/// Recursive descent on : expression = term ("or" term)*, term = factor ("and" factor)*,
+/// factor = "(" expression ")" | "not" factor | attribute "pr" | attribute operator value
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word))
+    }
+
+    fn next(&mut self) -> ApiResult<Token> {
+        match self.tokens.get(self.position) {
+            Some(t) => {
+                self.position += 1;
+                Ok(t.clone())
+            }
+            None => Err(filter_error("Filter ends unexpectedly.")),
+        }
+    }
+
+    fn expression(&mut self) -> ApiResult<Document> {
+        let mut terms = vec![self.term()?];
+        while self.peek_word("or") {
+            self.position += 1;
+            terms.push(self.term()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            doc! {"$or": terms}
+        })
+    }
+
+    fn term(&mut self) -> ApiResult<Document> {
+        let mut factors = vec![self.factor()?];
+        while self.peek_word("and") {
+            self.position += 1;
+            factors.push(self.factor()?);
+        }
+        Ok(if factors.len() == 1 {
+            factors.remove(0)
+        } else {
+            doc! {"$and": factors}
+        })
+    }
+
+    fn factor(&mut self) -> ApiResult<Document> {
+        match self.next()? {
+            Token::Open => {
+                let inner = self.expression()?;
+                match self.next()? {
+                    Token::Close => Ok(inner),
+                    _ => Err(filter_error("Missing closing parenthesis in filter.")),
+                }
+            }
+            Token::Word(w) if w.eq_ignore_ascii_case("not") => {
+                let inner = self.factor()?;
+                Ok(doc! {"$nor": [inner]})
+            }
+            Token::Word(attribute) => {
+                let field = field(&attribute)?;
+                let op = match self.next()? {
+                    Token::Word(o) => o.to_ascii_lowercase(),
+                    _ => return Err(filter_error("Missing operator in filter.")),
+                };
+                if op == "pr" {
+                    return Ok(doc! {field: {"$exists": true, "$nin": [Bson::Null, "", []]}});
+                }
+                let token = self.next()?;
+                comparison(field, &op, value(field, &token)?)
+            }
+            _ => Err(filter_error("Unexpected token in filter.")),
+        }
+    }
+}
+
+/// Translate a SCIM filter (RFC 7644 3.4.2.2) on users into a Mongo filter
+pub fn parse_filter(filter: &str) -> ApiResult<Document> {
+    let mut parser = Parser {
+        tokens: tokenize(filter)?,
+        position: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Ok(Document::new());
+    }
+
+    let result = parser.expression()?;
+    if parser.position != parser.tokens.len() {
+        return Err(filter_error("Unexpected token at the end of the filter."));
+    }
+    Ok(result)
+}
+
+/// Return the number of users matching a filter and the page starting at `start_index` (1 based)
+pub async fn list_users(
+    db: &MongoRepo,
+    filter: Document,
+    start_index: u64,
+    count: u64,
+) -> ApiResult<(u64, Vec<User>)> {
+    let total = match db.user_col.count_documents(filter.clone(), None).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Error counting users : {}", e)
+            ));
+        }
+    };
+
+    if count == 0 {
+        return Ok((total, Vec::new()));
+    }
+
+    let find_options = FindOptions::builder()
+        .sort(doc! {"_id": 1})
+        .skip(start_index.max(1) - 1)
+        .limit(count.min(MAX_COUNT) as i64)
+        .build();
+
+    let mut cursors = match db.user_col.find(filter, find_options).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Error getting list of users : {}", e)
+            ));
+        }
+    };
+
+    let mut users = Vec::new();
+    while let Some(user) = cursors.next().await {
+        match user {
+            Ok(o) => users.push(o),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Error getting list of users : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok((total, users))
+}
+
+fn path_error(path: &str) -> ApiError {
+    local_error!(
+        LocalError::ContextError,
+        format!("Attribute {} cannot be patched.", path)
+    )
+}
+
+fn value_error(path: &str) -> ApiError {
+    local_error!(
+        LocalError::ValidationError,
+        format!("Wrong value for attribute {}.", path)
+    )
+}
+
+fn string_value(path: &str, value: &serde_json::Value) -> ApiResult<String> {
+    match value.as_str() {
+        Some(s) => Ok(s.to_string()),
+        None => Err(value_error(path)),
+    }
+}
+
+fn role_values(path: &str, value: &serde_json::Value) -> ApiResult<Vec<String>> {
+    let items = match value {
+        serde_json::Value::Array(a) => a.clone(),
+        other => vec![other.clone()],
+    };
+    let mut roles = Vec::new();
+    for item in items {
+        match item.get("value").and_then(|v| v.as_str()).or(item.as_str()) {
+            Some(r) => roles.push(r.to_string()),
+            None => return Err(value_error(path)),
+        }
+    }
+    Ok(roles)
+}
+
+/// Role named by a `roles[value eq "x"]` path
+fn role_selector(path: &str) -> Option<String> {
+    let inner = path.strip_prefix("roles[")?.strip_suffix(']')?;
+    let mut parts = inner.splitn(3, ' ');
+    let (attribute, op, value) = (parts.next()?, parts.next()?, parts.next()?);
+    if attribute.eq_ignore_ascii_case("value") && op.eq_ignore_ascii_case("eq") {
+        Some(value.trim_matches('"').to_string())
+    } else {
+        None
+    }
+}
+
+fn apply_value(
+    user: &mut ScimUser,
+    op: &str,
+    path: &str,
+    value: &serde_json::Value,
+) -> ApiResult<()> {
+    match path.to_ascii_lowercase().as_str() {
+        "active" => match value
+            .as_bool()
+            .or_else(|| value.as_str().map(|s| s == "true"))
+        {
+            Some(b) => user.active = b,
+            None => return Err(value_error(path)),
+        },
+        "username" => user.user_name = string_value(path, value)?,
+        "externalid" => user.external_id = Some(string_value(path, value)?),
+        "displayname" | "name.formatted" => {
+            let name = string_value(path, value)?;
+            user.display_name = Some(name.clone());
+            user.name = Some(ScimName {
+                formatted: Some(name),
+            });
+        }
+        "name" => match value.get("formatted").and_then(|f| f.as_str()) {
+            Some(f) => {
+                user.display_name = Some(f.to_string());
+                user.name = Some(ScimName {
+                    formatted: Some(f.to_string()),
+                });
+            }
+            None => return Err(value_error(path)),
+        },
+        "title" => user.title = Some(string_value(path, value)?),
+        "addresses.locality" => {
+            let locality = string_value(path, value)?;
+            for address in user.addresses.iter_mut() {
+                address.locality = Some(locality.clone());
+            }
+        }
+        "roles" => {
+            let roles = role_values(path, value)?;
+            if op == "replace" {
+                user.roles.clear();
+            }
+            for role in roles {
+                if !user.roles.iter().any(|r| r.value == role) {
+                    user.roles.push(ScimRole { value: role });
+                }
+            }
+        }
+        _ => return Err(path_error(path)),
+    }
+    Ok(())
+}
+
+/// Apply the operations of a SCIM PatchOp (RFC 7644 3.5.2) to the SCIM view of a user
+pub fn apply_operations(user: &mut ScimUser, operations: &[ScimOperation]) -> ApiResult<()> {
+    for operation in operations {
+        let op = operation.op.to_ascii_lowercase();
+        match (op.as_str(), &operation.path, &operation.value) {
+            ("add" | "replace", Some(path), Some(value)) => apply_value(user, &op, path, value)?,
+            // without path, the value holds the attributes to set
+            ("add" | "replace", None, Some(serde_json::Value::Object(attributes))) => {
+                for (path, value) in attributes {
+                    apply_value(user, &op, path, value)?;
+                }
+            }
+            ("remove", Some(path), _) => match path.to_ascii_lowercase().as_str() {
+                "roles" => user.roles.clear(),
+                "externalid" => user.external_id = None,
+                _ => match role_selector(path) {
+                    Some(role) => user.roles.retain(|r| r.value != role),
+                    None => return Err(path_error(path)),
+                },
+            },
+            _ => {
+                return Err(local_error!(
+                    LocalError::ValidationError,
+                    format!("Unsupported patch operation {}.", operation.op)
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_filter() {
+    assert_eq!(
+        parse_filter("userName eq \"Toto@Corp.com\"").unwrap(),
+        doc! {"email": {"$regex": "^toto@corp\\.com$", "$options": "i"}}
+    );
+    assert_eq!(
+        parse_filter("active eq false and (title sw \"arch\" or roles eq \"admin\")").unwrap(),
+        doc! {"$and": [
+            {"status": "disabled"},
+            {"$or": [
+                {"title": {"$regex": "^arch", "$options": "i"}},
+                {"roles": {"$regex": "^admin$", "$options": "i"}},
+            ]},
+        ]}
+    );
+    assert!(parse_filter("emails[type eq \"work\"]").is_err());
+    assert!(parse_filter("userName eq").is_err());
+    assert!(parse_filter("password eq \"x\"").is_err());
+}
+
+#[test]
+fn test_apply_operations() {
+    let mut user = ScimUser {
+        user_name: String::from("toto@corp.com"),
+        active: true,
+        roles: vec![ScimRole {
+            value: String::from("reader"),
+        }],
+        ..Default::default()
+    };
+
+    let operations: Vec<ScimOperation> = serde_json::from_value(serde_json::json!([
+        {"op": "replace", "value": {"active": false, "title": "architect"}},
+        {"op": "add", "path": "roles", "value": [{"value": "writer"}]},
+        {"op": "remove", "path": "roles[value eq \"reader\"]"},
+    ]))
+    .unwrap();
+    apply_operations(&mut user, &operations).unwrap();
+
+    assert!(!user.active);
+    assert_eq!(user.title, Some(String::from("architect")));
+    assert_eq!(user.roles.len(), 1);
+    assert_eq!(user.roles[0].value, "writer");
+
+    let operations: Vec<ScimOperation> = serde_json::from_value(
+        serde_json::json!([{"op": "replace", "path": "password", "value": "x"}]),
+    )
+    .unwrap();
+    assert!(apply_operations(&mut user, &operations).is_err());
+}
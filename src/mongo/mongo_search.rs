@@ -0,0 +1,215 @@
+use mongodb::{
+    bson::doc,
+    bson::Document,
+    options::{FindOptions, IndexOptions},
+    IndexModel,
+};
+
+use futures::stream::StreamExt;
+
+use crate::models::policy_model::SearchHit;
+use mongodb::bson;
+
+use crate::mongo::mongo::MongoRepo;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Name of the text index, a collection can only have one
+pub const TEXT_INDEX: &str = "policy_text";
+
+/// Policy content searched by full text, read from the configuration server file
+/// search_paths = ["policy.name", "policy.address", "policy.title"]
+/// search_language = "none"   # no stemming nor stop words, better suited to names
+#[derive(Clone, Debug)]
+pub struct SearchConfig {
+    pub paths: Vec<String>,
+    pub language: String,
+}
+
+impl SearchConfig {
+    pub fn from_settings(settings: &config::Config) -> SearchConfig {
+        SearchConfig {
+            paths: settings
+                .get::<Vec<String>>("search_paths")
+                .unwrap_or_else(|_| {
+                    vec![
+                        String::from("policy.name"),
+                        String::from("policy.location"),
+                        String::from("policy.title"),
+                    ]
+                }),
+            language: settings
+                .get::<String>("search_language")
+                .unwrap_or_else(|_| String::from("none")),
+        }
+    }
+
+    /// Text index over the configured content paths
+    pub fn index(&self) -> IndexModel {
+        let mut keys = Document::new();
+        for path in &self.paths {
+            keys.insert(path, "text");
+        }
+
+        IndexModel::builder()
+            .keys(keys)
+            .options(
+                IndexOptions::builder()
+                    .name(String::from(TEXT_INDEX))
+                    .default_language(self.language.clone())
+                    .build(),
+            )
+            .build()
+    }
+}
+
+/// Wrap the search terms found in a text with <em></em>, None when no term is found.
+/// Terms are matched ignoring ASCII case.
+pub fn highlight(text: &str, terms: &[String]) -> Option<String> {
+    let lower = text.to_ascii_lowercase();
+    let mut matches: Vec<(usize, usize)> = Vec::new();
+
+    for term in terms {
+        let term = term.to_ascii_lowercase();
+        if term.is_empty() {
+            continue;
+        }
+        for (start, _) in lower.match_indices(&term) {
+            matches.push((start, start + term.len()));
+        }
+    }
+
+    if matches.is_empty() {
+        return None;
+    }
+
+    // merge overlapping matches of different terms
+    matches.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in matches {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let mut result = String::new();
+    let mut position = 0;
+    for (start, end) in merged {
+        result.push_str(&text[position..start]);
+        result.push_str("<em>");
+        result.push_str(&text[start..end]);
+        result.push_str("</em>");
+        position = end;
+    }
+    result.push_str(&text[position..]);
+
+    Some(result)
+}
+
+/// Full text search over the configured content paths, most relevant first
+pub async fn search_any(
+    db: &MongoRepo,
+    config: &SearchConfig,
+    query: &str,
+    pagination: (i64, i64),
+) -> ApiResult<Vec<SearchHit>> {
+    if query.trim().is_empty() {
+        return Err(local_error!(
+            LocalError::FilterStringarsing,
+            "Search query is empty."
+        ));
+    }
+
+    let (page, limit) = pagination;
+    if page < 1 || limit < 1 {
+        return Err(local_error!(
+            LocalError::ParsingError,
+            "Pagination page and limit must be positive."
+        ));
+    }
+
+    let find_options = FindOptions::builder()
+        .projection(doc! {"score": {"$meta": "textScore"}})
+        .sort(doc! {"score": {"$meta": "textScore"}})
+        .skip(((page - 1) * limit) as u64)
+        .limit(limit)
+        .build();
+
+    let mut cursors = match db
+        .policy_col
+        .find(doc! {"$text": {"$search": query}}, find_options)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while searching policies : {}", e)
+            ));
+        }
+    };
+
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|t| t.trim_matches(|c| c == '"' || c == '-').to_string())
+        .collect();
+
+    let mut hits = Vec::new();
+    while let Some(doc) = cursors.next().await {
+        let mut record = match doc {
+            Ok(o) => o,
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while searching policies : {}", e)
+                ));
+            }
+        };
+
+        let score = record.get_f64("score").unwrap_or_default();
+        record.remove("score");
+
+        let content: serde_json::Value = match bson::from_bson(bson::Bson::Document(record)) {
+            Ok(o) => o,
+            Err(_e) => {
+                return Err(local_error!(
+                    LocalError::ParsingError,
+                    "Output parsing result exception."
+                ));
+            }
+        };
+
+        let mut highlights = serde_json::Map::new();
+        for path in &config.paths {
+            let pointer = format!("/{}", path.replace('.', "/"));
+            if let Some(text) = content.pointer(&pointer).and_then(|v| v.as_str()) {
+                if let Some(h) = highlight(text, &terms) {
+                    highlights.insert(path.clone(), serde_json::json!(h));
+                }
+            }
+        }
+
+        hits.push(SearchHit {
+            content,
+            score,
+            highlights,
+        });
+    }
+
+    Ok(hits)
+}
+
+#[test]
+fn test_highlight() {
+    let terms = vec![String::from("paris"), String::from("rue")];
+    assert_eq!(
+        highlight("12 Rue de Paris", &terms),
+        Some(String::from("12 <em>Rue</em> de <em>Paris</em>"))
+    );
+    assert_eq!(highlight("Lyon", &terms), None);
+}
@@ -0,0 +1,156 @@
+use chrono::prelude::*;
+use mongodb::{bson::doc, bson::Document};
+
+use futures::stream::StreamExt;
+
+use mongodb::bson;
+
+use crate::mongo::mongo::MongoRepo;
+
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+/// Policy dates usable for month grouping and date range filters
+const DATE_FIELDS: [&str; 4] = [
+    "requestDate",
+    "policyStartDate",
+    "policyEndDate",
+    "integrationDate",
+];
+
+/// Grouping dimensions and the policy content path they are read from
+const DIMENSIONS: [(&str, &str); 3] = [
+    ("policyholder", "policy.name"),
+    ("source", "source"),
+    ("status", "status"),
+];
+
+fn parse_date(value: &str, name: &str) -> ApiResult<bson::DateTime> {
+    match value.parse::<DateTime<Utc>>() {
+        Ok(o) => Ok(bson::DateTime::from_chrono(o)),
+        Err(_e) => Err(local_error!(
+            LocalError::FilterDateParsing,
+            format!("Date filter {} wrongly formatted.", name)
+        )),
+    }
+}
+
+/// Build the aggregation pipeline counting policies by dimension.
+/// `month` groups on the month of the `date` field, other dimensions on their content path.
+pub fn stats_pipeline(
+    dimension: &str,
+    date_field: &str,
+    from: Option<String>,
+    to: Option<String>,
+) -> ApiResult<Vec<Document>> {
+    if !DATE_FIELDS.contains(&date_field) {
+        return Err(local_error!(
+            LocalError::FilterStringarsing,
+            format!("Unknown date field {}.", date_field)
+        ));
+    }
+
+    let (group_key, sort) = if dimension == "month" {
+        (
+            bson::Bson::Document(
+                doc! {"$dateToString": {"format": "%Y-%m", "date": format!("${}", date_field)}},
+            ),
+            doc! {"_id": 1},
+        )
+    } else {
+        match DIMENSIONS.iter().find(|(name, _)| *name == dimension) {
+            Some((_, path)) => (
+                bson::Bson::String(format!("${}", path)),
+                doc! {"count": -1, "_id": 1},
+            ),
+            None => {
+                return Err(local_error!(
+                    LocalError::FilterStringarsing,
+                    format!("Unknown statistics dimension {}.", dimension)
+                ));
+            }
+        }
+    };
+
+    let mut range = Document::new();
+    if let Some(f) = from {
+        range.insert("$gte", parse_date(&f, "from")?);
+    }
+    if let Some(t) = to {
+        range.insert("$lt", parse_date(&t, "to")?);
+    }
+
+    let mut pipeline = Vec::new();
+    if !range.is_empty() {
+        pipeline.push(doc! {"$match": {date_field: range}});
+    }
+    pipeline.push(doc! {"$group": {"_id": group_key, "count": {"$sum": 1}}});
+    pipeline.push(doc! {"$sort": sort});
+    pipeline.push(doc! {"$project": {"_id": 0, "key": "$_id", "count": 1}});
+
+    Ok(pipeline)
+}
+
+/// Count policies grouped by month of a date, policyholder, source or status, within a date range
+pub async fn stats_any(
+    db: &MongoRepo,
+    dimension: &str,
+    date_field: &str,
+    from: Option<String>,
+    to: Option<String>,
+) -> ApiResult<Vec<serde_json::Value>> {
+    let pipeline = stats_pipeline(dimension, date_field, from, to)?;
+
+    let mut cursors = match db.policy_col.aggregate(pipeline, None).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Exception while aggregating policies : {}", e)
+            ));
+        }
+    };
+
+    let mut records = Vec::new();
+    while let Some(doc) = cursors.next().await {
+        match doc {
+            Ok(o) => records.push(bson::Bson::Document(o).into_relaxed_extjson()),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Exception while aggregating policies : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+#[test]
+fn test_stats_pipeline() {
+    let pipeline = stats_pipeline(
+        "month",
+        "policyStartDate",
+        Some(String::from("2023-01-01T00:00:00Z")),
+        None,
+    )
+    .unwrap();
+    assert_eq!(pipeline.len(), 4);
+    assert!(pipeline[0]
+        .get_document("$match")
+        .unwrap()
+        .contains_key("policyStartDate"));
+
+    let pipeline = stats_pipeline("source", "requestDate", None, None).unwrap();
+    assert_eq!(
+        pipeline[0],
+        doc! {"$group": {"_id": "$source", "count": {"$sum": 1}}}
+    );
+
+    assert!(stats_pipeline("colour", "requestDate", None, None).is_err());
+    assert!(stats_pipeline("month", "birthDate", None, None).is_err());
+}
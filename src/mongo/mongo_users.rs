@@ -1,65 +1,552 @@
-use crate::models::user_model::User;
+use crate::models::audit_model::Actor;
+use crate::models::user_model::{validate_role, User, UserPatch};
+use chrono::prelude::*;
 use futures::stream::StreamExt;
+use mongodb::bson;
 use mongodb::{
-    bson::doc, bson::oid::ObjectId, error::Error, results::DeleteResult, results::InsertOneResult,
+    bson::doc, bson::oid::ObjectId, bson::Document, options::FindOptions, results::InsertOneResult,
+    ClientSession,
 };
 
-use crate::mongo::mongo::MongoRepo;
+use crate::mongo::mongo::{self, MongoRepo};
+use crate::mongo::mongo_assignments;
+use crate::mongo::mongo_audit;
 
-/// Create User API, return Mongo Oid on success, Err(e) if exception.
-/// Json User data from : { "name" : "toto", "location":"paris","title":"architect" }
-pub async fn create_user(db: &MongoRepo, new_user: User) -> Result<InsertOneResult, Error> {
+use crate::error::{ApiError, LocalError};
+use crate::local_error;
+use crate::transaction;
+
+// Alias for ResultwT,ApiError>
+type ApiResult<T> = Result<T, ApiError>;
+
+fn parse_oid(id: &str) -> ApiResult<ObjectId> {
+    match ObjectId::parse_str(id) {
+        Ok(o) => Ok(o),
+        Err(_e) => Err(local_error!(
+            LocalError::OidFormatError,
+            "ObjectId wrongly structure."
+        )),
+    }
+}
+
+fn validate(result: Result<(), String>) -> ApiResult<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(local_error!(LocalError::ValidationError, e)),
+    }
+}
+
+/// Escape a filter value so that it is matched literally in a regex
+pub fn escape_regex(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Build the users filter : values match the start of the field, ignoring case
+pub fn users_filter(
+    name: Option<String>,
+    location: Option<String>,
+    title: Option<String>,
+) -> Document {
+    let mut filter = Document::new();
+    for (field, value) in [("name", name), ("location", location), ("title", title)] {
+        if let Some(v) = value {
+            filter.insert(
+                field,
+                doc! {"$regex": format!("^{}", escape_regex(&v)), "$options": "i"},
+            );
+        }
+    }
+    filter
+}
+
+fn write_error(e: mongodb::error::Error, desc: &str) -> ApiError {
+    if mongo::is_duplicate_key(&e) {
+        return local_error!(
+            LocalError::ConflictError,
+            "A user with the same email or identity provider subject already exists."
+        );
+    }
+    mongo::write_error(e, desc)
+}
+
+/// Roles are kept once, in the order given
+fn unique_roles(roles: Vec<String>) -> Vec<String> {
+    let mut unique: Vec<String> = Vec::new();
+    for role in roles {
+        if !unique.contains(&role) {
+            unique.push(role);
+        }
+    }
+    unique
+}
+
+/// Create User API, return Mongo Oid on success.
+/// Json User data from : { "name" : "toto", "location":"paris","title":"architect", "email":"toto@corp.com", "roles":["reader"] }
+pub async fn create_user(
+    db: &MongoRepo,
+    new_user: User,
+    actor: &Actor,
+) -> ApiResult<InsertOneResult> {
+    validate(new_user.validate())?;
+    insert_user(db, new_user, actor).await
+}
+
+/// Create a user provisioned by SCIM, only the email is required
+pub async fn create_provisioned_user(
+    db: &MongoRepo,
+    new_user: User,
+    actor: &Actor,
+) -> ApiResult<InsertOneResult> {
+    validate(new_user.validate_provisioned())?;
+    insert_user(db, new_user, actor).await
+}
+
+async fn insert_user(db: &MongoRepo, new_user: User, actor: &Actor) -> ApiResult<InsertOneResult> {
+    let now = bson::DateTime::from_chrono(Utc::now());
     let new_doc = User {
         id: None,
         name: new_user.name,
         location: new_user.location,
         title: new_user.title,
+        email: new_user.email.map(|e| e.to_lowercase()),
+        roles: unique_roles(new_user.roles),
+        status: new_user.status,
+        idp_subject: new_user.idp_subject,
+        created_date: Some(now),
+        updated_date: Some(now),
     };
 
-    let user = db
+    transaction!(db, |session| insert_user_with_session(
+        db,
+        &new_doc,
+        actor,
+        &mut session
+    ))
+}
+
+async fn insert_user_with_session(
+    db: &MongoRepo,
+    new_doc: &User,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<InsertOneResult> {
+    let user = match db
         .user_col
-        .insert_one(new_doc, None)
+        .insert_one_with_session(new_doc, None, session)
         .await
-        .ok()
-        .expect("Error creating user on atlas.");
+    {
+        Ok(o) => o,
+        Err(e) => return Err(write_error(e, "Error creating user")),
+    };
+
+    let id = user.inserted_id.as_object_id().map(|oid| oid.to_hex());
+    mongo_audit::record_with_session(db, session, actor, "user.created", None, id.as_deref())
+        .await?;
 
     Ok(user)
 }
 
-/// Return signle User from a get Oid
-pub async fn get_user(db: &MongoRepo, id: &String) -> Result<User, Error> {
-    let obj_id = ObjectId::parse_str(id).unwrap();
-    let filter = doc!("_id": obj_id);
-    let user_detail = db
+/// Return single User from a get Oid
+pub async fn get_user(db: &MongoRepo, id: &str) -> ApiResult<User> {
+    let filter = doc!("_id": parse_oid(id)?);
+
+    match db.user_col.find_one(filter, None).await {
+        Ok(Some(o)) => Ok(o),
+        Ok(None) => Err(local_error!(LocalError::DataNotFoundError, "No user.")),
+        Err(e) => Err(local_error!(
+            LocalError::ConnectionError,
+            format!("Error getting user's detail : {}", e)
+        )),
+    }
+}
+
+/// User linked to an identity : the user of the identity provider subject, else the user of the email
+pub async fn linked_user(
+    db: &MongoRepo,
+    subject: Option<&str>,
+    email: Option<&str>,
+) -> ApiResult<Option<User>> {
+    let mut filters = Vec::new();
+    if let Some(subject) = subject {
+        filters.push(doc! {"idpSubject": subject});
+    }
+    if let Some(email) = email {
+        filters.push(doc! {"email": email.to_lowercase()});
+    }
+
+    for filter in filters {
+        match db.user_col.find_one(filter, None).await {
+            Ok(Some(o)) => return Ok(Some(o)),
+            Ok(None) => {}
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Error getting user's detail : {}", e)
+                ))
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Return a page of users, ordered by name, filtered by name, location and title
+pub async fn get_all_users(
+    db: &MongoRepo,
+    filter: Document,
+    pagination: (i64, i64),
+) -> ApiResult<Vec<User>> {
+    let (page, limit) = pagination;
+    if page < 1 || limit < 1 {
+        return Err(local_error!(
+            LocalError::ParsingError,
+            "Pagination page and limit must be positive."
+        ));
+    }
+
+    let find_options = FindOptions::builder()
+        .sort(doc! {"name": 1, "_id": 1})
+        .skip(((page - 1) * limit) as u64)
+        .limit(limit)
+        .build();
+
+    let mut cursors = match db.user_col.find(filter, find_options).await {
+        Ok(o) => o,
+        Err(e) => {
+            return Err(local_error!(
+                LocalError::ConnectionError,
+                format!("Error getting list of users : {}", e)
+            ));
+        }
+    };
+
+    let mut users = Vec::new();
+    while let Some(user) = cursors.next().await {
+        match user {
+            Ok(o) => users.push(o),
+            Err(e) => {
+                return Err(local_error!(
+                    LocalError::ConnectionError,
+                    format!("Error getting list of users : {}", e)
+                ));
+            }
+        }
+    }
+
+    Ok(users)
+}
+
+/// $set of the fields given in a patch
+fn patch_document(patch: UserPatch) -> ApiResult<Document> {
+    let mut set = Document::new();
+    if let Some(name) = patch.name {
+        set.insert("name", name);
+    }
+    if let Some(location) = patch.location {
+        set.insert("location", location);
+    }
+    if let Some(title) = patch.title {
+        set.insert("title", title);
+    }
+    if let Some(email) = patch.email {
+        set.insert("email", email.to_lowercase());
+    }
+    if let Some(roles) = patch.roles {
+        set.insert("roles", unique_roles(roles));
+    }
+    if let Some(status) = patch.status {
+        match bson::to_bson(&status) {
+            Ok(o) => set.insert("status", o),
+            Err(_e) => {
+                return Err(local_error!(
+                    LocalError::ParsingError,
+                    "User status parsing failed."
+                ));
+            }
+        };
+    }
+    if let Some(subject) = patch.idp_subject {
+        set.insert("idpSubject", subject);
+    }
+    set.insert("updatedDate", bson::DateTime::from_chrono(Utc::now()));
+    Ok(set)
+}
+
+/// Replace a user, creation date excepted. Email and identity provider subject missing from the body are removed.
+pub async fn update_user(db: &MongoRepo, id: &str, user: User, actor: &Actor) -> ApiResult<User> {
+    validate(user.validate())?;
+    replace_user(db, id, user, actor).await
+}
+
+/// Replace a user provisioned by SCIM, only the email is required
+pub async fn update_provisioned_user(
+    db: &MongoRepo,
+    id: &str,
+    user: User,
+    actor: &Actor,
+) -> ApiResult<User> {
+    validate(user.validate_provisioned())?;
+    replace_user(db, id, user, actor).await
+}
+
+async fn replace_user(db: &MongoRepo, id: &str, user: User, actor: &Actor) -> ApiResult<User> {
+    let mut unset = Document::new();
+    if user.email.is_none() {
+        unset.insert("email", "");
+    }
+    if user.idp_subject.is_none() {
+        unset.insert("idpSubject", "");
+    }
+
+    let patch = UserPatch {
+        name: Some(user.name),
+        location: Some(user.location),
+        title: Some(user.title),
+        email: user.email,
+        roles: Some(user.roles),
+        status: Some(user.status),
+        idp_subject: user.idp_subject,
+    };
+
+    let mut update = doc! {"$set": patch_document(patch)?};
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    apply_update(db, id, update, "user.updated", actor).await
+}
+
+/// Update the fields given in the patch, other fields are kept
+pub async fn patch_user(
+    db: &MongoRepo,
+    id: &str,
+    patch: UserPatch,
+    actor: &Actor,
+) -> ApiResult<User> {
+    validate(patch.validate())?;
+
+    let update = doc! {"$set": patch_document(patch)?};
+    apply_update(db, id, update, "user.updated", actor).await
+}
+
+/// Grant a role to a user, granting a role already held changes nothing
+pub async fn add_role(db: &MongoRepo, id: &str, role: &str, actor: &Actor) -> ApiResult<User> {
+    validate(validate_role(role))?;
+
+    let update = doc! {
+        "$addToSet": {"roles": role},
+        "$set": {"updatedDate": bson::DateTime::from_chrono(Utc::now())},
+    };
+    apply_update(db, id, update, "user.role.granted", actor).await
+}
+
+/// Revoke a role of a user
+pub async fn remove_role(db: &MongoRepo, id: &str, role: &str, actor: &Actor) -> ApiResult<User> {
+    let update = doc! {
+        "$pull": {"roles": role},
+        "$set": {"updatedDate": bson::DateTime::from_chrono(Utc::now())},
+    };
+    apply_update(db, id, update, "user.role.revoked", actor).await
+}
+
+/// Update a user and record the audit entry in one transaction, then read the updated user
+async fn apply_update(
+    db: &MongoRepo,
+    id: &str,
+    update: Document,
+    action: &str,
+    actor: &Actor,
+) -> ApiResult<User> {
+    let oid = parse_oid(id)?;
+    transaction!(db, |session| update_with_session(
+        db,
+        oid,
+        &update,
+        action,
+        actor,
+        &mut session
+    ))?;
+
+    get_user(db, id).await
+}
+
+async fn update_with_session(
+    db: &MongoRepo,
+    oid: ObjectId,
+    update: &Document,
+    action: &str,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    let result = match db
         .user_col
-        .find_one(filter, None)
+        .update_one_with_session(doc!("_id": oid), update.clone(), None, session)
         .await
-        .ok()
-        .expect("Error getting user's detail");
-    Ok(user_detail.unwrap())
+    {
+        Ok(o) => o,
+        Err(e) => return Err(write_error(e, "Error updating user")),
+    };
+
+    if result.matched_count == 0 {
+        return Err(local_error!(LocalError::DataNotFoundError, "No user."));
+    }
+
+    let id = oid.to_hex();
+    mongo_audit::record_with_session(db, session, actor, action, Some(&id), Some(&id)).await
 }
 
-/// Return list of users without filtering/pagination
-pub async fn get_all_users(db: &MongoRepo) -> Result<Vec<User>, Error> {
-    let cursors = db
+/// Delete users based on an Oid, with their policy assignments, in one transaction
+pub async fn delete_user(db: &MongoRepo, id: &str, actor: &Actor) -> ApiResult<()> {
+    let oid = parse_oid(id)?;
+    transaction!(db, |session| delete_user_with_session(
+        db,
+        oid,
+        actor,
+        &mut session
+    ))
+}
+
+async fn delete_user_with_session(
+    db: &MongoRepo,
+    oid: ObjectId,
+    actor: &Actor,
+    session: &mut ClientSession,
+) -> ApiResult<()> {
+    let result = match db
         .user_col
-        .find(None, None)
+        .delete_one_with_session(doc!("_id": oid), None, session)
         .await
-        .ok()
-        .expect("Error getting list of users");
-    let users = cursors.map(|doc| doc.unwrap()).collect().await;
-    Ok(users)
+    {
+        Ok(o) => o,
+        Err(e) => return Err(mongo::write_error(e, "Error deleting user")),
+    };
+
+    if result.deleted_count == 0 {
+        return Err(local_error!(LocalError::DataNotFoundError, "No user."));
+    }
+
+    // a deleted user keeps no assignment on policies
+    let id = oid.to_hex();
+    mongo_assignments::remove_user(db, session, &id).await?;
+
+    mongo_audit::record_with_session(db, session, actor, "user.deleted", Some(&id), None).await
 }
 
-/// Delete users based on an Oid
-pub async fn delete_user(db: &MongoRepo, id: &String) -> Result<DeleteResult, Error> {
-    let obj_id = ObjectId::parse_str(id).unwrap();
-    let filter = doc!("_id": obj_id);
-    let user_detail = db
+/// Bring users created before roles, status and timestamps to the current model :
+/// active, without role, dated from the migration. Run at startup, migrated users are left unchanged.
+pub async fn migrate_users(db: &MongoRepo) -> ApiResult<u64> {
+    let now = bson::DateTime::from_chrono(Utc::now());
+    let filter = doc! {"status": {"$exists": false}};
+    let update = doc! {
+        "$set": {"status": "active", "createdDate": now, "updatedDate": now},
+        "$addToSet": {"roles": {"$each": []}},
+    };
+
+    match db
         .user_col
-        .delete_one(filter, None)
+        .clone_with_type::<Document>()
+        .update_many(filter, update, None)
         .await
-        .ok()
-        .expect("Error deleting user");
-    Ok(user_detail)
+    {
+        Ok(o) => Ok(o.modified_count),
+        Err(e) => Err(local_error!(
+            LocalError::ConnectionError,
+            format!("Users migration failed : {}", e)
+        )),
+    }
+}
+
+#[test]
+fn test_users_filter() {
+    let filter = users_filter(
+        Some(String::from("jean.")),
+        None,
+        Some(String::from("Arch")),
+    );
+
+    assert_eq!(
+        filter,
+        doc! {
+            "name": {"$regex": "^jean\\.", "$options": "i"},
+            "title": {"$regex": "^Arch", "$options": "i"},
+        }
+    );
+    assert!(users_filter(None, None, None).is_empty());
+}
+
+#[test]
+fn test_user_validation() {
+    use crate::models::user_model::UserStatus;
+
+    let user: User = serde_json::from_value(serde_json::json!({
+        "name": "toto",
+        "location": "paris",
+        "title": "architect",
+    }))
+    .unwrap();
+    assert!(user.validate().is_ok());
+    assert_eq!(user.status, UserStatus::Active);
+    assert!(user.roles.is_empty());
+
+    let user = User {
+        title: String::from("  "),
+        ..user
+    };
+    assert_eq!(user.validate(), Err(String::from("User title is empty.")));
+
+    let user = User {
+        title: String::from("architect"),
+        email: Some(String::from("toto@corp")),
+        ..user
+    };
+    assert!(user.validate().is_err());
+
+    let user = User {
+        email: Some(String::from("toto@corp.com")),
+        roles: vec![String::from("policy:write"), String::from("bad role")],
+        ..user
+    };
+    assert_eq!(
+        user.validate(),
+        Err(String::from("User role bad role is not valid."))
+    );
+
+    assert!(UserPatch::default().validate().is_err());
+    let patch = UserPatch {
+        location: Some(String::from("lyon\n")),
+        ..Default::default()
+    };
+    assert!(patch.validate().is_err());
+
+    // a provisioned user only requires its email
+    let user: User = serde_json::from_value(serde_json::json!({
+        "name": "",
+        "location": "",
+        "title": "",
+        "email": "toto@corp.com",
+    }))
+    .unwrap();
+    assert!(user.validate().is_err());
+    assert!(user.validate_provisioned().is_ok());
+
+    let user = User {
+        title: String::from("architect\n"),
+        ..user
+    };
+    assert!(user.validate_provisioned().is_err());
+
+    let user = User {
+        title: String::new(),
+        email: None,
+        ..user
+    };
+    assert_eq!(
+        user.validate_provisioned(),
+        Err(String::from("User email is empty."))
+    );
 }
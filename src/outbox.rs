@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use mongodb::bson::{Bson, Document};
+use rocket::tokio;
+use rocket::tokio::io::AsyncWriteExt;
+
+use crate::mongo::mongo::MongoRepo;
+use crate::mongo::mongo_outbox;
+
+/// Destination of the domain events drained from the outbox
+#[rocket::async_trait]
+pub trait OutboxSink: Send + Sync {
+    fn name(&self) -> String;
+    async fn publish(&self, event: &serde_json::Value) -> Result<(), String>;
+}
+
+/// Print events on the standard output
+pub struct StdoutSink;
+
+#[rocket::async_trait]
+impl OutboxSink for StdoutSink {
+    fn name(&self) -> String {
+        String::from("stdout")
+    }
+
+    async fn publish(&self, event: &serde_json::Value) -> Result<(), String> {
+        println!("{}", event);
+        Ok(())
+    }
+}
+
+/// Append events to a local NDJSON file, one event per line
+pub struct FileSink {
+    pub path: String,
+}
+
+#[rocket::async_trait]
+impl OutboxSink for FileSink {
+    fn name(&self) -> String {
+        format!("file {}", self.path)
+    }
+
+    async fn publish(&self, event: &serde_json::Value) -> Result<(), String> {
+        let mut file = match tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+        {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Open {} failed : {}", self.path, e)),
+        };
+
+        let line = format!("{}\n", event);
+        match file.write_all(line.as_bytes()).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Write in {} failed : {}", self.path, e)),
+        }
+    }
+}
+
+/// POST events as JSON to an HTTP endpoint, any non 2xx status is a failed delivery
+pub struct HttpSink {
+    pub url: String,
+    pub client: reqwest::Client,
+}
+
+#[rocket::async_trait]
+impl OutboxSink for HttpSink {
+    fn name(&self) -> String {
+        format!("http {}", self.url)
+    }
+
+    async fn publish(&self, event: &serde_json::Value) -> Result<(), String> {
+        match self.client.post(&self.url).json(event).send().await {
+            Ok(response) => match response.error_for_status() {
+                Ok(_o) => Ok(()),
+                Err(e) => Err(format!("POST {} failed : {}", self.url, e)),
+            },
+            Err(e) => Err(format!("POST {} failed : {}", self.url, e)),
+        }
+    }
+}
+
+/// Outbox relay settings, read from the configuration server file
+/// outbox_sinks = ["stdout", "file", "http"]
+/// outbox_file = "events.ndjson"
+/// outbox_http_url = "http://localhost:8080/events"
+/// outbox_poll_seconds = 5
+/// outbox_batch_size = 100
+pub struct OutboxConfig {
+    pub sinks: Vec<Box<dyn OutboxSink>>,
+    pub poll: Duration,
+    pub batch_size: i64,
+}
+
+impl OutboxConfig {
+    pub fn from_settings(settings: &config::Config) -> Result<OutboxConfig, String> {
+        let names = settings
+            .get::<Vec<String>>("outbox_sinks")
+            .unwrap_or_default();
+
+        let mut sinks: Vec<Box<dyn OutboxSink>> = Vec::new();
+        for name in names {
+            match name.as_str() {
+                "stdout" => sinks.push(Box::new(StdoutSink)),
+                "file" => match settings.get::<String>("outbox_file") {
+                    Ok(path) => sinks.push(Box::new(FileSink { path })),
+                    Err(e) => return Err(format!("outbox_file missing for file sink : {}", e)),
+                },
+                "http" => match settings.get::<String>("outbox_http_url") {
+                    Ok(url) => sinks.push(Box::new(HttpSink {
+                        url,
+                        client: reqwest::Client::new(),
+                    })),
+                    Err(e) => return Err(format!("outbox_http_url missing for http sink : {}", e)),
+                },
+                other => return Err(format!("Unknown outbox sink {}", other)),
+            }
+        }
+
+        // an empty batch would be taken for a full one and the relay would never wait
+        let batch_size = settings.get::<i64>("outbox_batch_size").unwrap_or(100);
+        if batch_size <= 0 {
+            return Err(format!(
+                "outbox_batch_size must be positive, {} given",
+                batch_size
+            ));
+        }
+
+        Ok(OutboxConfig {
+            sinks,
+            poll: Duration::from_secs(settings.get::<u64>("outbox_poll_seconds").unwrap_or(5)),
+            batch_size,
+        })
+    }
+}
+
+/// Convert an outbox record to the published event. `eventId` lets consumers drop duplicates.
+fn to_event(record: &Document) -> serde_json::Value {
+    let mut event = record.clone();
+    if let Ok(id) = record.get_object_id("_id") {
+        event.insert("eventId", id.to_hex());
+    }
+    for field in ["_id", "delivered", "attempts", "lastError"] {
+        event.remove(field);
+    }
+    Bson::Document(event).into_relaxed_extjson()
+}
+
+/// Publish pending events to every sink, in outbox order.
+/// An event is flagged as delivered only once all sinks accepted it, so a failure stops the batch
+/// and the event is sent again on the next run (at-least-once delivery).
+pub async fn drain(db: &MongoRepo, config: &OutboxConfig) -> Result<usize, String> {
+    let events = match mongo_outbox::get_pending(db, config.batch_size).await {
+        Ok(o) => o,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut delivered = 0;
+    for record in events {
+        let id = match record.get_object_id("_id") {
+            Ok(o) => o,
+            Err(_e) => return Err(String::from("Outbox record without ObjectId.")),
+        };
+        let event = to_event(&record);
+
+        for sink in &config.sinks {
+            if let Err(e) = sink.publish(&event).await {
+                let error = format!("Delivery to {} failed : {}", sink.name(), e);
+                if let Err(ee) = mongo_outbox::mark_failed(db, id, &error).await {
+                    eprintln!("{}", ee);
+                }
+                return Err(error);
+            }
+        }
+
+        if let Err(e) = mongo_outbox::mark_delivered(db, id).await {
+            return Err(e.to_string());
+        }
+        delivered += 1;
+    }
+
+    Ok(delivered)
+}
+
+/// Start the background task draining the outbox into the configured sinks
+pub fn start_relay(db: MongoRepo, config: OutboxConfig) {
+    if config.sinks.is_empty() {
+        println!("No outbox sink configured, domain events are kept in the outbox.");
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            match drain(&db, &config).await {
+                // more events may be waiting, do not wait for the next poll
+                Ok(n) if n as i64 == config.batch_size => continue,
+                Ok(_n) => {}
+                Err(e) => eprintln!("Outbox relay error : {}", e),
+            }
+            tokio::time::sleep(config.poll).await;
+        }
+    });
+}
+
+#[test]
+fn test_outbox_config_sinks() {
+    let settings = config::Config::builder()
+        .add_source(config::File::from_str(
+            "outbox_sinks = [\"stdout\", \"file\"]\noutbox_file = \"events.ndjson\"",
+            config::FileFormat::Toml,
+        ))
+        .build()
+        .unwrap();
+    let outbox = OutboxConfig::from_settings(&settings).unwrap();
+    assert_eq!(outbox.sinks.len(), 2);
+    assert_eq!(outbox.sinks[1].name(), "file events.ndjson");
+
+    let settings = config::Config::builder()
+        .add_source(config::File::from_str(
+            "outbox_sinks = [\"http\"]",
+            config::FileFormat::Toml,
+        ))
+        .build()
+        .unwrap();
+    assert!(OutboxConfig::from_settings(&settings).is_err());
+}
+
+#[test]
+fn test_outbox_config_batch_size() {
+    for batch_size in ["0", "-5"] {
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                &format!("outbox_batch_size = {}", batch_size),
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        assert!(OutboxConfig::from_settings(&settings).is_err());
+    }
+
+    let settings = config::Config::builder().build().unwrap();
+    assert_eq!(
+        OutboxConfig::from_settings(&settings).unwrap().batch_size,
+        100
+    );
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use rocket::tokio;
+
+use crate::models::audit_model::Actor;
+use crate::mongo::mongo::MongoRepo;
+use crate::mongo::mongo_retention;
+
+/// Retention rules, read from the configuration server file
+/// retention_deleted_days = 2557      # purge deleted policies after 7 years
+/// retention_history_versions = 10    # keep the last 10 history versions of a policy
+/// retention_purge_hours = 24         # run the purge job every day, no job when missing
+/// A rule is disabled when missing, its value has to be at least 1 otherwise.
+#[derive(Clone)]
+pub struct RetentionConfig {
+    pub deleted_days: Option<i64>,
+    pub history_versions: Option<usize>,
+    pub purge_interval: Option<Duration>,
+}
+
+impl RetentionConfig {
+    pub fn from_settings(settings: &config::Config) -> Result<RetentionConfig, String> {
+        Ok(RetentionConfig {
+            // a cutoff in the future would purge every deleted policy
+            deleted_days: positive(settings, "retention_deleted_days")?,
+            // no version kept would drop the whole history
+            history_versions: positive(settings, "retention_history_versions")?.map(|v| v as usize),
+            purge_interval: settings
+                .get::<u64>("retention_purge_hours")
+                .ok()
+                .map(|h| Duration::from_secs(h * 3600)),
+        })
+    }
+}
+
+/// Optional setting, an invalid value fails instead of disabling its rule
+fn positive(settings: &config::Config, name: &str) -> Result<Option<i64>, String> {
+    match settings.get::<i64>(name) {
+        Ok(v) if v >= 1 => Ok(Some(v)),
+        Ok(v) => Err(format!("{} must be at least 1, {} given", name, v)),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(format!("{} : {}", name, e)),
+    }
+}
+
+/// Caller recorded in the audit trail of the scheduled purge
+fn job_actor() -> Actor {
+    Actor {
+        subject: String::from("retention-job"),
+        scope: String::new(),
+        ip: None,
+        route: String::from("retention_purge_hours"),
+        tenant: None,
+    }
+}
+
+/// Plan then run a retention purge, audited for `actor`.
+/// Return the number of deleted and history records removed
+pub async fn run_purge(
+    db: &MongoRepo,
+    config: &RetentionConfig,
+    actor: &Actor,
+) -> Result<(u64, u64), String> {
+    let plan =
+        match mongo_retention::plan_purge(db, config.deleted_days, config.history_versions).await {
+            Ok(o) => o,
+            Err(e) => return Err(e.to_string()),
+        };
+
+    match mongo_retention::purge(db, &plan, actor).await {
+        Ok(o) => Ok(o),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Start the scheduled purge job when a purge interval is configured
+pub fn start_purge_job(db: MongoRepo, config: &RetentionConfig) {
+    let interval = match config.purge_interval {
+        Some(o) => o,
+        None => {
+            println!("No retention_purge_hours configured, retention purge job not started.");
+            return;
+        }
+    };
+
+    let config = config.clone();
+
+    tokio::spawn(async move {
+        let actor = job_actor();
+        loop {
+            match run_purge(&db, &config, &actor).await {
+                Ok((deleted, history)) => println!(
+                    "Retention purge removed {} deleted policies and {} history versions.",
+                    deleted, history
+                ),
+                Err(e) => eprintln!("Retention purge error : {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[test]
+fn test_retention_config() {
+    let settings = config::Config::builder()
+        .add_source(config::File::from_str(
+            "retention_deleted_days = 2557\nretention_purge_hours = 24",
+            config::FileFormat::Toml,
+        ))
+        .build()
+        .unwrap();
+    let retention = RetentionConfig::from_settings(&settings).unwrap();
+    assert_eq!(retention.deleted_days, Some(2557));
+    assert_eq!(retention.history_versions, None);
+    assert_eq!(retention.purge_interval, Some(Duration::from_secs(86400)));
+
+    for rule in [
+        "retention_deleted_days = 0",
+        "retention_deleted_days = -30",
+        "retention_history_versions = 0",
+        "retention_history_versions = -1",
+    ] {
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(rule, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        assert!(
+            RetentionConfig::from_settings(&settings).is_err(),
+            "{}",
+            rule
+        );
+    }
+}
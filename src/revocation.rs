@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use rocket::tokio::sync::Mutex;
+
+use crate::jwt_secure::Claims;
+use crate::models::revocation_model::Revocation;
+use crate::mongo::mongo::MongoRepo;
+use crate::mongo::mongo_revocations;
+
+/// Revoked jtis, and for each revoked subject the time (seconds) its tokens must be issued after
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    pub jtis: HashSet<String>,
+    pub subjects: HashMap<String, i64>,
+}
+
+impl RevocationList {
+    pub fn add(&mut self, revocation: &Revocation) {
+        if let Some(jti) = &revocation.jti {
+            self.jtis.insert(jti.clone());
+        }
+        if let (Some(subject), Some(before)) = (&revocation.subject, revocation.revoked_before) {
+            let before = before.timestamp_millis() / 1000;
+            let entry = self.subjects.entry(subject.clone()).or_insert(before);
+            *entry = (*entry).max(before);
+        }
+    }
+
+    /// A token without iat cannot prove it was issued after the subject revocation
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        if claims.jti.as_ref().is_some_and(|j| self.jtis.contains(j)) {
+            return true;
+        }
+        match claims.sub.as_ref().and_then(|s| self.subjects.get(s)) {
+            Some(before) => claims.iat.is_none_or(|iat| iat < *before),
+            None => false,
+        }
+    }
+}
+
+struct CachedList {
+    list: RevocationList,
+    loaded: Option<Instant>,
+    /// revocations made on this instance since the last reload started, kept by the reloaded list
+    added: Vec<Revocation>,
+}
+
+/// Revocations kept in memory, read again from Mongo every refresh interval.
+/// Read from the configuration server file :
+/// revocation_refresh_seconds = 30   # delay for a revocation made on another instance to be enforced
+pub struct RevocationCache {
+    refresh: Duration,
+    cached: Mutex<CachedList>,
+    /// held by the one request reloading the list, the others use the current list meanwhile
+    reload: Mutex<()>,
+}
+
+impl RevocationCache {
+    pub fn new(refresh: Duration) -> RevocationCache {
+        RevocationCache {
+            refresh,
+            cached: Mutex::new(CachedList {
+                list: RevocationList::default(),
+                loaded: None,
+                added: Vec::new(),
+            }),
+            reload: Mutex::new(()),
+        }
+    }
+
+    pub fn from_settings(settings: &config::Config) -> RevocationCache {
+        RevocationCache::new(Duration::from_secs(
+            settings
+                .get::<u64>("revocation_refresh_seconds")
+                .unwrap_or(30),
+        ))
+    }
+
+    /// Enforce a revocation made on this instance at once
+    pub async fn add(&self, revocation: &Revocation) {
+        let mut cached = self.cached.lock().await;
+        cached.list.add(revocation);
+        cached.added.push(revocation.clone());
+    }
+
+    /// Forget the cached list, a lifted revocation is only removed by a reload
+    pub async fn invalidate(&self) {
+        self.cached.lock().await.loaded = None;
+    }
+
+    fn is_stale(&self, cached: &CachedList) -> bool {
+        cached.loaded.is_none_or(|l| l.elapsed() >= self.refresh)
+    }
+
+    /// Whether the claims are revoked. On a Mongo failure the last loaded list is used.
+    /// The list is read from Mongo without holding the cache, then swapped.
+    pub async fn is_revoked(&self, db: &MongoRepo, claims: &Claims) -> bool {
+        {
+            let cached = self.cached.lock().await;
+            if !self.is_stale(&cached) {
+                return cached.list.is_revoked(claims);
+            }
+        }
+
+        if let Ok(_reloading) = self.reload.try_lock() {
+            self.cached.lock().await.added.clear();
+            let loaded = mongo_revocations::list(db).await;
+
+            let mut cached = self.cached.lock().await;
+            match loaded {
+                Ok(revocations) => {
+                    let mut list = RevocationList::default();
+                    for revocation in revocations.iter().chain(cached.added.iter()) {
+                        list.add(revocation);
+                    }
+                    cached.list = list;
+                    cached.loaded = Some(Instant::now());
+                }
+                Err(e) => eprintln!("Revocation list not refreshed : {}", e),
+            }
+            cached.added.clear();
+        }
+
+        self.cached.lock().await.list.is_revoked(claims)
+    }
+}
+
+#[test]
+fn test_revocation_list() {
+    use chrono::prelude::*;
+    use mongodb::bson;
+
+    let revocation = |jti: Option<&str>, subject: Option<&str>, before: Option<i64>| Revocation {
+        id: None,
+        jti: jti.map(String::from),
+        subject: subject.map(String::from),
+        revoked_before: before.map(bson::DateTime::from_millis),
+        reason: None,
+        revoked_by: String::from("admin"),
+        revoked_date: bson::DateTime::from_chrono(Utc::now()),
+    };
+    let claims = |jti: Option<&str>, sub: &str, iat: Option<i64>| -> Claims {
+        serde_json::from_value(serde_json::json!({"jti": jti, "sub": sub, "iat": iat})).unwrap()
+    };
+
+    let mut list = RevocationList::default();
+    list.add(&revocation(Some("leaked"), None, None));
+    list.add(&revocation(None, Some("batch"), Some(1714636800000)));
+
+    assert!(list.is_revoked(&claims(Some("leaked"), "other", Some(1714640400))));
+    assert!(!list.is_revoked(&claims(Some("fine"), "other", Some(1714640400))));
+    assert!(list.is_revoked(&claims(None, "batch", Some(1714636000))));
+    assert!(!list.is_revoked(&claims(None, "batch", Some(1714640400))));
+    assert!(list.is_revoked(&claims(None, "batch", None)));
+}
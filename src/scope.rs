@@ -0,0 +1,157 @@
+use std::marker::PhantomData;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::jwt_secure::{NetworkResponse, Response, ResponseBody, JWT};
+
+/// Scope a route requires, checked against the space separated scopes of the token or API key
+pub trait Scope: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+macro_rules! scope {
+    ($marker:ident, $name:expr) => {
+        pub struct $marker;
+
+        impl Scope for $marker {
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+/// Operations each scope authorizes, the route to scope table returned to the caller by /api/me
+pub const OPERATIONS: &[(&str, &[&str])] = &[
+    (
+        "policies:read",
+        &[
+            "policies.read",
+            "policies.search",
+            "policies.stats",
+            "policies.count",
+            "policies.changes",
+            "attachments.read",
+            "assignments.read",
+        ],
+    ),
+    (
+        "policies:write",
+        &[
+            "policies.create",
+            "policies.update",
+            "policies.upsert",
+            "policies.delete",
+            "attachments.write",
+            "assignments.write",
+        ],
+    ),
+    (
+        "users:read",
+        &[
+            "users.read",
+            "users.roles.read",
+            "users.policies.read",
+            "scim.read",
+        ],
+    ),
+    (
+        "users:write",
+        &[
+            "users.create",
+            "users.update",
+            "users.delete",
+            "users.roles.write",
+            "scim.write",
+        ],
+    ),
+    (
+        "admin",
+        &[
+            "audit.read",
+            "holds.write",
+            "retention.read",
+            "retention.purge",
+            "indexes.read",
+            "apikeys.write",
+            "revocations.read",
+            "revocations.write",
+        ],
+    ),
+    ("tenants:all", &["tenants.any"]),
+];
+
+scope!(PoliciesRead, "policies:read");
+scope!(PoliciesWrite, "policies:write");
+scope!(UsersRead, "users:read");
+scope!(UsersWrite, "users:write");
+scope!(Admin, "admin");
+scope!(CrossTenant, "tenants:all");
+
+/// Guard of a route : 401 without a valid token, 403 when the token lacks the scope
+pub struct RequireScope<S: Scope> {
+    scope: PhantomData<S>,
+}
+
+pub fn has_scope(scopes: &str, required: &str) -> bool {
+    scopes.split_whitespace().any(|s| s == required)
+}
+
+/// Operations authorized by space separated scopes, in the order of the scope table
+pub fn operations(scopes: &str) -> Vec<&'static str> {
+    OPERATIONS
+        .iter()
+        .filter(|(scope, _)| has_scope(scopes, scope))
+        .flat_map(|(_, operations)| operations.iter().copied())
+        .collect()
+}
+
+#[rocket::async_trait]
+impl<'r, S: Scope> FromRequest<'r> for RequireScope<S> {
+    type Error = NetworkResponse;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, NetworkResponse> {
+        let jwt = rocket::outcome::try_outcome!(req.guard::<JWT>().await);
+
+        if has_scope(&jwt.claims.scope, S::NAME) {
+            return Outcome::Success(RequireScope { scope: PhantomData });
+        }
+
+        let message = format!("Scope {} required", S::NAME);
+        println!("Error authorizing JWT token - {}", message);
+        let response = Response {
+            body: ResponseBody::Message(message),
+        };
+        Outcome::Failure((
+            Status::Forbidden,
+            NetworkResponse::Forbidden(serde_json::to_string(&response).unwrap_or_default()),
+        ))
+    }
+}
+
+#[test]
+fn test_has_scope() {
+    assert!(has_scope("policies:read users:read", UsersRead::NAME));
+    assert!(!has_scope("policies:read", PoliciesWrite::NAME));
+    assert!(!has_scope("policies:readers", PoliciesRead::NAME));
+    assert!(!has_scope("", Admin::NAME));
+}
+
+#[test]
+fn test_operations() {
+    let operations = operations("users:read admin:none");
+    assert!(operations.contains(&"users.read"));
+    assert!(!operations.contains(&"users.create"));
+    assert!(!operations.contains(&"audit.read"));
+
+    // every scope marker has its operations
+    for name in [
+        PoliciesRead::NAME,
+        PoliciesWrite::NAME,
+        UsersRead::NAME,
+        UsersWrite::NAME,
+        Admin::NAME,
+        CrossTenant::NAME,
+    ] {
+        assert!(OPERATIONS.iter().any(|(scope, _)| *scope == name));
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::jwt_secure::{self, Claims};
+use crate::mongo::mongo::MongoRepo;
+use crate::scope::{has_scope, CrossTenant, Scope};
+
+/// Tenants sharing the deployment, read from the configuration server file
+/// tenants = ["retail", "corporate"]   # single tenant (middleoffice database) when missing
+/// tenant_isolation = "database"       # middleoffice_<tenant> database, or "prefix" : <tenant>_<collection>
+/// tenant_sources = ["claim", "header", "host"]
+/// tenant_header = "X-Tenant"
+#[derive(Clone, Debug)]
+pub struct TenantConfig {
+    pub tenants: Vec<String>,
+    pub isolation: String,
+    pub sources: Vec<String>,
+    pub header: String,
+}
+
+impl TenantConfig {
+    pub fn from_settings(settings: &config::Config) -> Result<TenantConfig, String> {
+        let config = TenantConfig {
+            tenants: settings.get::<Vec<String>>("tenants").unwrap_or_default(),
+            isolation: settings
+                .get::<String>("tenant_isolation")
+                .unwrap_or_else(|_| String::from("database")),
+            sources: settings
+                .get::<Vec<String>>("tenant_sources")
+                .unwrap_or_else(|_| {
+                    vec![
+                        String::from("claim"),
+                        String::from("header"),
+                        String::from("host"),
+                    ]
+                }),
+            header: settings
+                .get::<String>("tenant_header")
+                .unwrap_or_else(|_| String::from("X-Tenant")),
+        };
+
+        if config.isolation != "database" && config.isolation != "prefix" {
+            return Err(format!("Unknown tenant isolation {}.", config.isolation));
+        }
+        for source in &config.sources {
+            if !["claim", "header", "host"].contains(&source.as_str()) {
+                return Err(format!("Unknown tenant source {}.", source));
+            }
+        }
+        // tenant names end up in database and collection names
+        for tenant in &config.tenants {
+            if tenant.is_empty()
+                || !tenant
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!("Invalid tenant name {}.", tenant));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Repos of every tenant, built on the shared client at startup
+pub struct TenantRepos {
+    pub config: TenantConfig,
+    pub default: MongoRepo,
+    pub repos: HashMap<String, MongoRepo>,
+}
+
+impl TenantRepos {
+    pub fn new(base: MongoRepo, config: TenantConfig) -> TenantRepos {
+        let mut repos = HashMap::new();
+        for tenant in &config.tenants {
+            let repo = if config.isolation == "prefix" {
+                MongoRepo::new(base.repo.clone(), &base.database, &format!("{}_", tenant))
+            } else {
+                MongoRepo::new(
+                    base.repo.clone(),
+                    &format!("{}_{}", base.database, tenant),
+                    "",
+                )
+            };
+            repos.insert(tenant.clone(), repo);
+        }
+
+        TenantRepos {
+            config,
+            default: base,
+            repos,
+        }
+    }
+
+    pub fn is_multi_tenant(&self) -> bool {
+        !self.config.tenants.is_empty()
+    }
+
+    /// Every repo background jobs (outbox relay, retention purge, indexes) have to run on
+    pub fn all(&self) -> Vec<MongoRepo> {
+        if self.is_multi_tenant() {
+            self.repos.values().cloned().collect()
+        } else {
+            vec![self.default.clone()]
+        }
+    }
+}
+
+/// Tenant found in the JWT claim, the tenant header and the first label of the host
+#[derive(Debug, Default)]
+pub struct TenantHints {
+    pub claim: Option<String>,
+    pub header: Option<String>,
+    pub host: Option<String>,
+}
+
+/// Tenant claim of an authenticated caller. When tenants are read from the claim, a caller without
+/// tenant claim is forbidden unless it holds the cross-tenant scope : isolation is not left to the caller.
+pub fn claim_hint(config: &TenantConfig, claims: &Claims) -> Result<Option<String>, Status> {
+    if !config.sources.iter().any(|s| s == "claim") {
+        return Ok(None);
+    }
+    match &claims.tenant {
+        Some(t) => Ok(Some(t.clone())),
+        None if has_scope(&claims.scope, CrossTenant::NAME) => Ok(None),
+        None => Err(Status::Forbidden),
+    }
+}
+
+/// Resolve the tenant of a request. The JWT claim cannot be overridden :
+/// a header or host naming another tenant is forbidden, so a token never reads another tenant.
+pub fn resolve(config: &TenantConfig, hints: &TenantHints) -> Result<String, Status> {
+    let mut resolved: Option<&String> = None;
+
+    for source in &config.sources {
+        let hint = match source.as_str() {
+            "claim" => hints.claim.as_ref(),
+            "header" => hints.header.as_ref(),
+            _ => hints.host.as_ref().filter(|h| config.tenants.contains(h)),
+        };
+        match (resolved, hint) {
+            (None, Some(h)) => resolved = Some(h),
+            (Some(r), Some(h)) if r != h => return Err(Status::Forbidden),
+            _ => {}
+        }
+    }
+
+    match resolved {
+        Some(t) if config.tenants.contains(t) => Ok(t.clone()),
+        Some(_t) => Err(Status::NotFound),
+        None => Err(Status::BadRequest),
+    }
+}
+
+/// The repo of the request tenant. Routes take `db: &MongoRepo` so that every policy and user query
+/// is isolated in the tenant database or collections.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r MongoRepo {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, String> {
+        let tenants = match req.rocket().state::<TenantRepos>() {
+            Some(o) => o,
+            None => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    String::from("Tenant repos not managed."),
+                ))
+            }
+        };
+
+        if !tenants.is_multi_tenant() {
+            return Outcome::Success(&tenants.default);
+        }
+
+        let mut hints = TenantHints {
+            header: req
+                .headers()
+                .get_one(&tenants.config.header)
+                .map(|h| h.to_string()),
+            host: req
+                .host()
+                .and_then(|h| h.domain().as_str().split('.').next().map(|d| d.to_string())),
+            ..Default::default()
+        };
+        if req.headers().contains("authorization") || req.headers().contains("x-api-key") {
+            if let Ok(claims) = jwt_secure::request_claims(req).await {
+                match claim_hint(&tenants.config, claims) {
+                    Ok(claim) => hints.claim = claim,
+                    Err(status) => {
+                        return Outcome::Failure((
+                            status,
+                            String::from("Caller bound to no tenant."),
+                        ))
+                    }
+                }
+            }
+        }
+
+        match resolve(&tenants.config, &hints) {
+            Ok(tenant) => Outcome::Success(&tenants.repos[&tenant]),
+            Err(status) => Outcome::Failure((status, String::from("Tenant resolution failed."))),
+        }
+    }
+}
+
+#[test]
+fn test_resolve_tenant() {
+    let config = TenantConfig {
+        tenants: vec![String::from("retail"), String::from("corporate")],
+        isolation: String::from("database"),
+        sources: vec![
+            String::from("claim"),
+            String::from("header"),
+            String::from("host"),
+        ],
+        header: String::from("X-Tenant"),
+    };
+
+    let hints = TenantHints {
+        header: Some(String::from("retail")),
+        host: Some(String::from("api")),
+        ..Default::default()
+    };
+    assert_eq!(resolve(&config, &hints), Ok(String::from("retail")));
+
+    // a token of a tenant cannot read another tenant through the header
+    let hints = TenantHints {
+        claim: Some(String::from("corporate")),
+        header: Some(String::from("retail")),
+        ..Default::default()
+    };
+    assert_eq!(resolve(&config, &hints), Err(Status::Forbidden));
+
+    let hints = TenantHints {
+        header: Some(String::from("wholesale")),
+        ..Default::default()
+    };
+    assert_eq!(resolve(&config, &hints), Err(Status::NotFound));
+    assert_eq!(
+        resolve(&config, &TenantHints::default()),
+        Err(Status::BadRequest)
+    );
+}
+
+#[test]
+fn test_tenant_repos_isolation() {
+    let options = mongodb::options::ClientOptions::builder()
+        .hosts(vec![mongodb::options::ServerAddress::Tcp {
+            host: String::from("localhost"),
+            port: None,
+        }])
+        .build();
+    let client = mongodb::Client::with_options(options).unwrap();
+    let base = MongoRepo::new(client, "middleoffice", "");
+
+    let mut config = TenantConfig {
+        tenants: vec![String::from("retail"), String::from("corporate")],
+        isolation: String::from("database"),
+        sources: vec![String::from("header")],
+        header: String::from("X-Tenant"),
+    };
+    let tenants = TenantRepos::new(base.clone(), config.clone());
+    assert_eq!(
+        tenants.repos["retail"].policy_col.namespace().to_string(),
+        "middleoffice_retail.policies"
+    );
+    assert_eq!(
+        tenants.repos["corporate"].user_col.namespace().to_string(),
+        "middleoffice_corporate.Users"
+    );
+
+    config.isolation = String::from("prefix");
+    let tenants = TenantRepos::new(base, config);
+    assert_eq!(
+        tenants.repos["retail"].policy_col.namespace().to_string(),
+        "middleoffice.retail_policies"
+    );
+    assert_ne!(
+        tenants.repos["retail"].history_col.namespace(),
+        tenants.repos["corporate"].history_col.namespace()
+    );
+}
+
+#[test]
+fn test_claim_hint() {
+    let mut config = TenantConfig {
+        tenants: vec![String::from("retail"), String::from("corporate")],
+        isolation: String::from("database"),
+        sources: vec![String::from("claim"), String::from("header")],
+        header: String::from("X-Tenant"),
+    };
+    let claims = |scope: &str, tenant: Option<&str>| -> Claims {
+        serde_json::from_value(serde_json::json!({"scope": scope, "tenant": tenant})).unwrap()
+    };
+
+    assert_eq!(
+        claim_hint(&config, &claims("policies:read", Some("retail"))),
+        Ok(Some(String::from("retail")))
+    );
+    assert_eq!(
+        claim_hint(&config, &claims("policies:read", None)),
+        Err(Status::Forbidden)
+    );
+    assert_eq!(
+        claim_hint(&config, &claims("admin tenants:all", None)),
+        Ok(None)
+    );
+
+    // tenants chosen by header or host only, the claims do not bind the caller
+    config.sources = vec![String::from("header")];
+    assert_eq!(
+        claim_hint(&config, &claims("policies:read", None)),
+        Ok(None)
+    );
+}
@@ -2,19 +2,33 @@
 //#![allow(unused_variables)]
 
 #[cfg(test)]
-use crate::{delete_any, get_all_any, get_any, update_any};
+use crate::{delete_any, get_all_any, get_any, get_changes, get_me, get_user, update_any};
 use rocket::serde::Deserialize;
 
 use crate::rocket;
+use mongodb::bson::doc;
+use rocket::http::{Header, Status};
 use rocket::local::asynchronous::Client;
 use rocket::serde::json::json;
 
 use crate::config;
+use crate::jwks::KeySource;
+use crate::models::audit_model::Actor;
 use crate::mongo;
+use crate::mongo::{mongo_any, mongo_assignments, mongo_attachments, mongo_retention, mongo_users};
+use crate::tenant::{TenantConfig, TenantRepos};
+use crate::token::test_service;
 use std::process;
 
 #[launch]
 async fn rocket() -> _ {
+    tenant_rocket(Vec::new()).await
+}
+
+/// Test rocket isolating the given tenants in their own database, tenant read from the token claim
+/// and the X-Tenant header.
+/// Single tenant when no tenant is given.
+async fn tenant_rocket(tenants: Vec<String>) -> rocket::Rocket<rocket::Build> {
     let settings = config::init_configuration(String::from("")).await.unwrap();
     let uri = match settings.get::<String>("db_uri_atlas") {
         Ok(o) => o,
@@ -29,10 +43,46 @@ async fn rocket() -> _ {
         }
     };
 
+    let tenants = TenantRepos::new(
+        repo,
+        TenantConfig {
+            tenants,
+            isolation: String::from("database"),
+            sources: vec![String::from("claim"), String::from("header")],
+            header: String::from("X-Tenant"),
+        },
+    );
+
     //rocket::build().manage(repo).mount("/", routes![get_any])
     rocket::build()
-        .manage(repo)
-        .mount("/", routes![get_any, get_all_any, update_any, delete_any])
+        .manage(tenants)
+        .manage(KeySource::from_pem(include_bytes!("token_test_pk.pem")).unwrap())
+        .mount(
+            "/",
+            routes![
+                get_any,
+                get_all_any,
+                update_any,
+                delete_any,
+                get_changes,
+                get_user,
+                get_me
+            ],
+        )
+}
+
+/// Authorization header of a token carrying the given scopes, signed with the test key.
+/// The test rocket verifies the tokens with the public key file, without identity provider.
+fn bearer(scope: &str) -> Header<'static> {
+    tenant_bearer("tests", scope)
+}
+
+/// Authorization header of a token of a tenant client (retail, corporate), tests has no tenant
+fn tenant_bearer(client: &str, scope: &str) -> Header<'static> {
+    let token = test_service()
+        .issue("client_credentials", client, client, Some(scope))
+        .unwrap();
+    Header::new("Authorization", format!("Bearer {}", token.access_token))
 }
 
 /// All exception shoulbd be prefix by a lower case expression as detailled object.
@@ -46,7 +96,10 @@ struct Except {
 async fn get_api_oid_error() {
     let client = Client::tracked(rocket().await);
     let binding = client.await.unwrap();
-    let response = binding.get("/api/any/1234").dispatch();
+    let response = binding
+        .get("/api/any/1234")
+        .header(bearer("policies:read"))
+        .dispatch();
 
     assert_eq!(
         response
@@ -63,7 +116,10 @@ async fn get_api_oid_error() {
 async fn get_api_oid_not_found() {
     let client = Client::tracked(rocket().await);
     let binding = client.await.unwrap();
-    let response = binding.get("/api/any/655c7c5b037c912bb7ce3973").dispatch();
+    let response = binding
+        .get("/api/any/655c7c5b037c912bb7ce3973")
+        .header(bearer("policies:read"))
+        .dispatch();
 
     assert_eq!(
         response
@@ -82,6 +138,7 @@ async fn get_api_delete_not_found() {
     let binding = client.await.unwrap();
     let response = binding
         .delete("/api/any/654a03b71dd74c6443810c2f")
+        .header(bearer("policies:write"))
         .dispatch();
 
     assert_eq!(
@@ -104,6 +161,7 @@ async fn get_api_put_not_found() {
     });
     let response = binding
         .put("/api/any/655c7c41037c412bb7ce6971")
+        .header(bearer("policies:write"))
         .body(body.to_string())
         .dispatch();
 
@@ -118,6 +176,53 @@ async fn get_api_put_not_found() {
     );
 }
 
+/// Mutations require an authenticated caller for the audit trail
+#[async_test]
+async fn get_api_delete_unauthorized() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+    let response = binding
+        .delete("/api/any/654a03b71dd74c6443810c2f")
+        .dispatch();
+
+    assert_eq!(response.await.status(), Status::Unauthorized);
+}
+
+#[async_test]
+async fn get_api_put_unauthorized() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+    let body = json!({
+        "source": "Dummy",
+    });
+    let response = binding
+        .put("/api/any/655c7c41037c412bb7ce6971")
+        .body(body.to_string())
+        .dispatch();
+
+    assert_eq!(response.await.status(), Status::Unauthorized);
+}
+
+#[async_test]
+async fn get_api_changes_invalid_token() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+    let response = binding
+        .get("/api/any/changes?since=abc")
+        .header(bearer("policies:read"))
+        .dispatch();
+
+    assert_eq!(
+        response
+            .await
+            .into_json::<Except>()
+            .await
+            .unwrap()
+            .exception,
+        "Parsing exception : Invalid sync token."
+    );
+}
+
 #[async_test]
 async fn test_ping_db_validate_configuration() {
     let settings = config::init_configuration(String::from("")).await.unwrap();
@@ -155,3 +260,322 @@ async fn test_get_any_path() {
 
     assert_eq!(mongo::mongo::ping_db(&db).await.unwrap(), ());
 }
+
+#[async_test]
+async fn get_api_tenant_missing() {
+    let client = Client::tracked(
+        tenant_rocket(vec![String::from("retail"), String::from("corporate")]).await,
+    );
+    let binding = client.await.unwrap();
+    let response = binding.get("/api/any/655c7c5b037c912bb7ce3973").dispatch();
+
+    assert_eq!(response.await.status(), Status::BadRequest);
+}
+
+/// A policy of a tenant is never returned to another tenant
+#[async_test]
+async fn get_api_no_cross_tenant_read() {
+    let rocket = tenant_rocket(vec![String::from("retail"), String::from("corporate")]).await;
+    let retail = rocket.state::<TenantRepos>().unwrap().repos["retail"].clone();
+    let inserted = retail
+        .policy_col
+        .insert_one(doc! {"source": "Dummy"}, None)
+        .await
+        .unwrap();
+    let id = inserted.inserted_id.as_object_id().unwrap().to_hex();
+
+    let client = Client::tracked(rocket).await.unwrap();
+
+    let response = client
+        .get(format!("/api/any/{}", id))
+        .header(tenant_bearer("corporate", "policies:read"))
+        .dispatch();
+    assert_eq!(
+        response
+            .await
+            .into_json::<Except>()
+            .await
+            .unwrap()
+            .exception,
+        "Data not found : No result."
+    );
+
+    let response = client
+        .get(format!("/api/any/{}", id))
+        .header(tenant_bearer("retail", "policies:read"))
+        .dispatch();
+    assert_eq!(response.await.status(), Status::Ok);
+
+    // the tenant of the token cannot be overridden by the header
+    let response = client
+        .get(format!("/api/any/{}", id))
+        .header(Header::new("X-Tenant", "retail"))
+        .header(tenant_bearer("corporate", "policies:read"))
+        .dispatch();
+    assert_eq!(response.await.status(), Status::Forbidden);
+
+    // a caller of no tenant only chooses a tenant with the cross-tenant scope
+    let response = client
+        .get(format!("/api/any/{}", id))
+        .header(Header::new("X-Tenant", "retail"))
+        .header(bearer("policies:read"))
+        .dispatch();
+    assert_eq!(response.await.status(), Status::Forbidden);
+
+    let response = client
+        .get(format!("/api/any/{}", id))
+        .header(Header::new("X-Tenant", "retail"))
+        .header(bearer("policies:read tenants:all"))
+        .dispatch();
+    assert_eq!(response.await.status(), Status::Ok);
+
+    retail
+        .policy_col
+        .delete_one(doc! {"_id": inserted.inserted_id}, None)
+        .await
+        .unwrap();
+}
+
+#[async_test]
+async fn get_api_user_oid_error() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+    let response = binding
+        .get("/api/user/1234")
+        .header(bearer("users:read"))
+        .dispatch();
+
+    assert_eq!(response.await.status(), Status::BadRequest);
+}
+
+#[async_test]
+async fn get_api_user_not_found() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+    let response = binding
+        .get("/api/user/655c7c5b037c912bb7ce3973")
+        .header(bearer("users:read"))
+        .dispatch();
+
+    assert_eq!(response.await.status(), Status::NotFound);
+}
+
+/// A valid token without the scope of the route is forbidden, no token is unauthorized
+#[async_test]
+async fn get_api_missing_scope() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+
+    let response = binding
+        .get("/api/any/655c7c5b037c912bb7ce3973")
+        .header(bearer("users:read"))
+        .dispatch();
+    assert_eq!(response.await.status(), Status::Forbidden);
+
+    let response = binding.get("/api/any/655c7c5b037c912bb7ce3973").dispatch();
+    assert_eq!(response.await.status(), Status::Unauthorized);
+}
+
+/// An unknown API key is unauthorized, like an invalid token
+#[async_test]
+async fn get_api_invalid_api_key() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+    let response = binding
+        .get("/api/any/655c7c5b037c912bb7ce3973")
+        .header(Header::new("X-API-Key", "mo_unknown"))
+        .dispatch();
+
+    assert_eq!(response.await.status(), Status::Unauthorized);
+}
+
+/// The caller identity needs a valid token, whatever its scopes
+#[async_test]
+async fn get_api_me_unauthorized() {
+    let client = Client::tracked(rocket().await);
+    let binding = client.await.unwrap();
+    let response = binding.get("/api/me").dispatch();
+
+    assert_eq!(response.await.status(), Status::Unauthorized);
+}
+
+/// A new version of a policy carries its attachments and assignments over, a deletion archives them
+#[async_test]
+async fn policy_version_carries_attachments_and_assignments() {
+    let rocket = rocket().await;
+    let db = rocket.state::<TenantRepos>().unwrap().default.clone();
+    let actor = Actor {
+        subject: String::from("tests"),
+        scope: String::from("policies:write users:write"),
+        ip: None,
+        route: String::from("tests"),
+        tenant: None,
+    };
+    let policy = json!({
+        "context": {
+            "requestDate": "2024-05-02T08:00:00Z",
+            "policyStartDate": "2024-06-01T00:00:00Z",
+            "policyEndDate": "2025-05-31T00:00:00Z",
+        },
+        "policy": {"name": "carry over"},
+    });
+
+    let v1 = mongo_any::create_any(&db, policy.clone(), &actor)
+        .await
+        .unwrap();
+    let v1_id = v1.id.unwrap().to_hex();
+    let attachment =
+        mongo_attachments::add_attachment(&db, &v1_id, "terms.txt", "text/plain", b"terms", &actor)
+            .await
+            .unwrap();
+    let user = serde_json::from_value(json!({
+        "name": "carry",
+        "location": "paris",
+        "title": "underwriter",
+        "email": format!("{}@tests.corp.com", v1_id),
+    }))
+    .unwrap();
+    let user_id = mongo_users::create_user(&db, user, &actor)
+        .await
+        .unwrap()
+        .inserted_id
+        .as_object_id()
+        .unwrap()
+        .to_hex();
+    let request =
+        serde_json::from_value(json!({"userId": &user_id, "role": "underwriter"})).unwrap();
+    mongo_assignments::assign(&db, &v1_id, request, &actor)
+        .await
+        .unwrap();
+
+    // the new version takes the attachments and assignments of the previous one
+    let v2 = mongo_any::update_any(&db, policy, v1_id.clone(), &actor)
+        .await
+        .unwrap();
+    let v2_id = v2.id.unwrap().to_hex();
+
+    let attachments = mongo_attachments::list_attachments(&db, &v2_id)
+        .await
+        .unwrap();
+    assert_eq!(attachments.len(), 1);
+    assert_eq!(attachments[0].id, attachment.id);
+    assert!(!attachments[0].archived);
+    assert!(mongo_attachments::list_attachments(&db, &v1_id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let assignments = mongo_assignments::policy_assignments(&db, &v2_id)
+        .await
+        .unwrap();
+    assert_eq!(assignments.len(), 1);
+    assert_eq!(assignments[0].user_id, user_id);
+    assert_eq!(assignments[0].previous_object_ids, vec![v1_id.clone()]);
+
+    // deleting the policy archives them
+    mongo_any::delete_any(&db, &v2_id, &actor).await.unwrap();
+
+    let attachments = mongo_attachments::list_attachments(&db, &v2_id)
+        .await
+        .unwrap();
+    assert!(attachments.iter().all(|a| a.archived));
+    let assignments = mongo_assignments::policy_assignments(&db, &v2_id)
+        .await
+        .unwrap();
+    assert!(assignments.iter().all(|a| a.archived));
+    assert!(mongo_assignments::user_assignments(&db, &user_id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    mongo_attachments::purge(&db, std::slice::from_ref(&v2_id))
+        .await
+        .unwrap();
+    let mut session = mongo::mongo::start_transaction(&db).await.unwrap();
+    let purged = mongo_assignments::purge(&db, &mut session, std::slice::from_ref(&v2_id)).await;
+    mongo::mongo::end_transaction(&mut session, purged)
+        .await
+        .unwrap();
+    mongo_users::delete_user(&db, &user_id, &actor)
+        .await
+        .unwrap();
+    db.deleted_col
+        .delete_many(doc! {"content._id": v2.id.unwrap()}, None)
+        .await
+        .unwrap();
+}
+
+/// The purge of a deleted policy past retention removes its archived attachments
+#[async_test]
+async fn retention_purge_includes_attachments() {
+    let rocket = rocket().await;
+    let db = rocket.state::<TenantRepos>().unwrap().default.clone();
+    let actor = Actor {
+        subject: String::from("tests"),
+        scope: String::from("policies:write admin"),
+        ip: None,
+        route: String::from("tests"),
+        tenant: None,
+    };
+    let policy = json!({
+        "context": {
+            "requestDate": "2024-05-02T08:00:00Z",
+            "policyStartDate": "2024-06-01T00:00:00Z",
+            "policyEndDate": "2025-05-31T00:00:00Z",
+        },
+        "policy": {"name": "retention"},
+    });
+
+    let created = mongo_any::create_any(&db, policy, &actor).await.unwrap();
+    let oid = created.id.unwrap();
+    let id = oid.to_hex();
+    mongo_attachments::add_attachment(&db, &id, "terms.txt", "text/plain", b"terms", &actor)
+        .await
+        .unwrap();
+    mongo_any::delete_any(&db, &id, &actor).await.unwrap();
+
+    // deleted long before the retention period
+    db.deleted_col
+        .update_one(
+            doc! {"content._id": oid},
+            doc! {"$set": {"deletedDate": mongodb::bson::DateTime::from_millis(0)}},
+            None,
+        )
+        .await
+        .unwrap();
+
+    let plan = mongo_retention::plan_purge(&db, Some(30), None)
+        .await
+        .unwrap();
+    assert!(plan.deleted.contains(&id));
+    assert_eq!(
+        mongo_attachments::list_attachments(&db, &id)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    mongo_retention::purge(&db, &plan, &actor).await.unwrap();
+    assert!(mongo_attachments::list_attachments(&db, &id)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[async_test]
+async fn api_routes_do_not_collide() {
+    // the state of the real service is not managed here, only the route collisions are checked
+    match rocket::build()
+        .mount("/", crate::api_routes())
+        .ignite()
+        .await
+    {
+        Ok(_o) => {}
+        Err(e) => assert!(
+            !matches!(e.kind(), rocket::error::ErrorKind::Collisions(_)),
+            "{}",
+            e
+        ),
+    }
+}
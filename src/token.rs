@@ -0,0 +1,463 @@
+use std::fs;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chrono::prelude::*;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::signature::{KeyPair, RsaKeyPair};
+use rocket::http::RawStr;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::jwt_secure::Claims;
+
+/// Client allowed to get tokens from the built-in token service
+/// [[token_clients]]
+/// client_id = "batch"
+/// secret_sha256 = "<hex sha256 of the client secret>"
+/// scope = "policies:read policies:write"
+/// tenant = "retail"
+#[derive(Clone, Debug, Deserialize)]
+pub struct TokenClient {
+    pub client_id: String,
+    pub secret_sha256: String,
+    #[serde(default)]
+    pub scope: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+/// Built-in token service, read from the configuration server file. Disabled when token_key_file is missing.
+/// token_key_file = "/etc/middleoffice/token.pem"   # RSA private key, PKCS#1 or PKCS#8 PEM
+/// token_key_id = "middleoffice-1"
+/// token_issuer = "middleoffice"
+/// token_ttl_seconds = 3600
+#[derive(Clone, Debug)]
+pub struct TokenConfig {
+    pub key_file: String,
+    pub key_id: String,
+    pub issuer: String,
+    pub ttl_seconds: i64,
+    pub clients: Vec<TokenClient>,
+}
+
+impl TokenConfig {
+    pub fn from_settings(settings: &config::Config) -> Option<TokenConfig> {
+        let key_file = settings.get::<String>("token_key_file").ok()?;
+
+        Some(TokenConfig {
+            key_file,
+            key_id: settings
+                .get::<String>("token_key_id")
+                .unwrap_or_else(|_| String::from("middleoffice-1")),
+            issuer: settings
+                .get::<String>("token_issuer")
+                .unwrap_or_else(|_| String::from("middleoffice")),
+            ttl_seconds: settings.get::<i64>("token_ttl_seconds").unwrap_or(3600),
+            clients: settings
+                .get::<Vec<TokenClient>>("token_clients")
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Public key of the token service, as published in the JWKS
+#[derive(Clone, Debug, Serialize)]
+pub struct PublicJwk {
+    pub kty: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub alg: String,
+    pub kid: String,
+    pub n: String,
+    pub e: String,
+}
+
+/// Claims of the issued tokens
+#[derive(Debug, Serialize)]
+struct IssuedClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    iat: i64,
+    exp: i64,
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+}
+
+/// Token issued to a client, RFC 6749 5.1
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub scope: String,
+}
+
+/// RFC 6749 5.2 error codes of the token endpoint
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    InvalidRequest,
+    InvalidClient,
+    InvalidScope,
+    UnsupportedGrantType,
+    ServerError,
+}
+
+impl TokenError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenError::InvalidRequest => "invalid_request",
+            TokenError::InvalidClient => "invalid_client",
+            TokenError::InvalidScope => "invalid_scope",
+            TokenError::UnsupportedGrantType => "unsupported_grant_type",
+            TokenError::ServerError => "server_error",
+        }
+    }
+}
+
+/// Token service signing RS256 tokens with the configured private key
+pub struct TokenService {
+    pub config: TokenConfig,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    pub jwk: PublicJwk,
+}
+
+/// Modulus and exponent of a RSA private key, PKCS#1 or PKCS#8 PEM, as base64url JWK components
+pub fn rsa_components(pem: &str) -> Result<(String, String), String> {
+    let pem = match pem::parse(pem) {
+        Ok(o) => o,
+        Err(e) => return Err(format!("Key is not a PEM file : {}", e)),
+    };
+    let key_pair = match pem.tag.as_str() {
+        "RSA PRIVATE KEY" => RsaKeyPair::from_der(&pem.contents),
+        "PRIVATE KEY" => RsaKeyPair::from_pkcs8(&pem.contents),
+        other => return Err(format!("Unsupported key format {}.", other)),
+    };
+    let key_pair = match key_pair {
+        Ok(o) => o,
+        Err(e) => return Err(format!("Key is not a RSA private key : {}", e)),
+    };
+    let public_key = key_pair.public_key();
+
+    Ok((
+        URL_SAFE_NO_PAD.encode(public_key.modulus().big_endian_without_leading_zero()),
+        URL_SAFE_NO_PAD.encode(public_key.exponent().big_endian_without_leading_zero()),
+    ))
+}
+
+/// Authorization header of a token request, for clients authenticating with HTTP Basic
+pub struct ClientAuthorization(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientAuthorization {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(ClientAuthorization(
+            req.headers().get_one("authorization").map(String::from),
+        ))
+    }
+}
+
+/// Client id and secret of a token request (RFC 6749 2.3.1) : HTTP Basic, the id and secret
+/// form-urlencoded before the base64 encoding, or client_id and client_secret in the body.
+/// A client uses one method only.
+pub fn client_credentials(
+    authorization: Option<&str>,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<(String, String), TokenError> {
+    let basic = match authorization {
+        Some(header) if header.len() > 6 && header[..6].eq_ignore_ascii_case("basic ") => {
+            &header[6..]
+        }
+        Some(_header) => return Err(TokenError::InvalidClient),
+        None => {
+            return Ok((
+                client_id.unwrap_or_default().to_string(),
+                client_secret.unwrap_or_default().to_string(),
+            ))
+        }
+    };
+    if client_secret.is_some() {
+        return Err(TokenError::InvalidRequest);
+    }
+
+    let decoded = match STANDARD.decode(basic.trim()).map(String::from_utf8) {
+        Ok(Ok(o)) => o,
+        _ => return Err(TokenError::InvalidClient),
+    };
+    let (id, secret) = match decoded.split_once(':') {
+        Some(o) => o,
+        None => return Err(TokenError::InvalidClient),
+    };
+    match (
+        RawStr::new(id).url_decode(),
+        RawStr::new(secret).url_decode(),
+    ) {
+        // a client_id in the body must name the authenticated client
+        (Ok(id), Ok(_)) if client_id.is_some_and(|c| c != id) => Err(TokenError::InvalidRequest),
+        (Ok(id), Ok(secret)) => Ok((id.into_owned(), secret.into_owned())),
+        _ => Err(TokenError::InvalidClient),
+    }
+}
+
+pub fn hex_sha256(value: &str) -> String {
+    format!("{:x}", Sha256::digest(value.as_bytes()))
+}
+
+impl TokenService {
+    pub fn new(config: TokenConfig) -> Result<TokenService, String> {
+        let pem = match fs::read_to_string(&config.key_file) {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Token key {} unreadable : {}", config.key_file, e)),
+        };
+        TokenService::from_pem(config, &pem)
+    }
+
+    pub fn from_pem(config: TokenConfig, pem: &str) -> Result<TokenService, String> {
+        let encoding_key = match EncodingKey::from_rsa_pem(pem.as_bytes()) {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Token key is not a RSA private key : {}", e)),
+        };
+        let (n, e) = rsa_components(pem)?;
+        let decoding_key = match DecodingKey::from_rsa_components(&n, &e) {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Token public key invalid : {}", e)),
+        };
+
+        Ok(TokenService {
+            jwk: PublicJwk {
+                kty: String::from("RSA"),
+                key_use: String::from("sig"),
+                alg: String::from("RS256"),
+                kid: config.key_id.clone(),
+                n,
+                e,
+            },
+            config,
+            encoding_key,
+            decoding_key,
+        })
+    }
+
+    /// Client credentials grant : the client secret is checked against its hash,
+    /// the requested scopes must be granted to the client, all of them by default
+    pub fn issue(
+        &self,
+        grant_type: &str,
+        client_id: &str,
+        client_secret: &str,
+        scope: Option<&str>,
+    ) -> Result<TokenResponse, TokenError> {
+        if grant_type != "client_credentials" {
+            return Err(TokenError::UnsupportedGrantType);
+        }
+        if client_id.is_empty() || client_secret.is_empty() {
+            return Err(TokenError::InvalidRequest);
+        }
+
+        let client = match self
+            .config
+            .clients
+            .iter()
+            .find(|c| c.client_id == client_id)
+        {
+            Some(c)
+                if c.secret_sha256
+                    .eq_ignore_ascii_case(&hex_sha256(client_secret)) =>
+            {
+                c
+            }
+            _ => return Err(TokenError::InvalidClient),
+        };
+
+        let granted: Vec<&str> = client.scope.split_whitespace().collect();
+        let scope = match scope {
+            Some(requested) if !requested.trim().is_empty() => {
+                if requested.split_whitespace().any(|s| !granted.contains(&s)) {
+                    return Err(TokenError::InvalidScope);
+                }
+                requested
+                    .split_whitespace()
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            }
+            _ => granted.join(" "),
+        };
+
+        let now = Utc::now().timestamp();
+        let claims = IssuedClaims {
+            iss: &self.config.issuer,
+            sub: &client.client_id,
+            iat: now,
+            exp: now + self.config.ttl_seconds,
+            scope: scope.clone(),
+            tenant: client.tenant.as_deref(),
+        };
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(self.config.key_id.clone());
+
+        match jsonwebtoken::encode(&header, &claims, &self.encoding_key) {
+            Ok(token) => Ok(TokenResponse {
+                access_token: token,
+                token_type: String::from("Bearer"),
+                expires_in: self.config.ttl_seconds,
+                scope,
+            }),
+            Err(e) => {
+                eprintln!("Token signature failed : {}", e);
+                Err(TokenError::ServerError)
+            }
+        }
+    }
+
+    /// Tokens carrying the key id of the service are verified locally
+    pub fn is_local(&self, token: &str) -> bool {
+        match jsonwebtoken::decode_header(token) {
+            Ok(header) => header.kid.as_deref() == Some(self.config.key_id.as_str()),
+            Err(_e) => false,
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(&[&self.config.issuer]);
+        jsonwebtoken::decode::<Claims>(token, &self.decoding_key, &validation).map(|t| t.claims)
+    }
+}
+
+/// Token service of the tests, signing with the test key (kid test-1, issuer middleoffice).
+/// Client tests has no tenant, clients retail and corporate belong to their tenant.
+/// The secret of a client is its id, every client is granted every scope, tenants:all included.
+#[cfg(test)]
+pub fn test_service() -> TokenService {
+    let client = |id: &str, tenant: Option<&str>| TokenClient {
+        client_id: String::from(id),
+        secret_sha256: hex_sha256(id),
+        scope: String::from(
+            "policies:read policies:write users:read users:write admin tenants:all",
+        ),
+        tenant: tenant.map(String::from),
+    };
+    let config = TokenConfig {
+        key_file: String::new(),
+        key_id: String::from("test-1"),
+        issuer: String::from("middleoffice"),
+        ttl_seconds: 300,
+        clients: vec![
+            client("tests", None),
+            client("retail", Some("retail")),
+            client("corporate", Some("corporate")),
+        ],
+    };
+    TokenService::from_pem(config, include_str!("test/token_test.pem")).unwrap()
+}
+
+#[test]
+fn test_issue_and_verify() {
+    let service = test_service();
+    assert_eq!(service.jwk.e, "AQAB");
+
+    let token = service
+        .issue(
+            "client_credentials",
+            "retail",
+            "retail",
+            Some("policies:read"),
+        )
+        .unwrap();
+    assert_eq!(token.scope, "policies:read");
+    assert!(service.is_local(&token.access_token));
+
+    let claims = service.verify(&token.access_token).unwrap();
+    assert_eq!(claims.sub, Some(String::from("retail")));
+    assert_eq!(claims.tenant, Some(String::from("retail")));
+
+    // the published components verify the token as a remote JWKS would
+    let key = DecodingKey::from_rsa_components(&service.jwk.n, &service.jwk.e).unwrap();
+    assert!(jsonwebtoken::decode::<Claims>(
+        &token.access_token,
+        &key,
+        &Validation::new(Algorithm::RS256)
+    )
+    .is_ok());
+
+    assert_eq!(
+        service
+            .issue("client_credentials", "retail", "wrong", None)
+            .err(),
+        Some(TokenError::InvalidClient)
+    );
+    assert_eq!(
+        service
+            .issue(
+                "client_credentials",
+                "retail",
+                "retail",
+                Some("users:admin")
+            )
+            .err(),
+        Some(TokenError::InvalidScope)
+    );
+    assert_eq!(
+        service.issue("password", "retail", "retail", None).err(),
+        Some(TokenError::UnsupportedGrantType)
+    );
+}
+
+#[test]
+fn test_rsa_components() {
+    let pem = include_str!("test/token_test.pem");
+    let (n, e) = rsa_components(pem).unwrap();
+    assert_eq!(e, "AQAB");
+
+    // the derived components verify what the public key file of the test key verifies
+    let claims = serde_json::json!({"sub": "tests", "exp": Utc::now().timestamp() + 60});
+    let key = EncodingKey::from_rsa_pem(pem.as_bytes()).unwrap();
+    let token = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key).unwrap();
+    let validation = Validation::new(Algorithm::RS256);
+    for key in [
+        DecodingKey::from_rsa_components(&n, &e).unwrap(),
+        DecodingKey::from_rsa_pem(include_bytes!("test/token_test_pk.pem")).unwrap(),
+    ] {
+        assert!(jsonwebtoken::decode::<serde_json::Value>(&token, &key, &validation).is_ok());
+    }
+
+    assert!(rsa_components(include_str!("test/token_test_pk.pem")).is_err());
+    assert!(rsa_components("not a key").is_err());
+}
+
+#[test]
+fn test_client_credentials() {
+    let basic = format!("Basic {}", STANDARD.encode("batch%3Ajobs:s3cr%2Bet"));
+    assert_eq!(
+        client_credentials(Some(&basic), None, None),
+        Ok((String::from("batch:jobs"), String::from("s3cr+et")))
+    );
+    assert_eq!(
+        client_credentials(None, Some("batch"), Some("s3cret")),
+        Ok((String::from("batch"), String::from("s3cret")))
+    );
+
+    // one authentication method only
+    assert_eq!(
+        client_credentials(Some(&basic), None, Some("s3cret")),
+        Err(TokenError::InvalidRequest)
+    );
+    assert_eq!(
+        client_credentials(Some(&basic), Some("other"), None),
+        Err(TokenError::InvalidRequest)
+    );
+    assert_eq!(
+        client_credentials(Some("Bearer abc"), None, None),
+        Err(TokenError::InvalidClient)
+    );
+    assert_eq!(
+        client_credentials(Some("Basic !!"), None, None),
+        Err(TokenError::InvalidClient)
+    );
+}